@@ -0,0 +1,380 @@
+use cgmath::point::{Point, Point3};
+use cgmath::ray::Ray3;
+use cgmath::vector::Vector3;
+use std::f32;
+
+/// An axis-aligned bounding box.
+#[deriving(Clone, PartialEq, Show)]
+pub struct BoundingBox {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>
+}
+
+fn axis(p: &Point3<f32>, a: uint) -> f32 {
+    match a { 0 => p.x, 1 => p.y, _ => p.z }
+}
+
+fn component(v: &Vector3<f32>, a: uint) -> f32 {
+    match a { 0 => v.x, 1 => v.y, _ => v.z }
+}
+
+impl BoundingBox {
+    /// The empty box, neutral element of `union`.
+    pub fn empty() -> BoundingBox {
+        BoundingBox {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY)
+        }
+    }
+
+    pub fn from_points(points: &[Point3<f32>]) -> BoundingBox {
+        points.iter().fold(BoundingBox::empty(), |bb, p| bb.extend(p))
+    }
+
+    pub fn extend(&self, p: &Point3<f32>) -> BoundingBox {
+        BoundingBox {
+            min: Point3::new(self.min.x.min(p.x), self.min.y.min(p.y), self.min.z.min(p.z)),
+            max: Point3::new(self.max.x.max(p.x), self.max.y.max(p.y), self.max.z.max(p.z))
+        }
+    }
+
+    pub fn union(&self, other: &BoundingBox) -> BoundingBox {
+        self.extend(&other.min).extend(&other.max)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn centroid(&self) -> Point3<f32> {
+        Point3::new(
+            (self.min.x + self.max.x) * 0.5,
+            (self.min.y + self.max.y) * 0.5,
+            (self.min.z + self.max.z) * 0.5
+        )
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() { return 0.0; }
+        let d = self.max.sub_p(&self.min);
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn contains(&self, p: &Point3<f32>, tolerance: f32) -> bool {
+        range(0, 3).all(|a| {
+            axis(p, a) >= axis(&self.min, a) - tolerance && axis(p, a) <= axis(&self.max, a) + tolerance
+        })
+    }
+
+    /// Slab test. Returns the distance at which the ray enters the box if
+    /// it does so before `t_max`.
+    fn intersect(&self, origin: &Point3<f32>, inv_dir: &Vector3<f32>, t_max: f32) -> Option<f32> {
+        let mut t0 = 0.0f32;
+        let mut t1 = t_max;
+        for a in range(0, 3) {
+            let inv = component(inv_dir, a);
+            let near = (axis(&self.min, a) - axis(origin, a)) * inv;
+            let far = (axis(&self.max, a) - axis(origin, a)) * inv;
+            let (near, far) = if near > far { (far, near) } else { (near, far) };
+            // `max` and `min` discard the NaN coming from 0 * infinity.
+            t0 = near.max(t0);
+            t1 = far.min(t1);
+            if t0 > t1 { return None; }
+        }
+        Some(t0)
+    }
+}
+
+enum NodeKind {
+    // First index and number of primitives.
+    Leaf(uint, uint),
+    // Index of the right child and split axis. The left child follows its parent.
+    Interior(uint, uint)
+}
+
+struct Node {
+    bounds: BoundingBox,
+    kind: NodeKind
+}
+
+struct BuildPrimitive {
+    index: uint,
+    bounds: BoundingBox,
+    centroid: Point3<f32>
+}
+
+static BINS: uint = 12;
+static MAX_LEAF_SIZE: uint = 4;
+static MAX_DEPTH: uint = 60;
+// Cost of traversing a node relative to intersecting a primitive.
+static TRAVERSAL_COST: f32 = 0.125;
+
+/// A bounding volume hierarchy over indexed primitives, built with the
+/// surface area heuristic.
+///
+/// The hierarchy only knows about the bounds of the primitives. Queries take
+/// a closure intersecting the primitive of a given index.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<uint>
+}
+
+impl Bvh {
+    pub fn new(bounds: &[BoundingBox]) -> Bvh {
+        let mut prims: Vec<BuildPrimitive> = bounds.iter().enumerate().map(|(i, bb)| {
+            BuildPrimitive {index: i, bounds: bb.clone(), centroid: bb.centroid()}
+        }).collect();
+        let mut bvh = Bvh {nodes: Vec::with_capacity(2 * prims.len()), indices: Vec::with_capacity(prims.len())};
+        if !prims.is_empty() {
+            let len = prims.len();
+            bvh.build(&mut prims, 0, len, 0);
+        }
+        bvh
+    }
+
+    fn build(&mut self, prims: &mut Vec<BuildPrimitive>, start: uint, end: uint, depth: uint) -> uint {
+        let node_index = self.nodes.len();
+        let bounds = prims.slice(start, end).iter().fold(BoundingBox::empty(), |bb, p| bb.union(&p.bounds));
+        self.nodes.push(Node {bounds: bounds.clone(), kind: Leaf(0, 0)});
+        let count = end - start;
+        let split = if count <= 1 || depth >= MAX_DEPTH { None } else { find_split(prims.slice(start, end), &bounds) };
+        match split {
+            Some((ax, threshold, cost)) if count > MAX_LEAF_SIZE || cost < count as f32 => {
+                // Partition the primitives on the side of the split plane of their centroid.
+                let mut i = start;
+                let mut j = end;
+                while i < j {
+                    if axis(&prims[i].centroid, ax) < threshold {
+                        i += 1;
+                    } else {
+                        j -= 1;
+                        prims.as_mut_slice().swap(i, j);
+                    }
+                }
+                if i == start || i == end {
+                    self.make_leaf(node_index, prims.slice(start, end));
+                } else {
+                    self.build(prims, start, i, depth + 1);
+                    let right = self.build(prims, i, end, depth + 1);
+                    self.nodes.get_mut(node_index).kind = Interior(right, ax);
+                }
+            },
+            _ => self.make_leaf(node_index, prims.slice(start, end))
+        }
+        node_index
+    }
+
+    fn make_leaf(&mut self, node_index: uint, prims: &[BuildPrimitive]) {
+        let first = self.indices.len();
+        for p in prims.iter() {
+            self.indices.push(p.index);
+        }
+        self.nodes.get_mut(node_index).kind = Leaf(first, prims.len());
+    }
+
+    /// Finds the nearest primitive hit before `t_max`.
+    ///
+    /// `intersect` returns the hit with a given primitive along with its
+    /// ray parameter, which `t_max` is compared with.
+    pub fn nearest<T>(&self, ray: &Ray3<f32>, t_max: f32, intersect: |uint| -> Option<(T, f32)>) -> Option<(T, f32)> {
+        let mut nearest = None;
+        let mut t_max = t_max;
+        if self.nodes.is_empty() { return nearest; }
+        let dir = ray.direction;
+        let inv_dir = Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+        let mut stack = [0u, ..64];
+        let mut top = 1u;
+        while top > 0 {
+            top -= 1;
+            let node_index = stack[top];
+            let node = &self.nodes[node_index];
+            if node.bounds.intersect(&ray.origin, &inv_dir, t_max).is_none() { continue; }
+            match node.kind {
+                Leaf(first, count) => {
+                    for &i in self.indices.slice(first, first + count).iter() {
+                        match intersect(i) {
+                            Some((hit, t)) => if t < t_max {
+                                t_max = t;
+                                nearest = Some((hit, t));
+                            },
+                            None => ()
+                        }
+                    }
+                },
+                Interior(right, ax) => {
+                    // Visit the child closest to the ray origin first.
+                    let (near, far) = if component(&dir, ax) < 0.0 {
+                        (right, node_index + 1)
+                    } else {
+                        (node_index + 1, right)
+                    };
+                    stack[top] = far;
+                    stack[top + 1] = near;
+                    top += 2;
+                }
+            }
+        }
+        nearest
+    }
+
+    /// Returns whether any primitive is hit before `t_max`, stopping at the
+    /// first one found.
+    pub fn any(&self, ray: &Ray3<f32>, t_max: f32, hit: |uint| -> bool) -> bool {
+        if self.nodes.is_empty() { return false; }
+        let dir = ray.direction;
+        let inv_dir = Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+        let mut stack = [0u, ..64];
+        let mut top = 1u;
+        while top > 0 {
+            top -= 1;
+            let node_index = stack[top];
+            let node = &self.nodes[node_index];
+            if node.bounds.intersect(&ray.origin, &inv_dir, t_max).is_none() { continue; }
+            match node.kind {
+                Leaf(first, count) => {
+                    if self.indices.slice(first, first + count).iter().any(|&i| hit(i)) {
+                        return true;
+                    }
+                },
+                Interior(right, _) => {
+                    stack[top] = right;
+                    stack[top + 1] = node_index + 1;
+                    top += 2;
+                }
+            }
+        }
+        false
+    }
+
+    /// Calls `visit` on every primitive whose bounds contain `p`.
+    pub fn visit_point(&self, p: &Point3<f32>, tolerance: f32, visit: |uint|) {
+        if self.nodes.is_empty() { return; }
+        let mut stack = [0u, ..64];
+        let mut top = 1u;
+        while top > 0 {
+            top -= 1;
+            let node_index = stack[top];
+            let node = &self.nodes[node_index];
+            if !node.bounds.contains(p, tolerance) { continue; }
+            match node.kind {
+                Leaf(first, count) => {
+                    for &i in self.indices.slice(first, first + count).iter() {
+                        visit(i);
+                    }
+                },
+                Interior(right, _) => {
+                    stack[top] = right;
+                    stack[top + 1] = node_index + 1;
+                    top += 2;
+                }
+            }
+        }
+    }
+}
+
+/// Binned SAH split search. Returns the split axis and position, and the
+/// cost of the split in units of primitive intersections.
+fn find_split(prims: &[BuildPrimitive], bounds: &BoundingBox) -> Option<(uint, f32, f32)> {
+    let centroids = prims.iter().fold(BoundingBox::empty(), |bb, p| bb.extend(&p.centroid));
+    let extent = centroids.max.sub_p(&centroids.min);
+    let ax = if extent.x > extent.y && extent.x > extent.z { 0 } else if extent.y > extent.z { 1 } else { 2 };
+    let (low, size) = (axis(&centroids.min, ax), component(&extent, ax));
+    if size <= 0.0 { return None; }
+
+    let mut counts = [0u, ..BINS];
+    let mut bin_bounds = Vec::from_elem(BINS, BoundingBox::empty());
+    let bin_of = |p: &BuildPrimitive| {
+        let b = ((axis(&p.centroid, ax) - low) / size * BINS as f32) as uint;
+        if b >= BINS { BINS - 1 } else { b }
+    };
+    for p in prims.iter() {
+        let b = bin_of(p);
+        counts[b] += 1;
+        let bb = bin_bounds[b].union(&p.bounds);
+        *bin_bounds.get_mut(b) = bb;
+    }
+
+    let mut best: Option<(uint, f32)> = None;
+    for k in range(1, BINS) {
+        let (mut left, mut n_left) = (BoundingBox::empty(), 0u);
+        let (mut right, mut n_right) = (BoundingBox::empty(), 0u);
+        for b in range(0, BINS) {
+            if b < k {
+                left = left.union(&bin_bounds[b]);
+                n_left += counts[b];
+            } else {
+                right = right.union(&bin_bounds[b]);
+                n_right += counts[b];
+            }
+        }
+        if n_left == 0 || n_right == 0 { continue; }
+        let cost = TRAVERSAL_COST +
+            (left.surface_area() * n_left as f32 + right.surface_area() * n_right as f32) / bounds.surface_area();
+        if best.map_or(true, |(_, c)| cost < c) {
+            best = Some((k, cost));
+        }
+    }
+    best.map(|(k, cost)| (ax, low + size * k as f32 / BINS as f32, cost))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::point::{Point, Point3};
+    use cgmath::ray::{Ray, Ray3};
+    use cgmath::vector::{EuclideanVector, Vector3};
+    use std::f32;
+    use std::rand::{Rng, SeedableRng, XorShiftRng};
+
+    use bvh::{BoundingBox, Bvh};
+    use shape::{Shape, Sphere};
+
+    #[test]
+    fn test_bounding_box_intersect() {
+        let bb = BoundingBox::from_points(&[Point3::new(-1.0f32, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0)]);
+        let dir = Vector3::new(0.0f32, 0.0, 1.0);
+        let inv_dir = Vector3::new(1.0 / dir.x, 1.0 / dir.y, 1.0 / dir.z);
+        assert!(bb.intersect(&Point3::new(0.0, 0.0, -3.0), &inv_dir, 10.0) == Some(2.0));
+        assert!(bb.intersect(&Point3::new(0.0, 0.0, -3.0), &inv_dir, 1.0) == None);
+        assert!(bb.intersect(&Point3::new(2.0, 0.0, -3.0), &inv_dir, 10.0) == None);
+        assert!(bb.intersect(&Point::origin(), &inv_dir, 10.0) == Some(0.0));
+    }
+
+    fn distance(sphere: &Sphere<f32>, ray: Ray3<f32>) -> Option<f32> {
+        sphere.intersect(ray, 0.0, f32::INFINITY).map(|hit| hit.t)
+    }
+
+    #[test]
+    fn test_bvh_matches_brute_force() {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([1, 2, 3, 4]);
+        let spheres: Vec<Sphere<f32>> = Vec::from_fn(200, |_| {
+            let center = Point3::new(rng.gen::<f32>() * 10.0 - 5.0, rng.gen::<f32>() * 10.0 - 5.0, rng.gen::<f32>() * 10.0 + 5.0);
+            Sphere {center: center, radius: rng.gen::<f32>() * 0.5}
+        });
+        let bounds: Vec<BoundingBox> = spheres.iter().map(|s| s.bounds().unwrap()).collect();
+        let bvh = Bvh::new(bounds.as_slice());
+        for _ in range(0u, 500) {
+            let dir = Vector3::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5, 1.0).normalize();
+            let ray = Ray::new(Point::origin(), dir);
+            let mut expected = None;
+            for (i, s) in spheres.iter().enumerate() {
+                match distance(s, ray) {
+                    Some(t) => if expected.map_or(true, |(_, e)| t < e) { expected = Some((i, t)) },
+                    None => ()
+                }
+            }
+            let found = bvh.nearest(&ray, f32::INFINITY, |i| distance(&spheres[i], ray).map(|t| (i, t)));
+            assert!(found.map(|(i, _)| i) == expected.map(|(i, _)| i));
+            let blocked = bvh.any(&ray, f32::INFINITY, |i| distance(&spheres[i], ray).is_some());
+            assert!(blocked == expected.is_some());
+        }
+    }
+
+    #[test]
+    fn test_bvh_empty() {
+        let bvh = Bvh::new(&[]);
+        let ray = Ray::new(Point::origin(), Vector3::new(0.0f32, 0.0, 1.0));
+        assert!(bvh.nearest(&ray, 1.0, |i| Some((i, 0.0f32))).is_none());
+        assert!(!bvh.any(&ray, 1.0, |_| true));
+    }
+}
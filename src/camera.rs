@@ -1,10 +1,16 @@
 use std::cmp::max;
-use cgmath::point::{Point};
+use cgmath::point::{Point, Point3};
 use cgmath::ray::{Ray, Ray3};
-use cgmath::vector::{EuclideanVector, Vector3};
+use cgmath::vector::{EuclideanVector, Vector, Vector3};
+
+use sampling::concentric_disk;
 
 pub trait Camera {
-    fn make_ray(&self, x: u32, y: u32) -> Ray3<f32>;
+    /// Makes the ray going through the film point `(x, y)`, in pixels.
+    ///
+    /// `lens` is a point of the unit square, used by cameras with a finite
+    /// aperture to pick where the ray crosses the lens.
+    fn make_ray(&self, x: f32, y: f32, lens: (f32, f32)) -> Ray3<f32>;
 }
 
 pub struct OriginCamera {
@@ -14,16 +20,111 @@ pub struct OriginCamera {
 }
 
 impl Camera for OriginCamera {
-    fn make_ray(&self, x: u32, y: u32) -> Ray3<f32> {
+    fn make_ray(&self, x: f32, y: f32, _lens: (f32, f32)) -> Ray3<f32> {
         let maximum = max(self.width, self.height) as f32;
         let to_dim = |val: f32, range: f32| self.aperture * (val - range/2.0) / maximum;
-        let xx = to_dim(x as f32, self.width as f32);
-        let yy = to_dim(y as f32, self.height as f32);
+        let xx = to_dim(x, self.width as f32);
+        let yy = to_dim(y, self.height as f32);
         let v = Vector3::new(xx, yy, 1.0).normalize();
         Ray::new(Point::origin(), v)
     }
 }
 
+/// A pinhole camera placed at `eye` and looking at `target`.
+pub struct PerspectiveCamera {
+    eye: Point3<f32>,
+    // Orthonormal basis of the camera.
+    right: Vector3<f32>,
+    up: Vector3<f32>,
+    forward: Vector3<f32>,
+    // Half extent of the image plane at distance 1 from the eye.
+    half_width: f32,
+    half_height: f32,
+    width: u32,
+    height: u32
+}
+
+impl PerspectiveCamera {
+    /// `up` does not need to be orthogonal to the viewing direction, only
+    /// not parallel to it. `fov` is the vertical field of view in degrees and
+    /// `aspect` the ratio of the width of the image to its height.
+    ///
+    /// Fails if `eye` and `target` are the same point, or if `up` is
+    /// parallel to the viewing direction, which leaves the camera without an
+    /// orientation.
+    pub fn new(eye: Point3<f32>, target: Point3<f32>, up: Vector3<f32>, fov: f32, aspect: f32, width: u32, height: u32) -> Result<PerspectiveCamera, String> {
+        let view = target.sub_p(&eye);
+        if !(view.length2() > 0.0) {
+            return Err("the eye and the target of a camera must differ".to_string());
+        }
+        let forward = view.normalize();
+        let side = forward.cross(&up);
+        if !(side.length2() > 0.0) {
+            return Err("the up direction of a camera cannot be parallel to the viewing direction".to_string());
+        }
+        let right = side.normalize();
+        let half_height = (fov.to_radians() / 2.0).tan();
+        Ok(PerspectiveCamera {
+            eye: eye,
+            right: right,
+            up: right.cross(&forward),
+            forward: forward,
+            half_width: half_height * aspect,
+            half_height: half_height,
+            width: width,
+            height: height
+        })
+    }
+}
+
+impl PerspectiveCamera {
+    /// Direction from the eye to the film point, not normalized.
+    fn direction(&self, x: f32, y: f32) -> Vector3<f32> {
+        // Image coordinates go right and down, from -1 to 1.
+        let u = 2.0 * x / self.width as f32 - 1.0;
+        let v = 2.0 * y / self.height as f32 - 1.0;
+        self.forward
+            .add_v(&self.right.mul_s(u * self.half_width))
+            .add_v(&self.up.mul_s(-v * self.half_height))
+    }
+}
+
+impl Camera for PerspectiveCamera {
+    fn make_ray(&self, x: f32, y: f32, _lens: (f32, f32)) -> Ray3<f32> {
+        Ray::new(self.eye, self.direction(x, y).normalize())
+    }
+}
+
+/// A perspective camera with a thin lens, giving depth of field.
+///
+/// Only the points at `focal_distance` from the lens, measured along the
+/// viewing direction, are sharp.
+pub struct ThinLensCamera {
+    pinhole: PerspectiveCamera,
+    lens_radius: f32,
+    focal_distance: f32
+}
+
+impl ThinLensCamera {
+    pub fn new(pinhole: PerspectiveCamera, lens_radius: f32, focal_distance: f32) -> ThinLensCamera {
+        ThinLensCamera {pinhole: pinhole, lens_radius: lens_radius, focal_distance: focal_distance}
+    }
+}
+
+impl Camera for ThinLensCamera {
+    fn make_ray(&self, x: f32, y: f32, lens: (f32, f32)) -> Ray3<f32> {
+        let cam = &self.pinhole;
+        // `direction` has a unit component along `forward`, so this is the
+        // point of the focal plane seen through the center of the lens.
+        let focus = cam.eye.add_v(&cam.direction(x, y).mul_s(self.focal_distance));
+        let (lx, ly) = concentric_disk(lens.val0(), lens.val1());
+        let origin = cam.eye
+            .add_v(&cam.right.mul_s(lx * self.lens_radius))
+            .add_v(&cam.up.mul_s(ly * self.lens_radius));
+        Ray::new(origin, focus.sub_p(&origin).normalize())
+    }
+}
+
 #[test]
 fn test_origin_camera_make_ray() {
     let cam = OriginCamera {aperture: 1.0, height: 1000, width: 1000};
@@ -31,10 +132,68 @@ fn test_origin_camera_make_ray() {
     let ray_center = ray_from_origin(0.0, 0.0, 1.0);
     let ray_corner = ray_from_origin(0.5, 0.5, 1.0);
     let ray_corner1 = ray_from_origin(-0.5, -0.5, 1.0);
-    assert!(ray_center == cam.make_ray(500, 500));
-    assert!(ray_corner == cam.make_ray(1000, 1000));
-    assert!(ray_corner1 == cam.make_ray(0, 0));
+    let lens = (0.5, 0.5);
+    assert!(ray_center == cam.make_ray(500.0, 500.0, lens));
+    assert!(ray_corner == cam.make_ray(1000.0, 1000.0, lens));
+    assert!(ray_corner1 == cam.make_ray(0.0, 0.0, lens));
     let cam2 = OriginCamera {aperture: 2.0, height: 1000, width: 1000};
     let ray_corner2 = ray_from_origin(1.0, 1.0, 1.0);
-    assert!(ray_corner2 == cam2.make_ray(1000, 1000));
-}
\ No newline at end of file
+    assert!(ray_corner2 == cam2.make_ray(1000.0, 1000.0, lens));
+}
+
+#[test]
+fn test_perspective_camera_make_ray() {
+    let eye = Point3::new(1.0f32, 2.0, 3.0);
+    let cam = PerspectiveCamera::new(eye, Point3::new(1.0, 2.0, 10.0), Vector3::new(0.0, 1.0, 0.0), 90.0, 2.0, 200, 100).unwrap();
+    let close = |a: Vector3<f32>, b: Vector3<f32>| a.sub_v(&b).length() < 0.00001;
+    let center = cam.make_ray(100.0, 50.0, (0.5, 0.5));
+    assert!(center.origin == eye);
+    assert!(close(center.direction, Vector3::new(0.0, 0.0, 1.0)));
+    // Looking down +z with +y up, the right of the image is -x.
+    let top_right = cam.make_ray(200.0, 0.0, (0.0, 1.0));
+    assert!(close(top_right.direction, Vector3::new(-2.0, 1.0, 1.0).normalize()));
+    let bottom_left = cam.make_ray(0.0, 100.0, (1.0, 0.0));
+    assert!(close(bottom_left.direction, Vector3::new(2.0, -1.0, 1.0).normalize()));
+}
+
+#[test]
+fn test_perspective_camera_needs_an_orientation() {
+    let eye = Point3::new(1.0f32, 2.0, 3.0);
+    let up = Vector3::new(0.0f32, 1.0, 0.0);
+    assert!(PerspectiveCamera::new(eye, eye, up, 90.0, 1.0, 100, 100).is_err());
+    assert!(PerspectiveCamera::new(eye, Point3::new(1.0, 5.0, 3.0), up, 90.0, 1.0, 100, 100).is_err());
+    assert!(PerspectiveCamera::new(eye, Point3::new(1.0, -5.0, 3.0), up, 90.0, 1.0, 100, 100).is_err());
+}
+
+#[test]
+fn test_perspective_camera_matches_origin_camera() {
+    // The origin camera looks down +z with the image y axis along +y.
+    let origin = OriginCamera {aperture: 2.0, height: 100, width: 100};
+    let cam = PerspectiveCamera::new(Point::origin(), Point3::new(0.0, 0.0, 1.0), Vector3::new(0.0, -1.0, 0.0), 90.0, 1.0, 100, 100).unwrap();
+    for &(x, y) in [(0.0f32, 0.0f32), (100.0, 100.0), (30.0, 70.0), (50.5, 50.5)].iter() {
+        let d = origin.make_ray(x, y, (0.5, 0.5)).direction.sub_v(&cam.make_ray(x, y, (0.5, 0.5)).direction);
+        assert!(d.length() < 0.00001);
+    }
+}
+
+#[test]
+fn test_thin_lens_camera_focuses_on_focal_plane() {
+    let make_pinhole = || PerspectiveCamera::new(Point::origin(), Point3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0), 60.0, 1.0, 100, 100).unwrap();
+    let pinhole = make_pinhole();
+    let cam = ThinLensCamera::new(make_pinhole(), 0.5, 4.0);
+    let (x, y) = (20.0f32, 70.0f32);
+    let expected = pinhole.make_ray(x, y, (0.5, 0.5));
+    // The center of the lens behaves like a pinhole.
+    assert!(cam.make_ray(x, y, (0.5, 0.5)).direction.sub_v(&expected.direction).length() < 0.00001);
+    let focus_t = 4.0 / expected.direction.z;
+    let focus = expected.origin.add_v(&expected.direction.mul_s(focus_t));
+    for &lens in [(0.0f32, 0.0f32), (1.0, 0.3), (0.2, 0.9)].iter() {
+        let ray = cam.make_ray(x, y, lens);
+        assert!(ray.origin.z == 0.0);
+        assert!(ray.origin.sub_p(&Point::origin()).length() <= 0.5 + 0.00001);
+        // Every ray through the pixel crosses the focal plane at the same point.
+        let t = (4.0 - ray.origin.z) / ray.direction.z;
+        let p = ray.origin.add_v(&ray.direction.mul_s(t));
+        assert!(p.sub_p(&focus).length() < 0.0001);
+    }
+}
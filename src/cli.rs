@@ -0,0 +1,264 @@
+extern crate getopts;
+
+use getopts::{optflag, optopt, OptGroup, Matches};
+use std::cmp::{max, min};
+
+use film::OutputFormat;
+use filter::Filter;
+use render::RenderSettings;
+use tonemap::{ToneMap, ReinhardExtended};
+
+/// What the command line asks for.
+pub enum Command {
+    Help(String),
+    Render(Options)
+}
+
+pub struct Options {
+    pub input: Option<Path>,
+    pub output: Path,
+    pub format: OutputFormat,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub depth: Option<uint>,
+    pub samples: Option<uint>,
+    pub threads: Option<uint>,
+    pub seed: Option<u32>,
+    pub filter: Option<Filter>,
+    pub tone_map: Option<ToneMap>,
+    pub exposure: Option<f32>,
+    pub preview: bool
+}
+
+impl Options {
+    /// Overrides `settings` with the values given on the command line.
+    pub fn apply(&self, settings: &mut RenderSettings) {
+        settings.width = self.width.unwrap_or(settings.width);
+        settings.height = self.height.unwrap_or(settings.height);
+        settings.bounces = self.depth.unwrap_or(settings.bounces);
+        settings.samples = self.samples.unwrap_or(settings.samples);
+        settings.threads = self.threads.unwrap_or(settings.threads);
+        settings.seed = self.seed.unwrap_or(settings.seed);
+        settings.filter = self.filter.unwrap_or(settings.filter);
+        settings.tone_map = self.tone_map.unwrap_or(settings.tone_map);
+        settings.exposure = self.exposure.unwrap_or(settings.exposure);
+        if self.preview {
+            settings.width = max(settings.width / 4, 1);
+            settings.height = max(settings.height / 4, 1);
+            settings.bounces = min(settings.bounces, 2);
+            settings.samples = 1;
+        }
+    }
+}
+
+fn options() -> Vec<OptGroup> {
+    vec![
+        optopt("o", "output", "output image (default: result.png)", "FILE"),
+        optopt("f", "format", "output format: png, jpeg, ppm, or linear exr, hdr or pfm (default: from the output extension)", "FORMAT"),
+        optopt("", "width", "image width in pixels", "PIXELS"),
+        optopt("", "height", "image height in pixels", "PIXELS"),
+        optopt("d", "depth", "maximum number of bounces", "N"),
+        optopt("s", "samples", "samples per pixel", "N"),
+        optopt("t", "threads", "number of render threads (default: number of CPUs)", "N"),
+        optopt("", "seed", "seed of the random number generator", "N"),
+        optopt("", "filter", "pixel filter: box, tent, gaussian or mitchell (default: box)", "FILTER"),
+        optopt("", "tonemap", "display transform of 8 bit images: linear, srgb, reinhard, reinhard_extended, \
+                               aces or hable (default: srgb)", "NAME"),
+        optopt("", "white", "white point of the reinhard_extended tone map (default: 4)", "LIGHT"),
+        optopt("", "exposure", "exposure of 8 bit images, in stops (default: 0)", "STOPS"),
+        optflag("p", "preview", "fast low-quality render: quarter resolution, 2 bounces and 1 sample"),
+        optflag("h", "help", "print this help")
+    ]
+}
+
+/// Parses the arguments, including the program name.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let opts = options();
+    let program = if args.is_empty() { "mantaray".to_string() } else { args[0].clone() };
+    let matches = match getopts::getopts(args.tail(), opts.as_slice()) {
+        Ok(m) => m,
+        Err(f) => return Err(f.to_string())
+    };
+    if matches.opt_present("help") {
+        let brief = format!("Usage: {} [options] [SCENE]\n\n\
+                             Renders SCENE, or the built-in scene if omitted.", program);
+        return Ok(Help(getopts::usage(brief.as_slice(), opts.as_slice())));
+    }
+    if matches.free.len() > 1 {
+        return Err("expected at most one scene file".to_string());
+    }
+    let output = Path::new(matches.opt_str("output").unwrap_or("result.png".to_string()));
+    let format_name = match matches.opt_str("format") {
+        Some(f) => f,
+        None => match output.extension_str() {
+            Some(ext) => ext.to_string(),
+            None => return Err("cannot guess the output format, use --format".to_string())
+        }
+    };
+    let format_name: String = format_name.as_slice().chars().map(|c| c.to_lowercase()).collect();
+    let format = match OutputFormat::from_name(format_name.as_slice()) {
+        Some(format) => format,
+        None => return Err(format!("unsupported output format `{}`", format_name))
+    };
+    let filter = match matches.opt_str("filter") {
+        None => None,
+        Some(name) => match Filter::from_name(name.as_slice()) {
+            Some(filter) => Some(filter),
+            None => return Err(format!("unknown filter `{}`", name))
+        }
+    };
+    let tone_map = match matches.opt_str("tonemap") {
+        None => None,
+        Some(name) => match ToneMap::from_name(name.as_slice()) {
+            Some(tone_map) => Some(tone_map),
+            None => return Err(format!("unknown tone map `{}`", name))
+        }
+    };
+    let tone_map = match (tone_map, try!(float(&matches, "white"))) {
+        (tone_map, None) => tone_map,
+        (Some(ReinhardExtended(_)), Some(white)) if white > 0.0 => Some(ReinhardExtended(white)),
+        (Some(ReinhardExtended(_)), Some(_)) => return Err("--white must be greater than 0".to_string()),
+        (_, Some(_)) => return Err("--white only applies to --tonemap reinhard_extended".to_string())
+    };
+    Ok(Render(Options {
+        input: matches.free.as_slice().get(0).map(|f| Path::new(f.as_slice())),
+        output: output,
+        format: format,
+        width: try!(positive(&matches, "width")).map(|w| w as u32),
+        height: try!(positive(&matches, "height")).map(|h| h as u32),
+        depth: try!(number(&matches, "depth")),
+        samples: try!(positive(&matches, "samples")),
+        threads: try!(positive(&matches, "threads")),
+        seed: try!(number(&matches, "seed")).map(|s| s as u32),
+        filter: filter,
+        tone_map: tone_map,
+        exposure: try!(float(&matches, "exposure")),
+        preview: matches.opt_present("preview")
+    }))
+}
+
+fn number(matches: &Matches, name: &str) -> Result<Option<uint>, String> {
+    match matches.opt_str(name) {
+        None => Ok(None),
+        Some(s) => match from_str::<uint>(s.as_slice()) {
+            Some(n) => Ok(Some(n)),
+            None => Err(format!("--{} expects a non-negative integer, got `{}`", name, s))
+        }
+    }
+}
+
+fn float(matches: &Matches, name: &str) -> Result<Option<f32>, String> {
+    match matches.opt_str(name) {
+        None => Ok(None),
+        Some(s) => match from_str::<f32>(s.as_slice()) {
+            Some(x) if x.is_finite() => Ok(Some(x)),
+            _ => Err(format!("--{} expects a number, got `{}`", name, s))
+        }
+    }
+}
+
+fn positive(matches: &Matches, name: &str) -> Result<Option<uint>, String> {
+    match try!(number(matches, name)) {
+        Some(0) => Err(format!("--{} must be greater than 0", name)),
+        n => Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cli::{parse, Command, Help, Render, Options};
+    use film::{Png, Ppm, OpenExr, Pfm};
+    use filter::MitchellFilter;
+    use render::RenderSettings;
+    use tonemap::{Aces, ReinhardExtended};
+
+    fn run(args: &[&str]) -> Result<Command, String> {
+        let mut all = vec!["mantaray".to_string()];
+        for a in args.iter() {
+            all.push(a.to_string());
+        }
+        parse(all.as_slice())
+    }
+
+    fn options(args: &[&str]) -> Options {
+        match run(args) {
+            Ok(Render(options)) => options,
+            Ok(Help(_)) => fail!("unexpected help"),
+            Err(msg) => fail!("unexpected error: {}", msg)
+        }
+    }
+
+    #[test]
+    fn test_defaults() {
+        let opts = options(&[]);
+        assert!(opts.input.is_none());
+        assert!(opts.output == Path::new("result.png"));
+        assert!(opts.format == Png);
+        assert!(opts.width.is_none() && opts.samples.is_none() && !opts.preview);
+    }
+
+    #[test]
+    fn test_options() {
+        let opts = options(&["-o", "out.ppm", "--width", "640", "--height", "480", "-d", "0",
+                             "-s", "16", "-t", "3", "--seed", "7", "--filter", "mitchell", "scene.toml"]);
+        assert!(opts.input == Some(Path::new("scene.toml")));
+        assert!(opts.output == Path::new("out.ppm"));
+        assert!(opts.format == Ppm);
+        assert!(opts.width == Some(640) && opts.height == Some(480));
+        assert!(opts.depth == Some(0) && opts.samples == Some(16));
+        assert!(opts.threads == Some(3) && opts.seed == Some(7));
+        let mut settings = RenderSettings::new();
+        opts.apply(&mut settings);
+        assert!(settings.width == 640 && settings.samples == 16 && settings.seed == 7);
+        assert!(settings.filter == MitchellFilter(1.0 / 3.0, 1.0 / 3.0));
+    }
+
+    #[test]
+    fn test_preview() {
+        let mut settings = RenderSettings::new();
+        options(&["--width", "400", "-s", "64", "--preview"]).apply(&mut settings);
+        assert!(settings.width == 100 && settings.height == 250);
+        assert!(settings.samples == 1 && settings.bounces <= 2);
+    }
+
+    #[test]
+    fn test_help() {
+        match run(&["--help"]) {
+            Ok(Help(text)) => assert!(text.as_slice().contains("--samples")),
+            _ => fail!("expected help")
+        }
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(run(&["--width", "0"]).is_err());
+        assert!(run(&["-s", "many"]).is_err());
+        assert!(run(&["-o", "result.bmp"]).is_err());
+        assert!(run(&["-o", "result"]).is_err());
+        assert!(run(&["a.toml", "b.toml"]).is_err());
+        assert!(run(&["--bogus"]).is_err());
+        assert!(run(&["--filter", "lanczos"]).is_err());
+        assert!(run(&["-o", "result", "-f", "PNG"]).is_ok());
+    }
+
+    #[test]
+    fn test_tone_map() {
+        let mut settings = RenderSettings::new();
+        options(&["--tonemap", "aces", "--exposure=-1.5"]).apply(&mut settings);
+        assert!(settings.tone_map == Aces && settings.exposure == -1.5);
+        assert!(run(&["--tonemap", "filmic"]).is_err());
+        assert!(run(&["--exposure", "bright"]).is_err());
+        assert!(options(&["--tonemap", "reinhard_extended"]).tone_map == Some(ReinhardExtended(4.0)));
+        assert!(options(&["--tonemap", "reinhard_extended", "--white", "10"]).tone_map == Some(ReinhardExtended(10.0)));
+        assert!(run(&["--tonemap", "reinhard_extended", "--white", "0"]).is_err());
+        assert!(run(&["--tonemap", "aces", "--white", "10"]).is_err());
+        assert!(run(&["--white", "10"]).is_err());
+    }
+
+    #[test]
+    fn test_hdr_formats() {
+        assert!(options(&["-o", "beauty.EXR"]).format == OpenExr);
+        assert!(options(&["-o", "result", "-f", "pfm"]).format == Pfm);
+    }
+}
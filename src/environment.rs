@@ -0,0 +1,225 @@
+use cgmath::vector::Vector3;
+use std::f32::consts::PI;
+
+use hdr;
+use light::Light;
+use sampling::search_cdf;
+
+/// Light coming from infinitely far away, seen by rays leaving the scene.
+pub trait Environment {
+    fn radiance(&self, direction: Vector3<f32>) -> Light;
+    /// Samples a direction from two uniform numbers, returning it with its
+    /// density per unit solid angle, or `None` if the environment is not
+    /// worth sampling.
+    fn sample(&self, u: f32, v: f32) -> Option<(Vector3<f32>, f32)>;
+    /// Density of `sample` returning `direction`.
+    fn pdf(&self, direction: Vector3<f32>) -> f32;
+}
+
+/// The same light in every direction. It is left to material sampling,
+/// which is already proportional to the cosine term.
+pub struct ConstantBackground {
+    color: Light
+}
+
+impl ConstantBackground {
+    pub fn new(color: Light) -> ConstantBackground {
+        ConstantBackground {color: color}
+    }
+}
+
+impl Environment for ConstantBackground {
+    fn radiance(&self, _: Vector3<f32>) -> Light {
+        self.color
+    }
+
+    fn sample(&self, _: f32, _: f32) -> Option<(Vector3<f32>, f32)> {
+        None
+    }
+
+    fn pdf(&self, _: Vector3<f32>) -> f32 {
+        0.0
+    }
+}
+
+/// Rotates `v` by `angle` radians around the y axis.
+fn rotate_y(v: Vector3<f32>, angle: f32) -> Vector3<f32> {
+    let (sin, cos) = (angle.sin(), angle.cos());
+    Vector3::new(v.x * cos - v.z * sin, v.y, v.x * sin + v.z * cos)
+}
+
+/// Image of the whole sphere of directions in the equirectangular
+/// (latitude-longitude) projection: x is the angle around the y axis, as in
+/// `Sphere::uv`, and the rows go from +y at the top down to -y.
+///
+/// Directions are importance sampled by the luminance of the pixels, so
+/// that small bright areas such as the sun are found by shadow rays.
+pub struct EnvironmentMap {
+    width: uint,
+    height: uint,
+    pixels: Vec<Light>,
+    /// Around the y axis, in radians.
+    rotation: f32,
+    /// Probability of picking each pixel, rows from top to bottom.
+    probabilities: Vec<f32>,
+    /// Cumulated probabilities of the rows.
+    rows: Vec<f32>,
+    /// Cumulated probabilities of the pixels within each row, normalized.
+    columns: Vec<f32>
+}
+
+impl EnvironmentMap {
+    pub fn new(width: uint, height: uint, pixels: Vec<Light>) -> EnvironmentMap {
+        assert!(width > 0 && height > 0 && pixels.len() == width * height);
+        // Rows near the poles cover less solid angle.
+        let mut weights = Vec::with_capacity(width * height);
+        for y in range(0, height) {
+            let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+            for x in range(0, width) {
+                let p = pixels[y * width + x];
+                let luminance = 0.2126 * p.red + 0.7152 * p.green + 0.0722 * p.blue;
+                weights.push(luminance.max(0.0) * sin_theta);
+            }
+        }
+        let total = weights.iter().fold(0.0, |a, &w| a + w);
+        let mut rows = Vec::with_capacity(height);
+        let mut columns = Vec::with_capacity(width * height);
+        let mut row_sum = 0.0;
+        for y in range(0, height) {
+            let row = weights.slice(y * width, (y + 1) * width);
+            let row_total = row.iter().fold(0.0, |a, &w| a + w);
+            let mut sum = 0.0;
+            for (x, &w) in row.iter().enumerate() {
+                // Rows which are never picked get a uniform distribution.
+                sum += if row_total > 0.0 { w / row_total } else { 1.0 / width as f32 };
+                columns.push(if x == width - 1 { 1.0 } else { sum });
+            }
+            row_sum += row_total;
+            rows.push(if total > 0.0 { row_sum / total } else { 0.0 });
+        }
+        let probabilities = weights.iter().map(|&w| if total > 0.0 { w / total } else { 0.0 }).collect();
+        EnvironmentMap {
+            width: width,
+            height: height,
+            pixels: pixels,
+            rotation: 0.0,
+            probabilities: probabilities,
+            rows: rows,
+            columns: columns
+        }
+    }
+
+    /// Loads a Radiance `.hdr` or PFM image.
+    pub fn load(path: &Path) -> Result<EnvironmentMap, String> {
+        let image = try!(hdr::load(path));
+        Ok(EnvironmentMap::new(image.width, image.height, image.pixels))
+    }
+
+    /// Turns the environment by `degrees` around the y axis.
+    pub fn with_rotation(self, degrees: f32) -> EnvironmentMap {
+        EnvironmentMap {rotation: degrees.to_radians(), ..self}
+    }
+
+    /// Pixel seen in `direction`, and the sine of its polar angle.
+    fn locate(&self, direction: Vector3<f32>) -> (uint, f32) {
+        let d = rotate_y(direction, -self.rotation);
+        let length = (d.x * d.x + d.y * d.y + d.z * d.z).sqrt();
+        let cos_theta = (d.y / length).max(-1.0).min(1.0);
+        let u = (d.z.atan2(d.x) + PI) / (2.0 * PI);
+        let v = cos_theta.acos() / PI;
+        let x = ((u * self.width as f32) as uint).min(self.width - 1);
+        let y = ((v * self.height as f32) as uint).min(self.height - 1);
+        (y * self.width + x, (1.0 - cos_theta * cos_theta).max(0.0).sqrt())
+    }
+
+    /// Converts the probability of a pixel to a density per solid angle.
+    fn density(&self, probability: f32, sin_theta: f32) -> f32 {
+        if sin_theta <= 0.0 { return 0.0; }
+        // Pixels are uniform in (u, v), and dw = 2 PI^2 sin(theta) du dv.
+        probability * (self.width * self.height) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, direction: Vector3<f32>) -> Light {
+        self.pixels[self.locate(direction).val0()]
+    }
+
+    fn sample(&self, u: f32, v: f32) -> Option<(Vector3<f32>, f32)> {
+        if self.rows[self.height - 1] <= 0.0 {
+            return None;
+        }
+        // Picks a row, then a pixel in it, reusing the numbers within them.
+        let y = search_cdf(self.rows.as_slice(), u);
+        let row_start = if y == 0 { 0.0 } else { self.rows[y - 1] };
+        let fy = ((u - row_start) / (self.rows[y] - row_start)).max(0.0).min(1.0);
+        let columns = self.columns.slice(y * self.width, (y + 1) * self.width);
+        let x = search_cdf(columns, v);
+        let column_start = if x == 0 { 0.0 } else { columns[x - 1] };
+        let fx = ((v - column_start) / (columns[x] - column_start)).max(0.0).min(1.0);
+        let theta = PI * (y as f32 + fy) / self.height as f32;
+        let phi = 2.0 * PI * (x as f32 + fx) / self.width as f32 - PI;
+        let local = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+        let pdf = self.density(self.probabilities[y * self.width + x], theta.sin());
+        if pdf <= 0.0 { None } else { Some((rotate_y(local, self.rotation), pdf)) }
+    }
+
+    fn pdf(&self, direction: Vector3<f32>) -> f32 {
+        let (i, sin_theta) = self.locate(direction);
+        self.density(self.probabilities[i], sin_theta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::vector::{EuclideanVector, Vector3};
+    use std::f32::consts::PI;
+    use std::rand::{Rng, XorShiftRng};
+
+    use environment::{Environment, EnvironmentMap};
+    use light::Light;
+
+    /// 8x4 dim map, but for one bright pixel below the horizon.
+    fn make_map(bright: f32) -> EnvironmentMap {
+        let mut pixels = Vec::from_elem(32, Light::white(0.1));
+        *pixels.get_mut(2 * 8 + 5) = Light::white(bright);
+        EnvironmentMap::new(8, 4, pixels)
+    }
+
+    #[test]
+    fn test_environment_lookup() {
+        let map = make_map(100.0);
+        assert!(map.radiance(Vector3::new(0.0, 1.0, 0.0)) == Light::white(0.1));
+        // Pixel column 5 of 8 is centered on u = 11/16.
+        let phi = 2.0 * PI * 11.0 / 16.0 - PI;
+        let theta = PI * 2.5 / 4.0;
+        let bright = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+        assert!(map.radiance(bright) == Light::white(100.0));
+        // Turning the map turns the bright pixel with it.
+        let turned = map.with_rotation(90.0);
+        let bright_turned = Vector3::new(-bright.z, bright.y, bright.x);
+        assert!(turned.radiance(bright_turned) == Light::white(100.0));
+        assert!(turned.radiance(bright) == Light::white(0.1));
+    }
+
+    #[test]
+    fn test_environment_sampling() {
+        let map = make_map(2.0).with_rotation(30.0);
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 20000u;
+        let mut solid_angle = 0.0;
+        let mut bright = 0u;
+        for _ in range(0, n) {
+            let (dir, pdf) = map.sample(rng.gen(), rng.gen()).unwrap();
+            assert!((dir.length() - 1.0).abs() < 0.0001);
+            assert!((map.pdf(dir) - pdf).abs() <= 0.001 * pdf);
+            solid_angle += 1.0 / pdf;
+            if map.radiance(dir) == Light::white(2.0) { bright += 1; }
+        }
+        // The densities integrate to one over the sphere, and about half of
+        // the samples go to the bright pixel, which has half of the power.
+        assert!((solid_angle / n as f32 - 4.0 * PI).abs() < 0.3);
+        assert!(bright > n * 2 / 5 && bright < n * 3 / 5);
+    }
+}
@@ -0,0 +1,143 @@
+extern crate image;
+
+use image::GenericImage;
+use std::io::File;
+use std::num::Bounded;
+
+use hdr;
+use light::Light;
+use tonemap::ToneMap;
+
+/// File formats the film can be saved in.
+#[deriving(Clone, PartialEq, Show)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Ppm,
+    /// Uncompressed 32 bit float OpenEXR.
+    OpenExr,
+    /// Radiance RGBE.
+    RadianceHdr,
+    /// Portable float map.
+    Pfm
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "png" => Some(Png),
+            "jpg" | "jpeg" => Some(Jpeg),
+            "ppm" => Some(Ppm),
+            "exr" => Some(OpenExr),
+            "hdr" => Some(RadianceHdr),
+            "pfm" => Some(Pfm),
+            _ => None
+        }
+    }
+}
+
+/// Linear floating point RGB image the renderer writes its pixels into,
+/// rows from top to bottom.
+pub struct Film {
+    pub width: u32,
+    pub height: u32,
+    pixels: Vec<Light>
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32) -> Film {
+        Film {width: width, height: height, pixels: Vec::from_elem((width * height) as uint, Light::zero())}
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> Light {
+        self.pixels[(y * self.width + x) as uint]
+    }
+
+    pub fn set(&mut self, x: u32, y: u32, light: Light) {
+        *self.pixels.get_mut((y * self.width + x) as uint) = light;
+    }
+
+    pub fn pixels(&self) -> &[Light] {
+        self.pixels.as_slice()
+    }
+
+    /// Brings the light to [0, 1] with `tone_map`, after `exposure` stops,
+    /// and quantizes it to 8 bits per channel.
+    pub fn to_rgb8(&self, tone_map: ToneMap, exposure: f32) -> image::ImageBuf<image::Rgb<u8>> {
+        let mut buffer = image::ImageBuf::new(self.width, self.height);
+        for y in range(0, self.height) {
+            for x in range(0, self.width) {
+                buffer.put_pixel(x, y, color_from_light(tone_map.apply(self.get(x, y), exposure)));
+            }
+        }
+        buffer
+    }
+
+    /// Saves the film, tone mapped for 8 bit formats and linear otherwise.
+    pub fn save(&self, path: &Path, format: OutputFormat, tone_map: ToneMap, exposure: f32) -> Result<(), String> {
+        let mut fout = try!(File::create(path).map_err(|e| e.to_string()));
+        let (width, height) = (self.width as uint, self.height as uint);
+        let written = match format {
+            OpenExr => hdr::write_exr(&mut fout, width, height, self.pixels()),
+            RadianceHdr => hdr::write_hdr(&mut fout, width, height, self.pixels()),
+            Pfm => hdr::write_pfm(&mut fout, width, height, self.pixels()),
+            Png | Jpeg | Ppm => return self.save_ldr(fout, format, tone_map, exposure)
+        };
+        written.map_err(|e| e.to_string())
+    }
+
+    fn save_ldr(&self, fout: File, format: OutputFormat, tone_map: ToneMap, exposure: f32) -> Result<(), String> {
+        let format = match format {
+            Jpeg => image::JPEG,
+            Ppm => image::PPM,
+            _ => image::PNG
+        };
+        image::ImageRgb8(self.to_rgb8(tone_map, exposure)).save(fout, format).map_err(|e| e.to_string())
+    }
+}
+
+fn color_from_light(light: Light) -> image::Rgb<u8> {
+    image::Rgb(convert(light.red), convert(light.green), convert(light.blue))
+}
+
+fn convert(x: f32) -> u8 {
+    let low: u8 = Bounded::min_value();
+    let high: u8 = Bounded::max_value();
+    let scaled = x * high as f32;
+    let fenced = scaled.max(low as f32).min(high as f32);
+    fenced.round() as u8
+}
+
+#[cfg(test)]
+mod tests {
+
+    extern crate image;
+
+    use image::GenericImage;
+    use film::{Film, OutputFormat, Jpeg, OpenExr};
+    use light::Light;
+    use tonemap::{LinearClamp, Reinhard};
+
+    #[test]
+    fn test_output_format() {
+        assert!(OutputFormat::from_name("jpg") == Some(Jpeg));
+        assert!(OutputFormat::from_name("exr") == Some(OpenExr));
+        assert!(OutputFormat::from_name("tiff") == None);
+    }
+
+    #[test]
+    fn test_film() {
+        let mut film = Film::new(3, 2);
+        film.set(2, 1, Light::new(4.0, 0.5, -1.0));
+        // The film keeps light above white, which 8 bits can only clamp.
+        assert!(film.get(2, 1) == Light::new(4.0, 0.5, -1.0));
+        assert!(film.get(1, 1) == Light::zero());
+        let rgb = film.to_rgb8(LinearClamp, 0.0);
+        assert!(rgb.get_pixel(2, 1) == image::Rgb(255, 128, 0));
+        assert!(rgb.get_pixel(0, 0) == image::Rgb(0, 0, 0));
+        // Tone mapping keeps some of it, here red at 4 / 5 and green at 1 / 3
+        // before sRGB encoding.
+        let mapped = film.to_rgb8(Reinhard, 0.0);
+        assert!(mapped.get_pixel(2, 1) == image::Rgb(231, 156, 0));
+    }
+}
@@ -0,0 +1,97 @@
+/// Pixel reconstruction filters.
+///
+/// Filters are separable: the weight of a sample is the product of the 1D
+/// filter evaluated at its offsets from the pixel center along each axis.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Filter {
+    /// Averages the samples falling inside the pixel.
+    BoxFilter,
+    /// Linear falloff down to zero at the given radius.
+    TentFilter(f32),
+    /// Gaussian of the given radius and falloff, shifted to reach zero at the radius.
+    GaussianFilter(f32, f32),
+    /// Mitchell-Netravali cubic with parameters B and C, of radius 2.
+    MitchellFilter(f32, f32)
+}
+
+impl Filter {
+    /// Parses a filter name, using common parameters for each kind.
+    pub fn from_name(name: &str) -> Option<Filter> {
+        match name {
+            "box" => Some(BoxFilter),
+            "tent" => Some(TentFilter(1.0)),
+            "gaussian" => Some(GaussianFilter(1.5, 2.0)),
+            "mitchell" => Some(MitchellFilter(1.0 / 3.0, 1.0 / 3.0)),
+            _ => None
+        }
+    }
+
+    /// Half width of the support of the filter, in pixels.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            BoxFilter => 0.5,
+            TentFilter(radius) => radius,
+            GaussianFilter(radius, _) => radius,
+            MitchellFilter(_, _) => 2.0
+        }
+    }
+
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        if x > self.radius() { return 0.0; }
+        match *self {
+            BoxFilter => 1.0,
+            TentFilter(radius) => 1.0 - x / radius,
+            GaussianFilter(radius, alpha) => (-alpha * x * x).exp() - (-alpha * radius * radius).exp(),
+            MitchellFilter(b, c) => {
+                let (x2, x3) = (x * x, x * x * x);
+                if x < 1.0 {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) / 6.0
+                } else {
+                    ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use filter::{Filter, BoxFilter, TentFilter, GaussianFilter, MitchellFilter};
+
+    #[test]
+    fn test_filter_from_name() {
+        assert!(Filter::from_name("box") == Some(BoxFilter));
+        assert!(Filter::from_name("tent") == Some(TentFilter(1.0)));
+        assert!(Filter::from_name("lanczos") == None);
+    }
+
+    #[test]
+    fn test_filter_support() {
+        for filter in [BoxFilter, TentFilter(1.0), GaussianFilter(1.5, 2.0), MitchellFilter(1.0 / 3.0, 1.0 / 3.0)].iter() {
+            let r = filter.radius();
+            assert!(filter.weight(0.0, 0.0) > 0.0);
+            assert!(filter.weight(r + 0.01, 0.0) == 0.0);
+            assert!(filter.weight(0.0, -r - 0.01) == 0.0);
+            assert!(filter.weight(0.3, 0.1) == filter.weight(-0.3, 0.1));
+            assert!(filter.weight(0.3, 0.1) <= filter.weight(0.0, 0.0));
+        }
+    }
+
+    #[test]
+    fn test_filter_values() {
+        assert!(BoxFilter.weight(0.49, -0.49) == 1.0);
+        assert!(TentFilter(2.0).weight(1.0, 0.0) == 0.5);
+        assert!(GaussianFilter(1.0, 2.0).weight(1.0, 0.0).abs() < 0.000001);
+        // The Mitchell-Netravali filter has negative lobes and is continuous.
+        let mitchell = MitchellFilter(1.0 / 3.0, 1.0 / 3.0);
+        assert!(mitchell.weight(1.5, 0.0) < 0.0);
+        assert!((mitchell.weight(0.9999, 0.0) - mitchell.weight(1.0001, 0.0)).abs() < 0.0001);
+        assert!(mitchell.weight(1.9999, 0.0).abs() < 0.0001);
+    }
+}
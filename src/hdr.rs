@@ -0,0 +1,392 @@
+//! High dynamic range images: Radiance RGBE (`.hdr`) and portable float
+//! maps (`.pfm`), which can be read and written, and OpenEXR, which can only
+//! be written.
+
+use std::io::{File, IoResult};
+use std::mem;
+
+use light::Light;
+
+/// Floating point RGB image, rows from top to bottom.
+pub struct HdrImage {
+    pub width: uint,
+    pub height: uint,
+    pub pixels: Vec<Light>
+}
+
+/// Loads an image, choosing the format from the file extension.
+pub fn load(path: &Path) -> Result<HdrImage, String> {
+    let data = try!(File::open(path).read_to_end().map_err(|e| format!("{}: {}", path.display(), e)));
+    let image = match path.extension_str() {
+        Some("hdr") => read_hdr(data.as_slice()),
+        Some("pfm") => read_pfm(data.as_slice()),
+        _ => Err("unknown HDR format, expected .hdr or .pfm".to_string())
+    };
+    image.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: uint
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, String> {
+        if self.pos >= self.data.len() {
+            return Err("unexpected end of file".to_string());
+        }
+        self.pos += 1;
+        Ok(self.data[self.pos - 1])
+    }
+
+    /// Reads up to the end of the line, which is consumed but not returned.
+    fn line(&mut self) -> Result<String, String> {
+        let mut line = vec![];
+        loop {
+            match try!(self.byte()) {
+                b'\n' => break,
+                c => line.push(c)
+            }
+        }
+        String::from_utf8(line).map_err(|_| "invalid header".to_string())
+    }
+
+    /// Reads a word delimited by whitespace, and the whitespace after it.
+    fn word(&mut self) -> Result<String, String> {
+        let mut word = vec![];
+        loop {
+            let c = try!(self.byte());
+            if (c as char).is_whitespace() {
+                if !word.is_empty() { break; }
+            } else {
+                word.push(c);
+            }
+        }
+        String::from_utf8(word).map_err(|_| "invalid header".to_string())
+    }
+}
+
+fn parse<T: FromStr>(word: &str) -> Result<T, String> {
+    from_str(word).ok_or(format!("invalid number `{}`", word))
+}
+
+/// Reads a PFM image: color (`PF`) or grayscale (`Pf`) floats, stored from
+/// the bottom row up, little endian if the scale is negative.
+pub fn read_pfm(data: &[u8]) -> Result<HdrImage, String> {
+    let mut reader = Reader {data: data, pos: 0};
+    let channels = match try!(reader.word()).as_slice() {
+        "PF" => 3u,
+        "Pf" => 1u,
+        _ => return Err("not a PFM file".to_string())
+    };
+    let width: uint = try!(parse(try!(reader.word()).as_slice()));
+    let height: uint = try!(parse(try!(reader.word()).as_slice()));
+    let scale: f32 = try!(parse(try!(reader.word()).as_slice()));
+    let mut floats = Vec::with_capacity(width * height * channels);
+    for _ in range(0, width * height * channels) {
+        let mut bytes = [0u8, ..4];
+        for b in bytes.iter_mut() {
+            *b = try!(reader.byte());
+        }
+        let bits = if scale < 0.0 {
+            (bytes[3] as u32 << 24) | (bytes[2] as u32 << 16) | (bytes[1] as u32 << 8) | bytes[0] as u32
+        } else {
+            (bytes[0] as u32 << 24) | (bytes[1] as u32 << 16) | (bytes[2] as u32 << 8) | bytes[3] as u32
+        };
+        floats.push(unsafe { mem::transmute::<u32, f32>(bits) });
+    }
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in range(0, height) {
+        let row = height - 1 - y;
+        for x in range(0, width) {
+            let i = (row * width + x) * channels;
+            pixels.push(if channels == 3 {
+                Light::new(floats[i], floats[i + 1], floats[i + 2])
+            } else {
+                Light::white(floats[i])
+            });
+        }
+    }
+    Ok(HdrImage {width: width, height: height, pixels: pixels})
+}
+
+/// Reads a Radiance RGBE image, flat or run-length encoded, in the usual
+/// top to bottom, left to right orientation.
+pub fn read_hdr(data: &[u8]) -> Result<HdrImage, String> {
+    let mut reader = Reader {data: data, pos: 0};
+    if !try!(reader.line()).as_slice().starts_with("#?") {
+        return Err("not a Radiance HDR file".to_string());
+    }
+    loop {
+        let line = try!(reader.line());
+        if line.as_slice().trim().is_empty() { break; }
+        if line.as_slice().starts_with("FORMAT=") && line.as_slice() != "FORMAT=32-bit_rle_rgbe" {
+            return Err(format!("unsupported {}", line));
+        }
+    }
+    let resolution = try!(reader.line());
+    let words: Vec<&str> = resolution.as_slice().words().collect();
+    if words.len() != 4 || words[0] != "-Y" || words[2] != "+X" {
+        return Err(format!("unsupported orientation `{}`", resolution));
+    }
+    let height: uint = try!(parse(words[1]));
+    let width: uint = try!(parse(words[3]));
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut scanline = Vec::from_elem(width * 4, 0u8);
+    for _ in range(0, height) {
+        try!(read_scanline(&mut reader, scanline.as_mut_slice(), width));
+        for x in range(0, width) {
+            let rgbe = scanline.slice(x * 4, x * 4 + 4);
+            pixels.push(rgbe_to_light(rgbe[0], rgbe[1], rgbe[2], rgbe[3]));
+        }
+    }
+    Ok(HdrImage {width: width, height: height, pixels: pixels})
+}
+
+/// Reads `width` RGBE pixels into `scanline`, decoding the run-length
+/// encoding where each channel is stored separately.
+fn read_scanline(reader: &mut Reader, scanline: &mut [u8], width: uint) -> Result<(), String> {
+    let data = reader.data;
+    let pos = reader.pos;
+    let encoded = width >= 8 && width < 32768 && pos + 4 <= data.len() &&
+        data[pos] == 2 && data[pos + 1] == 2 && data[pos + 2] & 0x80 == 0;
+    if !encoded {
+        for b in scanline.iter_mut() {
+            *b = try!(reader.byte());
+        }
+        return Ok(());
+    }
+    reader.pos += 4;
+    if ((data[pos + 2] as uint) << 8 | data[pos + 3] as uint) != width {
+        return Err("wrong scanline width".to_string());
+    }
+    for channel in range(0, 4u) {
+        let mut x = 0;
+        while x < width {
+            let count = try!(reader.byte()) as uint;
+            if count > 128 {
+                let value = try!(reader.byte());
+                if x + count - 128 > width { return Err("bad run length".to_string()); }
+                for _ in range(0, count - 128) {
+                    scanline[x * 4 + channel] = value;
+                    x += 1;
+                }
+            } else {
+                if count == 0 || x + count > width { return Err("bad run length".to_string()); }
+                for _ in range(0, count) {
+                    scanline[x * 4 + channel] = try!(reader.byte());
+                    x += 1;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a pixel with a shared exponent, as Radiance does.
+fn rgbe_to_light(r: u8, g: u8, b: u8, e: u8) -> Light {
+    if e == 0 {
+        return Light::zero();
+    }
+    let f = 2.0f32.powi(e as i32 - (128 + 8));
+    Light::new((r as f32 + 0.5) * f, (g as f32 + 0.5) * f, (b as f32 + 0.5) * f)
+}
+
+/// Writes a little endian color PFM image.
+pub fn write_pfm(out: &mut Writer, width: uint, height: uint, pixels: &[Light]) -> IoResult<()> {
+    try!(write!(out, "PF\n{} {}\n-1.0\n", width, height));
+    for y in range(0, height).rev() {
+        for p in pixels.slice(y * width, (y + 1) * width).iter() {
+            try!(out.write_le_f32(p.red));
+            try!(out.write_le_f32(p.green));
+            try!(out.write_le_f32(p.blue));
+        }
+    }
+    Ok(())
+}
+
+/// Writes a Radiance RGBE image with flat scanlines.
+pub fn write_hdr(out: &mut Writer, width: uint, height: uint, pixels: &[Light]) -> IoResult<()> {
+    try!(write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width));
+    for p in pixels.iter() {
+        try!(out.write(&light_to_rgbe(*p)));
+    }
+    Ok(())
+}
+
+/// Encodes a pixel with a shared exponent. Negative values become black.
+fn light_to_rgbe(light: Light) -> [u8, ..4] {
+    let (r, g, b) = (light.red.max(0.0), light.green.max(0.0), light.blue.max(0.0));
+    let m = r.max(g).max(b);
+    if m < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // Mantissas in [128, 256) for the largest channel.
+    let mut e = m.log2().floor() as i32 + 1;
+    if m * 2.0f32.powi(8 - e) >= 256.0 {
+        e += 1;
+    }
+    let f = 2.0f32.powi(8 - e);
+    [(r * f) as u8, (g * f) as u8, (b * f) as u8, (e + 128) as u8]
+}
+
+/// Writes an uncompressed OpenEXR scanline image with 32 bit float R, G and
+/// B channels.
+pub fn write_exr(out: &mut Writer, width: uint, height: uint, pixels: &[Light]) -> IoResult<()> {
+    let mut header = vec![];
+    {
+        let attribute = |name: &str, kind: &str, value: &[u8]| {
+            header.push_all(name.as_bytes());
+            header.push(0);
+            header.push_all(kind.as_bytes());
+            header.push(0);
+            header.push_all(&le_bytes(value.len() as u32));
+            header.push_all(value);
+        };
+        // Channels are listed in alphabetical order, all FLOAT (type 2)
+        // without subsampling.
+        let mut channels = vec![];
+        for name in ["B", "G", "R"].iter() {
+            channels.push_all(name.as_bytes());
+            channels.push_all(&[0u8, 2, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        channels.push(0);
+        attribute("channels", "chlist", channels.as_slice());
+        attribute("compression", "compression", &[0u8]);
+        let mut window = vec![];
+        for &v in [0u32, 0, width as u32 - 1, height as u32 - 1].iter() {
+            window.push_all(&le_bytes(v));
+        }
+        attribute("dataWindow", "box2i", window.as_slice());
+        attribute("displayWindow", "box2i", window.as_slice());
+        attribute("lineOrder", "lineOrder", &[0u8]);
+        attribute("pixelAspectRatio", "float", &le_bytes(unsafe { mem::transmute::<f32, u32>(1.0) }));
+        attribute("screenWindowCenter", "v2f", &[0u8, 0, 0, 0, 0, 0, 0, 0]);
+        attribute("screenWindowWidth", "float", &le_bytes(unsafe { mem::transmute::<f32, u32>(1.0) }));
+    }
+    header.push(0);
+    // Magic number and version 2, single part scanline file.
+    try!(out.write(&[0x76u8, 0x2f, 0x31, 0x01, 2, 0, 0, 0]));
+    try!(out.write(header.as_slice()));
+    // One line per chunk, after the table of their offsets in the file.
+    let line_size = width * 3 * 4;
+    let first_line = 8 + header.len() + height * 8;
+    for y in range(0, height) {
+        try!(out.write_le_u64((first_line + y * (8 + line_size)) as u64));
+    }
+    for y in range(0, height) {
+        try!(out.write_le_i32(y as i32));
+        try!(out.write_le_i32(line_size as i32));
+        let row = pixels.slice(y * width, (y + 1) * width);
+        for p in row.iter() { try!(out.write_le_f32(p.blue)); }
+        for p in row.iter() { try!(out.write_le_f32(p.green)); }
+        for p in row.iter() { try!(out.write_le_f32(p.red)); }
+    }
+    Ok(())
+}
+
+fn le_bytes(v: u32) -> [u8, ..4] {
+    [v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::io::MemWriter;
+    use std::mem;
+
+    use light::Light;
+    use hdr::{read_hdr, read_pfm, write_exr, write_hdr, write_pfm};
+
+    fn sample_pixels() -> Vec<Light> {
+        vec![Light::new(0.25, 1.0, 3.5), Light::zero(), Light::new(1000.0, 0.001, 1.0),
+             Light::white(0.5), Light::new(2.0, 4.0, 8.0), Light::new(0.0, 0.0, 16.0)]
+    }
+
+    #[test]
+    fn test_read_pfm() {
+        // 1x2 color image, little endian: the bottom row comes first.
+        let mut data = b"PF\n1 2\n-1.0\n".to_vec();
+        for bytes in [[0u8, 0, 0x80, 0x3f], [0, 0, 0, 0x40], [0, 0, 0, 0], // 1, 2, 0
+                      [0, 0, 0x40, 0x40], [0, 0, 0, 0], [0, 0, 0x80, 0x3f]].iter() { // 3, 0, 1
+            data.push_all(bytes.as_slice());
+        }
+        let image = read_pfm(data.as_slice()).unwrap();
+        assert!(image.width == 1 && image.height == 2);
+        assert!(image.pixels == vec![Light::new(3.0, 0.0, 1.0), Light::new(1.0, 2.0, 0.0)]);
+        assert!(read_pfm(b"P6\n1 1\n255\n").is_err());
+        assert!(read_pfm(b"PF\n1 1\n-1.0\n\0\0").is_err());
+    }
+
+    #[test]
+    fn test_read_hdr() {
+        // 8x1 run-length encoded image: red has a run and green literal values.
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        data.push_all(&[2u8, 2, 0, 8]);
+        data.push_all(&[128u8 + 8, 127]);
+        data.push_all(&[8u8, 0, 1, 2, 3, 4, 5, 6, 7]);
+        data.push_all(&[128u8 + 8, 0]);
+        data.push_all(&[128u8 + 8, 129]);
+        let image = read_hdr(data.as_slice()).unwrap();
+        assert!(image.width == 8 && image.height == 1);
+        // Exponent 129 scales the mantissa by 2 / 256.
+        assert!(image.pixels[0] == Light::new(127.5 / 128.0, 0.5 / 128.0, 0.5 / 128.0));
+        assert!(image.pixels[7].green == 7.5 / 128.0);
+        // Flat pixels, and a zero exponent.
+        let mut flat = b"#?RGBE\n\n-Y 1 +X 2\n".to_vec();
+        flat.push_all(&[64u8, 64, 64, 128, 10, 10, 10, 0]);
+        let image = read_hdr(flat.as_slice()).unwrap();
+        assert!(image.pixels == vec![Light::white(64.5 / 256.0), Light::zero()]);
+        assert!(read_hdr(b"#?RADIANCE\n\n+Y 1 +X 1\n\0\0\0\0").is_err());
+    }
+
+    #[test]
+    fn test_write_pfm() {
+        let pixels = sample_pixels();
+        let mut out = MemWriter::new();
+        write_pfm(&mut out, 3, 2, pixels.as_slice()).unwrap();
+        let image = read_pfm(out.get_ref()).unwrap();
+        assert!(image.width == 3 && image.height == 2);
+        assert!(image.pixels == pixels);
+    }
+
+    #[test]
+    fn test_write_hdr() {
+        let pixels = sample_pixels();
+        let mut out = MemWriter::new();
+        write_hdr(&mut out, 3, 2, pixels.as_slice()).unwrap();
+        let image = read_hdr(out.get_ref()).unwrap();
+        assert!(image.width == 3 && image.height == 2);
+        // Channels keep 8 bits relative to the brightest one of the pixel.
+        for (p, q) in pixels.iter().zip(image.pixels.iter()) {
+            let m = p.max_component();
+            assert!((p.red - q.red).abs() <= m / 128.0);
+            assert!((p.green - q.green).abs() <= m / 128.0);
+            assert!((p.blue - q.blue).abs() <= m / 128.0);
+        }
+        assert!(image.pixels[1] == Light::zero());
+    }
+
+    fn le_u32(data: &[u8], pos: uint) -> u32 {
+        (data[pos + 3] as u32 << 24) | (data[pos + 2] as u32 << 16) | (data[pos + 1] as u32 << 8) | data[pos] as u32
+    }
+
+    #[test]
+    fn test_write_exr() {
+        let pixels = sample_pixels();
+        let mut out = MemWriter::new();
+        write_exr(&mut out, 3, 2, pixels.as_slice()).unwrap();
+        let data = out.get_ref();
+        assert!(data.slice_to(8) == [0x76u8, 0x2f, 0x31, 0x01, 2, 0, 0, 0].as_slice());
+        // The second entry of the offset table, right after the header, points
+        // to the chunk of line 1: y, size, then the blue, green and red rows.
+        let header_end = data.len() - 2 * 8 - 2 * (8 + 3 * 3 * 4);
+        let offset = le_u32(data, header_end + 8) as uint;
+        assert!(data[header_end - 1] == 0 && le_u32(data, header_end + 4) == 0);
+        assert!(le_u32(data, offset) == 1 && le_u32(data, offset + 4) == 36);
+        let float = |i: uint| unsafe { mem::transmute::<u32, f32>(le_u32(data, offset + 8 + i * 4)) };
+        assert!(float(0) == 0.5 && float(2) == 16.0);
+        assert!(float(4) == 4.0 && float(6) == 0.5 && float(8) == 0.0);
+        assert!(offset + 8 + 36 == data.len());
+    }
+}
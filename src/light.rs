@@ -1,22 +1,156 @@
 use cgmath::point::{Point, Point3};
-use cgmath::vector::EuclideanVector;
+use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+use std::f32::consts::PI;
+use std::rand::{Rng, XorShiftRng};
 
-pub struct LightSource {
-    origin: Point3<f32>,
-    light: Light
+use sampling::{Frame, concentric_disk, uniform_hemisphere};
+
+/// Light reaching a point from a sampled point of a light source.
+pub struct LightSample {
+    /// Unit vector from the lit point towards the light.
+    pub direction: Vector3<f32>,
+    /// Distance to the sampled point, for shadow rays.
+    pub distance: f32,
+    /// Incident radiance divided by the density, with respect to solid
+    /// angle, of sampling `direction`.
+    pub light: Light
+}
+
+/// Light sampled directly from the surfaces it lights, which gives soft
+/// shadows without waiting for paths to hit an emitter.
+///
+/// Light sources are not part of the geometry: rays do not hit them.
+pub trait LightSource {
+    /// Samples the light arriving at `point`, or returns `None` if the
+    /// sampled part of the light does not face it.
+    fn sample(&self, point: Point3<f32>, rng: &mut XorShiftRng) -> Option<LightSample>;
+}
+
+/// Converts the radiance from a point sampled with density `pdf_area` on
+/// the light, where its normal is `normal`, to a sample seen from `point`.
+fn area_sample(point: Point3<f32>, sampled: Point3<f32>, normal: Vector3<f32>, radiance: Light, pdf_area: f32) -> Option<LightSample> {
+    let to_light = sampled.sub_p(&point);
+    let distance = to_light.length();
+    let direction = to_light.div_s(distance);
+    let cos_light = -dot(normal, direction);
+    if cos_light <= 0.0 || distance <= 0.0 {
+        return None;
+    }
+    // dA = r^2 / cos dw
+    let pdf_solid_angle = pdf_area * distance * distance / cos_light;
+    Some(LightSample {direction: direction, distance: distance, light: radiance.mul_s(1.0 / pdf_solid_angle)})
+}
+
+/// Light emitted in every direction from a single point, with an
+/// inverse-square falloff.
+pub struct PointLight {
+    position: Point3<f32>,
+    intensity: Light
+}
+
+impl PointLight {
+    pub fn new(position: Point3<f32>, intensity: Light) -> PointLight {
+        PointLight {position: position, intensity: intensity}
+    }
+}
+
+impl LightSource for PointLight {
+    fn sample(&self, point: Point3<f32>, _: &mut XorShiftRng) -> Option<LightSample> {
+        let to_light = self.position.sub_p(&point);
+        let d2 = to_light.length2();
+        let distance = d2.sqrt();
+        Some(LightSample {direction: to_light.div_s(distance), distance: distance, light: self.intensity.mul_s(1.0 / d2)})
+    }
+}
+
+/// Parallelogram spanned by two edges from a corner, emitting `radiance`
+/// on the side of `edge1 x edge2`.
+pub struct RectangleLight {
+    corner: Point3<f32>,
+    edge1: Vector3<f32>,
+    edge2: Vector3<f32>,
+    normal: Vector3<f32>,
+    area: f32,
+    radiance: Light
+}
+
+impl RectangleLight {
+    /// Fails if the edges are parallel, or one of them is zero.
+    pub fn new(corner: Point3<f32>, edge1: Vector3<f32>, edge2: Vector3<f32>, radiance: Light) -> Result<RectangleLight, String> {
+        let cross = edge1.cross(&edge2);
+        let area = cross.length();
+        if !(area > 0.0) || !area.is_finite() {
+            return Err("the edges of a rectangle light cannot be parallel".to_string());
+        }
+        Ok(RectangleLight {corner: corner, edge1: edge1, edge2: edge2, normal: cross.div_s(area), area: area, radiance: radiance})
+    }
+}
+
+impl LightSource for RectangleLight {
+    fn sample(&self, point: Point3<f32>, rng: &mut XorShiftRng) -> Option<LightSample> {
+        let sampled = self.corner.add_v(&self.edge1.mul_s(rng.gen())).add_v(&self.edge2.mul_s(rng.gen()));
+        area_sample(point, sampled, self.normal, self.radiance, 1.0 / self.area)
+    }
+}
+
+/// Disk emitting `radiance` on the side of its normal.
+pub struct DiskLight {
+    center: Point3<f32>,
+    frame: Frame,
+    radius: f32,
+    radiance: Light
 }
 
-impl LightSource {
-    pub fn new(o: Point3<f32>, l: Light) -> LightSource {
-        LightSource {origin: o, light: l}
+impl DiskLight {
+    /// Fails if the normal is zero or the radius not positive.
+    pub fn new(center: Point3<f32>, normal: Vector3<f32>, radius: f32, radiance: Light) -> Result<DiskLight, String> {
+        let length = normal.length();
+        if !(length > 0.0) || !length.is_finite() {
+            return Err("the normal of a disk light cannot be zero".to_string());
+        }
+        if !(radius > 0.0) {
+            return Err("the radius of a disk light must be positive".to_string());
+        }
+        Ok(DiskLight {center: center, frame: Frame::from_normal(normal.div_s(length)), radius: radius, radiance: radiance})
     }
-    pub fn intensity(&self, point: Point3<f32>) -> Light {
-        let d2 = point.sub_p(&self.origin).length2();
-        let falloff = 1.0/(1.0 + d2);
-        self.light.mul_s(falloff)
+}
+
+impl LightSource for DiskLight {
+    fn sample(&self, point: Point3<f32>, rng: &mut XorShiftRng) -> Option<LightSample> {
+        let (x, y) = concentric_disk(rng.gen(), rng.gen());
+        let sampled = self.center.add_v(&self.frame.to_world(Vector3::new(x, y, 0.0)).mul_s(self.radius));
+        area_sample(point, sampled, self.frame.n, self.radiance, 1.0 / (PI * self.radius * self.radius))
+    }
+}
+
+/// Sphere emitting `radiance` outwards.
+pub struct SphereLight {
+    center: Point3<f32>,
+    radius: f32,
+    radiance: Light
+}
+
+impl SphereLight {
+    /// Fails if the radius is not positive.
+    pub fn new(center: Point3<f32>, radius: f32, radiance: Light) -> Result<SphereLight, String> {
+        if !(radius > 0.0) {
+            return Err("the radius of a sphere light must be positive".to_string());
+        }
+        Ok(SphereLight {center: center, radius: radius, radiance: radiance})
     }
+}
 
-    pub fn origin(&self) -> Point3<f32> { self.origin }
+impl LightSource for SphereLight {
+    fn sample(&self, point: Point3<f32>, rng: &mut XorShiftRng) -> Option<LightSample> {
+        // Only the half of the sphere facing the point can be seen from it.
+        let towards = point.sub_p(&self.center);
+        if towards.length2() <= self.radius * self.radius {
+            return None;
+        }
+        let normal = Frame::from_normal(towards.normalize()).to_world(uniform_hemisphere(rng.gen(), rng.gen()));
+        let sampled = self.center.add_v(&normal.mul_s(self.radius));
+        area_sample(point, sampled, normal, self.radiance, 1.0 / (2.0 * PI * self.radius * self.radius))
+    }
 }
 
 #[deriving(PartialEq, Show)]
@@ -46,10 +180,75 @@ impl Light {
     pub fn white(power: f32) -> Light {
         Light::new(power, power, power)
     }
+
+    pub fn max_component(&self) -> f32 {
+        self.red.max(self.green).max(self.blue)
+    }
 }
 
 impl Add<Light, Light> for Light {
     fn add(&self, other: &Light) -> Light {
         Light::new(self.red + other.red, self.green + other.green, self.blue + other.blue)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::point::{Point, Point3};
+    use cgmath::vector::{EuclideanVector, Vector3};
+    use std::f32::consts::PI;
+    use std::rand::XorShiftRng;
+
+    use light::{Light, LightSource, PointLight, RectangleLight, DiskLight, SphereLight};
+
+    /// Irradiance at the origin, on a surface facing +z, estimated from `n` samples.
+    fn irradiance(source: &LightSource, n: uint) -> f32 {
+        let mut rng = XorShiftRng::new_unseeded();
+        let mut total = 0.0;
+        for _ in range(0, n) {
+            match source.sample(Point::origin(), &mut rng) {
+                Some(sample) => total += sample.light.red * sample.direction.z.max(0.0),
+                None => ()
+            }
+        }
+        total / n as f32
+    }
+
+    #[test]
+    fn test_point_light_falloff() {
+        let light = PointLight::new(Point3::new(0.0, 0.0, 2.0), Light::white(4.0));
+        let sample = light.sample(Point::origin(), &mut XorShiftRng::new_unseeded()).unwrap();
+        assert!(sample.light == Light::white(1.0));
+        assert!(sample.distance == 2.0 && sample.direction == Vector3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_area_lights_irradiance() {
+        // A disk of radius r at height h, facing down, gives an irradiance
+        // of PI L r^2 / (h^2 + r^2) on its axis.
+        let disk = DiskLight::new(Point3::new(0.0, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0), 1.0, Light::white(1.0)).unwrap();
+        assert!((irradiance(&disk, 20000) - PI / 2.0).abs() < 0.02);
+        // A sphere of radius r at distance d gives PI L (r / d)^2.
+        let sphere = SphereLight::new(Point3::new(0.0, 0.0, 2.0), 1.0, Light::white(1.0)).unwrap();
+        assert!((irradiance(&sphere, 20000) - PI / 4.0).abs() < 0.02);
+        // A square of side 2 at height 1: 2 sqrt(2) atan(1 / sqrt(2)) L.
+        let square = RectangleLight::new(Point3::new(-1.0, -1.0, 1.0), Vector3::new(0.0, 2.0, 0.0), Vector3::new(2.0, 0.0, 0.0), Light::white(1.0)).unwrap();
+        let expected = 2.0 * 2.0f32.sqrt() * (1.0 / 2.0f32.sqrt()).atan();
+        assert!((irradiance(&square, 20000) - expected).abs() < 0.03);
+        // Area lights only emit on one side.
+        let away = RectangleLight::new(Point3::new(-1.0, -1.0, 1.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 2.0, 0.0), Light::white(1.0)).unwrap();
+        assert!(irradiance(&away, 100) == 0.0);
+    }
+
+    #[test]
+    fn test_degenerate_area_lights() {
+        let (origin, white) = (Point3::new(0.0f32, 0.0, 0.0), Light::white(1.0));
+        let x = Vector3::new(1.0f32, 0.0, 0.0);
+        assert!(RectangleLight::new(origin, x, Vector3::new(2.0, 0.0, 0.0), white).is_err());
+        assert!(RectangleLight::new(origin, x, Vector3::new(0.0, 0.0, 0.0), white).is_err());
+        assert!(DiskLight::new(origin, Vector3::new(0.0, 0.0, 0.0), 1.0, white).is_err());
+        assert!(DiskLight::new(origin, x, 0.0, white).is_err());
+        assert!(SphereLight::new(origin, -1.0, white).is_err());
+    }
+}
@@ -1,42 +1,84 @@
 use light::Light;
-use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
-use cgmath::point::{Point, Point3};
-use cgmath::ray::{Ray, Ray3};
-use std::rand;
-use std::rand::Rng;
-use std::rand::distributions::{IndependentSample, Range};
-use std::f32;
+use cgmath::vector::{dot, Vector, Vector3};
+use std::rand::{Rng, XorShiftRng};
+use std::f32::consts::PI;
 
+use sampling::{Frame, cosine_hemisphere};
+use shape::SurfacePoint;
+use texture::Texture;
+
+/// Direction sampled from a material, to continue a path with.
+pub struct BsdfSample {
+    pub direction: Vector3<f32>,
+    /// Value of the BSDF times the cosine of `direction` with the normal,
+    /// divided by `pdf`. This is the factor applied to the path throughput.
+    pub weight: Light,
+    pub pdf: f32,
+    /// Whether the direction comes from a Dirac distribution (mirror,
+    /// glass), which `eval` and `pdf` do not account for.
+    pub specular: bool
+}
+
+/// Surface scattering.
+///
+/// `dir_in` is the direction of the incoming ray, towards the surface, and
+/// `dir_out` the direction leaving it. The normal can be on either side.
 pub trait Material {
-    fn emittance(&self, _n: Vector3<f32>, _dir: Vector3<f32>) -> Light {
+    fn emittance(&self, _surface: &SurfacePoint, _dir: Vector3<f32>) -> Light {
         Light::zero()
     }
-    fn reflectance(&self, _normal: Vector3<f32>, _dir_in: Vector3<f32>, _dir_out: Vector3<f32>) -> Light {
+    /// Whether `emittance` can be non-zero, so that paths look for the
+    /// object as a light.
+    fn emits(&self) -> bool {
+        false
+    }
+    /// Fraction of the light arriving along `dir_in` from a light source
+    /// which is reflected towards `dir_out`: the BSDF times the cosine term.
+    fn reflectance(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        self.eval(surface, dir_in, dir_out).mul_s(dot(surface.normal, dir_in).abs())
+    }
+    /// Value of the BSDF, without the cosine term.
+    fn eval(&self, _surface: &SurfacePoint, _dir_in: Vector3<f32>, _dir_out: Vector3<f32>) -> Light {
         Light::zero()
     }
-    fn next_step(&self, _point: Point3<f32>, _n: Vector3<f32>, _dir_in: Vector3<f32>, _tracer: |Ray3<f32>| -> Light) -> Light {
+    /// Density, with respect to solid angle, of sampling `dir_out`.
+    fn pdf(&self, _surface: &SurfacePoint, _dir_in: Vector3<f32>, _dir_out: Vector3<f32>) -> f32 {
+        0.0
+    }
+    /// Samples an outgoing direction, or returns `None` when the path ends here.
+    fn sample(&self, _surface: &SurfacePoint, _dir_in: Vector3<f32>, _rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        None
+    }
+    /// Fraction of each channel absorbed per unit of distance travelled
+    /// inside the object, for materials letting light through.
+    fn absorption(&self) -> Light {
         Light::zero()
     }
 }
 
 pub struct DiffuseMaterial {
-    pub diffuse: Light,
+    pub diffuse: Box<Texture + Send + Sync>,
     // pub specular: Light,
     // pub shininess: f32
 }
 
 impl DiffuseMaterial {
     pub fn new(r: f32, g: f32, b: f32) -> DiffuseMaterial {
-        DiffuseMaterial { diffuse: Light::new(r, g, b) }
+        DiffuseMaterial::textured(box Light::new(r, g, b))
+    }
+
+    pub fn textured(diffuse: Box<Texture + Send + Sync>) -> DiffuseMaterial {
+        DiffuseMaterial { diffuse: diffuse }
     }
 }
 
 impl Material for DiffuseMaterial {
-    fn reflectance(&self, n: Vector3<f32>, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+    fn reflectance(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        let n = surface.normal;
         let proj = dot(n, -dir_in);
         if proj * dot(n, dir_out) > 0.0 {
             let proj = proj.abs();
-            self.diffuse.mul_s(proj)
+            self.diffuse.color(surface).mul_s(proj / PI)
             // let dir_in_reflection = dir_in.add_v(&n.mul_s(2.0).mul_s(proj));
             // let alignment = dot(dir_out, dir_in_reflection);
             // let specular = self.specular.mul_s(alignment.powf(self.shininess));
@@ -47,129 +89,181 @@ impl Material for DiffuseMaterial {
     }
 }
 
+/// Lambertian surface reflecting light from the whole scene.
 pub struct GlobalDiffuseMaterial {
-    diffuse: Light,
-    n_rays: uint,
+    diffuse: Box<Texture + Send + Sync>
 }
 
 impl GlobalDiffuseMaterial {
-    pub fn new(r: f32, g: f32, b:f32, n: uint) -> GlobalDiffuseMaterial {
-        GlobalDiffuseMaterial { diffuse: Light::new(r,g,b), n_rays: n }
+    pub fn new(r: f32, g: f32, b:f32) -> GlobalDiffuseMaterial {
+        GlobalDiffuseMaterial::textured(box Light::new(r,g,b))
     }
-}
 
-fn unit_vec_from_angles(theta: f32, phi: f32) -> Vector3<f32> {
-    Vector3::new(
-        theta.sin() * phi.cos(),
-        theta.sin() * phi.sin(),
-        theta.cos()
-    )
+    pub fn textured(diffuse: Box<Texture + Send + Sync>) -> GlobalDiffuseMaterial {
+        GlobalDiffuseMaterial { diffuse: diffuse }
+    }
 }
 
 impl Material for GlobalDiffuseMaterial {
-    fn reflectance(&self, n: Vector3<f32>, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
-        let proj = dot(n, -dir_in);
-        if proj * dot(n, dir_out) > 0.0 {
-            let proj = proj.abs();
-            self.diffuse.mul_s(proj)
+    fn eval(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        let n = surface.normal;
+        if dot(n, -dir_in) * dot(n, dir_out) > 0.0 {
+            self.diffuse.color(surface).mul_s(1.0 / PI)
         } else {
             Light::zero()
         }
     }
-    fn next_step(&self, point: Point3<f32>, n: Vector3<f32>, dir_in: Vector3<f32>, tracer: |Ray3<f32>| -> Light) -> Light {
-        let mut received = Light::zero();
-        let proj_in = dot(n, dir_in);
-        let mut rng = rand::task_rng();
-        let between = Range::new(-f32::consts::PI, f32::consts::PI);
-        for i in range(0, self.n_rays) {
-            let mut dir_out = unit_vec_from_angles(between.ind_sample(&mut rng), between.ind_sample(&mut rng));
-            let proj_out = dot(dir_out, n);
-            if proj_out * proj_in > 0.0 {
-                dir_out = -dir_out;
-            }
-            received = received + tracer(Ray::new(point, dir_out.normalize())).mul_s(proj_out.abs());
-        }
-        self.diffuse.mul_l(received.mul_s(1.0 / self.n_rays as f32))
+    fn pdf(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> f32 {
+        let n = surface.normal;
+        if dot(n, -dir_in) * dot(n, dir_out) > 0.0 { dot(n, dir_out).abs() / PI } else { 0.0 }
+    }
+    fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        let n = surface.normal;
+        // Cosine-weighted on the hemisphere on the side the ray comes from.
+        // The cosine and the 1/PI of the BSDF cancel out with the pdf.
+        let side = if dot(n, dir_in) > 0.0 { -n } else { n };
+        let local = cosine_hemisphere(rng.gen(), rng.gen());
+        let dir_out = Frame::from_normal(side).to_world(local);
+        Some(BsdfSample {direction: dir_out, weight: self.diffuse.color(surface), pdf: local.z / PI, specular: false})
     }
 }
 
 pub struct EmitterMaterial {
-    emittance: Light
+    emittance: Box<Texture + Send + Sync>
 }
 
 impl EmitterMaterial {
     pub fn new(r: f32, g: f32, b: f32) -> EmitterMaterial {
-        EmitterMaterial { emittance: Light::new(r,g,b) }
+        EmitterMaterial::textured(box Light::new(r,g,b))
+    }
+
+    pub fn textured(emittance: Box<Texture + Send + Sync>) -> EmitterMaterial {
+        EmitterMaterial { emittance: emittance }
     }
 }
 
 impl Material for EmitterMaterial {
-    fn emittance(&self, n: Vector3<f32>, dir: Vector3<f32>) -> Light {
-        self.emittance.mul_s(dot(n, dir))
+    fn emittance(&self, surface: &SurfacePoint, dir: Vector3<f32>) -> Light {
+        let n = surface.normal;
+        self.emittance.color(surface).mul_s(dot(n, dir))
+    }
+    fn emits(&self) -> bool {
+        true
     }
 }
 
 pub struct ReflectiveMaterial {
-    color: Light
+    color: Box<Texture + Send + Sync>
 }
 
 impl ReflectiveMaterial {
     pub fn new(r: f32, g: f32, b: f32) -> ReflectiveMaterial {
-        ReflectiveMaterial { color: Light::new(r,g,b) }
+        ReflectiveMaterial::textured(box Light::new(r,g,b))
+    }
+
+    pub fn textured(color: Box<Texture + Send + Sync>) -> ReflectiveMaterial {
+        ReflectiveMaterial { color: color }
     }
 }
 
 impl Material for ReflectiveMaterial {
-    fn next_step(&self, point: Point3<f32>, n: Vector3<f32>, dir_in: Vector3<f32>, tracer: |Ray3<f32>| -> Light) -> Light {
-        let reflected_dir = dir_in.sub_v(&n.mul_s(2.0 * dot(n, dir_in)));
-        let ray = Ray::new(point, reflected_dir);
-        let reflected = tracer(ray);
-        self.color.mul_l(reflected)
+    fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, _rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        let n = surface.normal;
+        Some(BsdfSample {direction: reflect(dir_in, n), weight: self.color.color(surface), pdf: 1.0, specular: true})
     }
 }
 
 pub struct RefractiveMaterial {
     color: Light,
-    index: f32
+    index: f32,
+    absorption: Light
 }
 
 impl RefractiveMaterial {
     pub fn new(r: f32, g: f32, b: f32, i: f32) -> RefractiveMaterial {
-        RefractiveMaterial { color: Light::new(r,g,b), index: i }
+        RefractiveMaterial { color: Light::new(r,g,b), index: i, absorption: Light::zero() }
+    }
+
+    /// Makes the medium absorb light, per channel and unit of distance
+    /// (Beer-Lambert law). Thick parts of the object get darker.
+    pub fn with_absorption(self, r: f32, g: f32, b: f32) -> RefractiveMaterial {
+        RefractiveMaterial { absorption: Light::new(r, g, b), ..self }
+    }
+
+    /// Ratio of the indices of refraction on the side of `dir_in` and on
+    /// the other side, and cosine of the incident angle.
+    fn eta(&self, n: Vector3<f32>, dir_in: Vector3<f32>) -> (f32, f32) {
+        let proj = dot(n, dir_in);
+        (if proj < 0.0 { 1.0/self.index } else { self.index }, proj.abs())
     }
 }
 
 impl Material for RefractiveMaterial {
-    fn next_step(&self, point: Point3<f32>, n: Vector3<f32>, dir_in: Vector3<f32>, tracer: |Ray3<f32>| -> Light) -> Light {
-        let proj = dot(n, dir_in);
-        let cos_theta1 = proj.abs();
-        let r = if proj < 0.0 { 1.0/self.index } else { self.index };
-        let squared_cos_theta2 = 1.0 - r.powi(2)*(1.0 - cos_theta1.powi(2));
-        let refracted_dir = if squared_cos_theta2 > 0.0 {
-            let cos_theta2 = squared_cos_theta2.sqrt();
-            let reverse = if proj < 0.0 { 1.0 } else { -1.0 };
-            let k = reverse*(r*cos_theta1 - cos_theta2);
-            dir_in.mul_s(r).add_v(&n.mul_s(k))
-        } else {
-            // Just relfect the incoming ray.
-            dir_in.sub_v(&n.mul_s(2.0 * proj))
+    fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        let n = surface.normal;
+        // Reflect or refract with probabilities given by the Fresnel
+        // equations, so that both weights reduce to the color.
+        let (eta, cos_i) = self.eta(n, dir_in);
+        let reflectance = fresnel_dielectric(cos_i, eta);
+        let sample = match refract(dir_in, n, eta) {
+            Some(refracted_dir) if rng.gen::<f32>() >= reflectance =>
+                BsdfSample {direction: refracted_dir, weight: self.color, pdf: 1.0 - reflectance, specular: true},
+            _ => BsdfSample {direction: reflect(dir_in, n), weight: self.color, pdf: reflectance, specular: true}
         };
-        let ray = Ray::new(point, refracted_dir);
-        let reflected = tracer(ray);
-        self.color.mul_l(reflected)
+        Some(sample)
+    }
+    fn absorption(&self) -> Light {
+        self.absorption
     }
 }
 
+/// Mirrors `dir_in` about the plane of normal `n`.
+pub fn reflect(dir_in: Vector3<f32>, n: Vector3<f32>) -> Vector3<f32> {
+    dir_in.sub_v(&n.mul_s(2.0 * dot(n, dir_in)))
+}
+
+/// Bends `dir_in` through an interface of normal `n` (on either side),
+/// following Snell's law with `eta` the ratio of the indices of refraction
+/// of the incident and transmitted sides. Returns `None` on total internal
+/// reflection.
+pub fn refract(dir_in: Vector3<f32>, n: Vector3<f32>, eta: f32) -> Option<Vector3<f32>> {
+    let proj = dot(n, dir_in);
+    let cos_theta1 = proj.abs();
+    let squared_cos_theta2 = 1.0 - eta.powi(2)*(1.0 - cos_theta1.powi(2));
+    if squared_cos_theta2 > 0.0 {
+        let cos_theta2 = squared_cos_theta2.sqrt();
+        let reverse = if proj < 0.0 { 1.0 } else { -1.0 };
+        let k = reverse*(eta*cos_theta1 - cos_theta2);
+        Some(dir_in.mul_s(eta).add_v(&n.mul_s(k)))
+    } else {
+        None
+    }
+}
+
+/// Fraction of unpolarized light reflected by a smooth interface between
+/// two dielectrics, for an incident angle of cosine `cos_i` and a ratio of
+/// indices of refraction `eta` (see `refract`).
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let squared_sin_t = eta * eta * (1.0 - cos_i * cos_i);
+    if squared_sin_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_t = (1.0 - squared_sin_t).sqrt();
+    let r_s = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_p = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    (r_s * r_s + r_p * r_p) / 2.0
+}
+
 #[cfg(test)]
 pub struct TestMaterial;
 
 #[cfg(test)]
 impl Material for TestMaterial {
-    fn emittance(&self, _n: Vector3<f32>, _dir: Vector3<f32>) -> Light {
+    fn emittance(&self, _surface: &SurfacePoint, _dir: Vector3<f32>) -> Light {
         Light::new(1.0, 1.0, 1.0)
     }
 
-    fn reflectance(&self, _: Vector3<f32>, _: Vector3<f32>, _: Vector3<f32>) -> Light {
+    fn reflectance(&self, _: &SurfacePoint, _: Vector3<f32>, _: Vector3<f32>) -> Light {
         Light::new(1.0, 1.0, 1.0)
     }
 }
@@ -177,115 +271,163 @@ impl Material for TestMaterial {
 #[cfg(test)]
 mod tests {
 
-    use material::{Material, DiffuseMaterial, ReflectiveMaterial, RefractiveMaterial};
+    use material::{Material, BsdfSample, DiffuseMaterial, GlobalDiffuseMaterial, ReflectiveMaterial, RefractiveMaterial};
+    use material::{reflect, refract, fresnel_dielectric};
     use cgmath::vector::Vector3;
-    use cgmath::point::Point3;
-    use cgmath::ray::Ray3;
+    use std::rand::XorShiftRng;
     use light::Light;
+    use shape::SurfacePoint;
+    use test_helpers::make_test_surface;
+    use texture::{ImageTexture, Nearest};
+
+    fn sample(mat: &Material, normal: Vector3<f32>, dir_in: Vector3<f32>) -> BsdfSample {
+        match mat.sample(&make_test_surface(normal), dir_in, &mut XorShiftRng::new_unseeded()) {
+            Some(sample) => sample,
+            None => fail!("expected a sample")
+        }
+    }
 
     #[test]
-    fn test_reflective_material_next_step() {
+    fn test_reflective_material_sample() {
         let mat = ReflectiveMaterial::new(1.0, 1.0, 0.0);
-        let p = Point3::new(0.0, 0.0, 0.0);
         let normal = Vector3::new(1.0, 0.0, 0.0);
         let dir_in = Vector3::new(1.0, 1.0, 1.0);
-        let tracer = |ray: Ray3<f32>| {
-            assert!(ray.origin == p);
-            assert!(ray.direction.x == -dir_in.x);
-            assert!(ray.direction.y == dir_in.y);
-            assert!(ray.direction.z == dir_in.z);
-            Light::white(0.2)
-        };
-        let res = mat.next_step(p, normal, dir_in, tracer);
-        assert!(res == Light::new(0.2, 0.2, 0.0))
+        let res = sample(&mat, normal, dir_in);
+        assert!(res.direction.x == -dir_in.x);
+        assert!(res.direction.y == dir_in.y);
+        assert!(res.direction.z == dir_in.z);
+        assert!(res.specular);
+        assert!(res.weight == Light::new(1.0, 1.0, 0.0))
+    }
+
+    /// Samples the material `n` times and returns the fraction of
+    /// reflections, checking that the other directions are `refracted`.
+    fn reflection_rate(mat: &Material, normal: Vector3<f32>, dir_in: Vector3<f32>, refracted: Vector3<f32>) -> f32 {
+        let surface = make_test_surface(normal);
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 10000u;
+        let mut reflections = 0u;
+        for _ in range(0, n) {
+            let res = mat.sample(&surface, dir_in, &mut rng).unwrap();
+            assert!(res.weight == Light::new(1.0, 1.0, 0.0));
+            if res.direction == reflect(dir_in, normal) {
+                reflections += 1;
+            } else {
+                assert!(res.direction == refracted);
+            }
+        }
+        reflections as f32 / n as f32
     }
 
     #[test]
-    fn test_refractive_material_next_step_outside_in() {
+    fn test_refractive_material_sample_outside_in() {
         let mat = RefractiveMaterial::new(1.0, 1.0, 0.0, 1.3);
-        let p = Point3::new(0.0, 0.0, 0.0);
         let normal = Vector3::new(1.0, 0.0, 0.0);
         let dir_in = -normal;
-        let tracer = |ray: Ray3<f32>| {
-            assert!(ray.origin == p);
-            println!("ray.direction = {}", ray.direction);
-            assert!(ray.direction == dir_in);
-            Light::white(0.2)
-        };
-        let res = mat.next_step(p, normal, dir_in, tracer);
-        assert!(res == Light::new(0.2, 0.2, 0.0))
+        // At normal incidence glass reflects ((n - 1) / (n + 1))^2 of the light.
+        let rate = reflection_rate(&mat, normal, dir_in, dir_in);
+        assert!((rate - 0.017).abs() < 0.005);
     }
 
     #[test]
-    fn test_refractive_material_next_step_outside_in_tangent() {
+    fn test_refractive_material_sample_outside_in_tangent() {
         let mat = RefractiveMaterial::new(1.0, 1.0, 0.0, 1.3);
-        let p = Point3::new(0.0, 0.0, 0.0);
         let normal = Vector3::new(1.0, 0.0, 0.0);
         let dir_in = Vector3::new(-0.00000000000001, 1.0, 0.0);
-        let tracer = |ray: Ray3<f32>| {
-            assert!(ray.origin == p);
-            println!("ray.direction = {}", ray.direction);
-            assert!(ray.direction.x < -0.6389);
-            assert!(ray.direction.y > 0.7692);
-            Light::white(0.2)
-        };
-        let res = mat.next_step(p, normal, dir_in, tracer);
-        assert!(res == Light::new(0.2, 0.2, 0.0))
+        let refracted = refract(dir_in, normal, 1.0 / 1.3).unwrap();
+        assert!(refracted.x < -0.6389);
+        assert!(refracted.y > 0.7692);
+        // Almost everything is reflected at grazing angles.
+        let res = sample(&mat, normal, dir_in);
+        assert!(res.direction == reflect(dir_in, normal));
     }
 
     #[test]
-    fn test_refractive_material_next_step_inside_out_tangent() {
+    fn test_refractive_material_sample_inside_out_tangent() {
         let mat = RefractiveMaterial::new(1.0, 1.0, 0.0, 1.3);
-        let p = Point3::new(0.0, 0.0, 0.0);
         let normal = Vector3::new(1.0, 0.0, 0.0);
         let dir_in = Vector3::new(0.00000000000001, 1.0, 0.0);
-        let tracer = |ray: Ray3<f32>| {
-            assert!(ray.origin == p);
-            assert!(ray.direction.x == -dir_in.x);
-            assert!(ray.direction.y == dir_in.y);
-            assert!(ray.direction.z == dir_in.z);
-            Light::white(0.2)
-        };
-        let res = mat.next_step(p, normal, dir_in, tracer);
-        assert!(res == Light::new(0.2, 0.2, 0.0))
+        assert!(refract(dir_in, normal, 1.3).is_none());
+        let res = sample(&mat, normal, dir_in);
+        assert!(res.direction.x == -dir_in.x);
+        assert!(res.direction.y == dir_in.y);
+        assert!(res.direction.z == dir_in.z);
+        assert!(res.pdf == 1.0);
     }
 
     #[test]
-    fn test_refractive_material_next_step_inside_out() {
+    fn test_refractive_material_sample_inside_out() {
         let mat = RefractiveMaterial::new(1.0, 1.0, 0.0, 1.3);
-        let p = Point3::new(0.0, 0.0, 0.0);
         let normal = Vector3::new(1.0, 0.0, 0.0);
         let dir_in = normal;
-        let tracer = |ray: Ray3<f32>| {
-            assert!(ray.origin == p);
-            println!("ray.direction = {}", ray.direction);
-            println!("dir_in = {}", dir_in);
-            assert!(ray.direction == dir_in);
-            Light::white(0.2)
-        };
-        let res = mat.next_step(p, normal, dir_in, tracer);
-        assert!(res == Light::new(0.2, 0.2, 0.0))
+        let rate = reflection_rate(&mat, normal, dir_in, dir_in);
+        assert!((rate - 0.017).abs() < 0.005);
+    }
+
+    #[test]
+    fn test_fresnel_dielectric() {
+        let normal = ((1.3f32 - 1.0) / (1.3 + 1.0)).powi(2);
+        assert!((fresnel_dielectric(1.0, 1.0 / 1.3) - normal).abs() < 0.00001);
+        assert!((fresnel_dielectric(1.0, 1.3) - normal).abs() < 0.00001);
+        assert!(fresnel_dielectric(0.5, 1.0) == 0.0);
+        // Total internal reflection beyond the critical angle.
+        assert!(fresnel_dielectric(0.5, 1.3) == 1.0);
+        // Grows towards grazing angles.
+        let mut previous = 0.0;
+        for i in range(0u, 11) {
+            let reflectance = fresnel_dielectric(1.0 - i as f32 / 10.0, 1.0 / 1.3);
+            assert!(reflectance >= previous && reflectance <= 1.0);
+            previous = reflectance;
+        }
+        assert!(previous > 0.99);
+    }
+
+    #[test]
+    fn test_global_diffuse_material_sample() {
+        let mat = GlobalDiffuseMaterial::new(0.5, 0.5, 0.5);
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let surface = make_test_surface(normal);
+        let dir_in = Vector3::new(0.0, 0.6, -0.8);
+        let mut rng = XorShiftRng::new_unseeded();
+        for _ in range(0u, 100) {
+            let res = mat.sample(&surface, dir_in, &mut rng).unwrap();
+            // Reflected back on the side of the incoming ray.
+            assert!(res.direction.z > 0.0 && !res.specular);
+            assert!((res.pdf - mat.pdf(&surface, dir_in, res.direction)).abs() < 0.00001);
+            let expected = mat.eval(&surface, dir_in, res.direction).mul_s(res.direction.z / res.pdf);
+            assert!((res.weight.red - expected.red).abs() < 0.00001);
+        }
+        assert!(mat.eval(&surface, dir_in, -normal) == Light::zero());
+    }
+
+    #[test]
+    fn test_textured_material() {
+        let checker = ImageTexture::new(2, 1, vec![Light::white(1.0), Light::white(0.5)]).with_interpolation(Nearest);
+        let mat = GlobalDiffuseMaterial::textured(box checker);
+        let normal = Vector3::new(0.0, 0.0, 1.0);
+        let dir_in = -normal;
+        let mut rng = XorShiftRng::new_unseeded();
+        let left = SurfacePoint {uv: (0.25, 0.5), ..make_test_surface(normal)};
+        let right = SurfacePoint {uv: (0.75, 0.5), ..make_test_surface(normal)};
+        assert!(mat.sample(&left, dir_in, &mut rng).unwrap().weight == Light::white(1.0));
+        assert!(mat.sample(&right, dir_in, &mut rng).unwrap().weight == Light::white(0.5));
     }
 
     #[test]
     fn test_diffuse_material_reflectance() {
-        let mat = DiffuseMaterial {
-            diffuse: Light::new(1.0, 1.0, 1.0),
-            specular: Light::new(0.0, 0.0, 0.0),
-            shininess: 0.0
-        };
+        let mat = DiffuseMaterial::new(1.0, 1.0, 1.0);
         let normal = Vector3::new(1.0, 0.0, 0.0);
         let dir_in = Vector3::new(1.0, 0.0, 0.0);
         let dir_out = Vector3::new(1.0, 0.0, 0.0);
-        let res = mat.reflectance(normal, dir_in, dir_out);
+        let res = mat.reflectance(&make_test_surface(normal), dir_in, dir_out);
         assert!(res == Light::zero());
-        let res = mat.reflectance(normal, -dir_in, dir_out);
+        let res = mat.reflectance(&make_test_surface(normal), -dir_in, dir_out);
         assert!(res != Light::zero());
         assert!(res.red > 0.0);
-        let res = mat.reflectance(normal, dir_in, -dir_out);
+        let res = mat.reflectance(&make_test_surface(normal), dir_in, -dir_out);
         assert!(res != Light::zero());
         assert!(res.red > 0.0);
-        let res = mat.reflectance(normal, -dir_in, -dir_out);
+        let res = mat.reflectance(&make_test_surface(normal), -dir_in, -dir_out);
         assert!(res == Light::zero());
     }
 }
\ No newline at end of file
@@ -0,0 +1,415 @@
+use cgmath::point::{Point, Point3};
+use cgmath::ray::Ray3;
+use cgmath::vector::{EuclideanVector, Vector, Vector3};
+use std::f32;
+
+use bvh::{BoundingBox, Bvh};
+use sampling::search_cdf;
+use shape::{Hit, Shape, gamma, max_abs, solid_angle_pdf};
+
+/// A triangle mesh sharing its vertices between faces.
+///
+/// Each triangle is a triple of indices into `vertices` and `normals`, which
+/// must have the same length. The normals are interpolated across faces to
+/// give a smooth shading. Texture coordinates are optional: `uvs` is either
+/// empty or has one entry per vertex.
+pub struct TriangleMesh {
+    pub vertices: Vec<Point3<f32>>,
+    pub normals: Vec<Vector3<f32>>,
+    pub uvs: Vec<(f32, f32)>,
+    pub triangles: Vec<[uint, ..3]>,
+    bvh: Bvh,
+    /// Cumulated areas of the triangles, to sample them.
+    areas: Vec<f32>
+}
+
+impl TriangleMesh {
+    pub fn new(vertices: Vec<Point3<f32>>, normals: Vec<Vector3<f32>>, triangles: Vec<[uint, ..3]>) -> TriangleMesh {
+        assert!(vertices.len() == normals.len());
+        for tri in triangles.iter() {
+            assert!(tri.iter().all(|&i| i < vertices.len()));
+        }
+        let bounds: Vec<BoundingBox> = triangles.iter().map(|tri| {
+            BoundingBox::from_points(&[vertices[tri[0]], vertices[tri[1]], vertices[tri[2]]])
+        }).collect();
+        let bvh = Bvh::new(bounds.as_slice());
+        let mut total = 0.0;
+        let areas = triangles.iter().map(|tri| {
+            let (p0, p1, p2) = (vertices[tri[0]], vertices[tri[1]], vertices[tri[2]]);
+            total += p1.sub_p(&p0).cross(&p2.sub_p(&p0)).length() / 2.0;
+            total
+        }).collect();
+        TriangleMesh { vertices: vertices, normals: normals, uvs: vec![], triangles: triangles, bvh: bvh, areas: areas }
+    }
+
+    pub fn with_uvs(self, uvs: Vec<(f32, f32)>) -> TriangleMesh {
+        assert!(uvs.len() == self.vertices.len());
+        TriangleMesh { uvs: uvs, ..self }
+    }
+
+    /// Builds a mesh whose vertex normals are the area-weighted average of
+    /// the normals of the faces sharing them.
+    pub fn with_smooth_normals(vertices: Vec<Point3<f32>>, triangles: Vec<[uint, ..3]>) -> TriangleMesh {
+        let mut normals = Vec::from_elem(vertices.len(), Vector3::new(0.0f32, 0.0, 0.0));
+        for tri in triangles.iter() {
+            let (p0, p1, p2) = (vertices[tri[0]], vertices[tri[1]], vertices[tri[2]]);
+            // The cross product length is twice the face area.
+            let face = p1.sub_p(&p0).cross(&p2.sub_p(&p0));
+            for &i in tri.iter() {
+                let n = normals[i].add_v(&face);
+                *normals.get_mut(i) = n;
+            }
+        }
+        let normals = normals.iter().map(|n| {
+            if n.length2() > 0.0 { n.normalize() } else { *n }
+        }).collect();
+        TriangleMesh::new(vertices, normals, triangles)
+    }
+
+    fn corners(&self, i: uint) -> (Point3<f32>, Point3<f32>, Point3<f32>) {
+        let tri = self.triangles[i];
+        (self.vertices[tri[0]], self.vertices[tri[1]], self.vertices[tri[2]])
+    }
+
+    fn face_normal(&self, i: uint) -> Vector3<f32> {
+        let (p0, p1, p2) = self.corners(i);
+        p1.sub_p(&p0).cross(&p2.sub_p(&p0)).normalize()
+    }
+
+    /// Möller-Trumbore intersection. Returns the ray parameter and the
+    /// barycentric coordinates `(u, v)` of the hit.
+    fn intersect_triangle(&self, i: uint, ray: &Ray3<f32>) -> Option<(f32, f32, f32)> {
+        let (p0, p1, p2) = self.corners(i);
+        let e1 = p1.sub_p(&p0);
+        let e2 = p2.sub_p(&p0);
+        let pvec = ray.direction.cross(&e2);
+        let det = e1.dot(&pvec);
+        // Any threshold would depend on the scale of the mesh: only rays
+        // exactly in the plane of the triangle are left out.
+        if det == 0.0 { return None; }
+        let inv_det = 1.0 / det;
+        let tvec = ray.origin.sub_p(&p0);
+        let u = tvec.dot(&pvec) * inv_det;
+        if u < 0.0 || u > 1.0 { return None; }
+        let qvec = tvec.cross(&e1);
+        let v = ray.direction.dot(&qvec) * inv_det;
+        if v < 0.0 || u + v > 1.0 { return None; }
+        let t = e2.dot(&qvec) * inv_det;
+        if t < 0.0 { None } else { Some((t, u, v)) }
+    }
+
+    /// Interpolates the vertex normals, falling back on the face normal
+    /// where they cancel out.
+    fn shading_normal(&self, i: uint, u: f32, v: f32) -> Vector3<f32> {
+        let tri = self.triangles[i];
+        let n = self.normals[tri[0]].mul_s(1.0 - u - v)
+            .add_v(&self.normals[tri[1]].mul_s(u))
+            .add_v(&self.normals[tri[2]].mul_s(v));
+        if n.length2() > 0.0 { n.normalize() } else { self.face_normal(i) }
+    }
+
+    /// Interpolates the texture coordinates of the vertices, or returns the
+    /// barycentric coordinates if there are none.
+    fn texture_uv(&self, i: uint, u: f32, v: f32) -> (f32, f32) {
+        if self.uvs.is_empty() {
+            return (u, v);
+        }
+        let tri = self.triangles[i];
+        let w = 1.0 - u - v;
+        let ((u0, v0), (u1, v1), (u2, v2)) = (self.uvs[tri[0]], self.uvs[tri[1]], self.uvs[tri[2]]);
+        (w * u0 + u * u1 + v * u2, w * v0 + u * v1 + v * v2)
+    }
+
+    /// Direction in which the texture coordinate u grows on the triangle, or
+    /// its first edge without texture coordinates.
+    fn tangent(&self, i: uint) -> Vector3<f32> {
+        let (p0, p1, p2) = self.corners(i);
+        let (e1, e2) = (p1.sub_p(&p0), p2.sub_p(&p0));
+        if self.uvs.is_empty() {
+            return e1;
+        }
+        let tri = self.triangles[i];
+        let ((u0, v0), (u1, v1), (u2, v2)) = (self.uvs[tri[0]], self.uvs[tri[1]], self.uvs[tri[2]]);
+        let (du1, dv1, du2, dv2) = (u1 - u0, v1 - v0, u2 - u0, v2 - v0);
+        let det = du1 * dv2 - du2 * dv1;
+        if det == 0.0 { e1 } else { e1.mul_s(dv2).sub_v(&e2.mul_s(dv1)).div_s(det) }
+    }
+
+    /// Projects the point on the plane of a triangle. Returns its distance to
+    /// the plane, how far outside the edges it is as a length, and its
+    /// barycentric coordinates, or `None` for a degenerate triangle.
+    fn project(&self, i: uint, point: Point3<f32>) -> Option<(f32, f32, f32, f32)> {
+        let (p0, p1, p2) = self.corners(i);
+        let e1 = p1.sub_p(&p0);
+        let e2 = p2.sub_p(&p0);
+        let face = e1.cross(&e2);
+        let area2 = face.length2();
+        if area2 == 0.0 { return None; }
+        let w = point.sub_p(&p0);
+        let distance = (w.dot(&face) / area2.sqrt()).abs();
+        let u = w.cross(&e2).dot(&face) / area2;
+        let v = e1.cross(&w).dot(&face) / area2;
+        // The barycentric coordinates scaled by the size of the triangle.
+        let outside = (-u).max(-v).max(u + v - 1.0).max(0.0) * area2.sqrt().sqrt();
+        Some((distance, outside, u, v))
+    }
+
+    /// Finds the triangle the point lies on and its barycentric coordinates.
+    ///
+    /// The point may be off by the rounding of its coordinates, so the
+    /// tolerance grows with their magnitude rather than being fixed.
+    fn locate(&self, point: Point3<f32>) -> Option<(uint, f32, f32)> {
+        let tolerance = 0.0001 * max_abs(point);
+        let mut best = None;
+        let mut best_distance = f32::INFINITY;
+        self.bvh.visit_point(&point, tolerance, |i| {
+            match self.project(i, point) {
+                Some((distance, outside, u, v)) if outside <= tolerance && distance < best_distance => {
+                    best = Some((i, u, v));
+                    best_distance = distance;
+                },
+                _ => {}
+            }
+        });
+        best
+    }
+
+    /// Finds the triangle closest to a point which is not on the mesh.
+    fn nearest(&self, point: Point3<f32>) -> Option<uint> {
+        let mut best = None;
+        let mut best_distance = f32::INFINITY;
+        for i in range(0, self.triangles.len()) {
+            match self.project(i, point) {
+                Some((distance, outside, _, _)) if distance * distance + outside * outside < best_distance => {
+                    best = Some(i);
+                    best_distance = distance * distance + outside * outside;
+                },
+                _ => {}
+            }
+        }
+        best
+    }
+}
+
+impl Shape for TriangleMesh {
+
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        let nearest = self.bvh.nearest(&ray, t_max, |i| {
+            match self.intersect_triangle(i, &ray) {
+                Some((t, u, v)) if t > t_min => Some(((i, u, v), t)),
+                _ => None
+            }
+        });
+        nearest.map(|((i, u, v), t)| {
+            // Interpolating the corners gives a point much closer to the
+            // triangle than following the ray.
+            let (p0, p1, p2) = self.corners(i);
+            let w = 1.0 - u - v;
+            let point = Point3::new(w * p0.x + u * p1.x + v * p2.x,
+                                    w * p0.y + u * p1.y + v * p2.y,
+                                    w * p0.z + u * p1.z + v * p2.z);
+            let error = gamma(7) * 3.0f32.sqrt() * (w.abs() * max_abs(p0) + u.abs() * max_abs(p1) + v.abs() * max_abs(p2));
+            Hit::new(&ray, t, self.face_normal(i), self.shading_normal(i, u, v), self.texture_uv(i, u, v), self.tangent(i), i)
+                .with_point(point, error)
+        })
+    }
+
+    fn normal(&self, point: Point3<f32>) -> Vector3<f32> {
+        match self.locate(point) {
+            Some((i, u, v)) => self.shading_normal(i, u, v),
+            None => match self.nearest(point) {
+                Some(i) => self.face_normal(i),
+                None => Vector3::new(0.0, 0.0, 0.0)
+            }
+        }
+    }
+
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        match self.locate(point) {
+            None => (0.0, 0.0),
+            Some((i, u, v)) => self.texture_uv(i, u, v)
+        }
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(BoundingBox::from_points(self.vertices.as_slice()))
+    }
+
+    /// Uniform over the area of the mesh: `u` picks a triangle with a
+    /// probability proportional to its area, and is then reused in it.
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        let total = match self.areas.last() {
+            Some(&total) if total > 0.0 => total,
+            _ => return None
+        };
+        let target = u * total;
+        let i = search_cdf(self.areas.as_slice(), target);
+        let start = if i == 0 { 0.0 } else { self.areas[i - 1] };
+        if self.areas[i] <= start { return None; }
+        let u = ((target - start) / (self.areas[i] - start)).max(0.0).min(1.0);
+        // Uniform barycentric coordinates.
+        let su = u.sqrt();
+        let (p0, p1, p2) = self.corners(i);
+        let point = p0.add_v(&p1.sub_p(&p0).mul_s(su * (1.0 - v))).add_v(&p2.sub_p(&p0).mul_s(su * v));
+        Some((point, solid_angle_pdf(1.0 / total, origin, point, self.face_normal(i))))
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        match (self.locate(point), self.areas.last()) {
+            (Some((i, _, _)), Some(&total)) if total > 0.0 => solid_angle_pdf(1.0 / total, origin, point, self.face_normal(i)),
+            _ => 0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::point::{Point, Point3};
+    use cgmath::ray::Ray;
+    use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+    use std::f32;
+    use std::rand::{Rng, XorShiftRng};
+
+    use mesh::TriangleMesh;
+    use shape::Shape;
+    use test_helpers::{leaving_directions, spawn_next};
+
+    fn make_square() -> TriangleMesh {
+        let vertices = vec![
+            Point3::new(-1.0f32, -1.0, 0.0),
+            Point3::new(1.0f32, -1.0, 0.0),
+            Point3::new(1.0f32, 1.0, 0.0),
+            Point3::new(-1.0f32, 1.0, 0.0)
+        ];
+        let normals = vec![
+            Vector3::new(-1.0f32, 0.0, -1.0).normalize(),
+            Vector3::new(1.0f32, 0.0, -1.0).normalize(),
+            Vector3::new(1.0f32, 0.0, -1.0).normalize(),
+            Vector3::new(-1.0f32, 0.0, -1.0).normalize()
+        ];
+        TriangleMesh::new(vertices, normals, vec![[0, 1, 2], [0, 2, 3]])
+    }
+
+    #[test]
+    fn test_mesh_intersect() {
+        let mesh = make_square();
+        let ray = Ray::new(Point3::new(0.5, 0.5, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        let hit = mesh.intersect(ray, 0.0, f32::INFINITY).unwrap();
+        assert!(hit.point.sub_p(&Point3::new(0.5, 0.5, 0.0)).length() < 0.0001);
+        assert!((hit.t - 2.0).abs() < 0.0001);
+        assert!(mesh.intersect(ray, 0.0, 1.9).is_none());
+        let ray_miss = Ray::new(Point3::new(1.5, 0.5, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(mesh.intersect(ray_miss, 0.0, f32::INFINITY).is_none());
+        let ray_behind = Ray::new(Point3::new(0.5, 0.5, 2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(mesh.intersect(ray_behind, 0.0, f32::INFINITY).is_none());
+    }
+
+    #[test]
+    fn test_mesh_hit() {
+        let uvs = vec![(0.0f32, 0.0f32), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let mesh = make_square().with_uvs(uvs);
+        // The upper left half of the square is the second triangle.
+        let ray = Ray::new(Point3::new(-0.5, 0.5, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        let hit = mesh.intersect(ray, 0.0, f32::INFINITY).unwrap();
+        // The winding makes the face normal +z, so the ray comes from behind.
+        assert!(hit.primitive == 1 && !hit.front_face);
+        assert!(hit.geometric_normal == Vector3::new(0.0, 0.0, 1.0));
+        // Smooth shading tilts the normal, but not the geometry.
+        assert!(hit.shading_normal.x < -0.1 && hit.geometric_normal.x == 0.0);
+        assert!((hit.uv.val0() - 0.25).abs() < 0.0001 && (hit.uv.val1() - 0.75).abs() < 0.0001);
+        // The tangent follows u, which grows along x.
+        assert!(hit.frame.s.x > 0.8 && dot(hit.frame.s, hit.shading_normal).abs() < 0.0001);
+        let right = Ray::new(Point3::new(0.5, -0.5, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(mesh.intersect(right, 0.0, f32::INFINITY).unwrap().primitive == 0);
+    }
+
+    #[test]
+    fn test_mesh_spawned_rays_at_any_scale() {
+        let mut rng = XorShiftRng::new_unseeded();
+        for &scale in [1e-6f32, 1.0, 1e6].iter() {
+            let vertices = make_square().vertices.iter()
+                .map(|p| Point3::new((p.x + 3.0) * scale, p.y * scale, (p.z + 7.0) * scale))
+                .collect();
+            let mesh = TriangleMesh::with_smooth_normals(vertices, vec![[0, 1, 2], [0, 2, 3]]);
+            for _ in range(0u, 200) {
+                let target = Vector3::new(rng.gen::<f32>() * 1.8 + 2.1, rng.gen::<f32>() * 1.8 - 0.9, 7.0).mul_s(scale);
+                let hit = mesh.intersect(Ray::new(Point::origin(), target), 0.0, f32::INFINITY).unwrap();
+                let (back, through) = leaving_directions(&hit, &mut rng);
+                assert!(spawn_next(&mesh, &hit, back, scale).is_none());
+                assert!(spawn_next(&mesh, &hit, through, scale).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn test_mesh_pdf_at_any_scale() {
+        for &scale in [1e-6f32, 1.0, 1e6].iter() {
+            let vertices = make_square().vertices.iter()
+                .map(|p| Point3::new((p.x + 3.0) * scale, p.y * scale, (p.z + 7.0) * scale))
+                .collect();
+            let mesh = TriangleMesh::with_smooth_normals(vertices, vec![[0, 1, 2], [0, 2, 3]]);
+            let origin = Point3::new(3.0 * scale, 0.0, 6.0 * scale);
+            for &(u, v) in [(0.1f32, 0.3f32), (0.5, 0.5), (0.99, 0.01), (0.7, 0.9)].iter() {
+                let (point, pdf) = mesh.sample(origin, u, v).unwrap();
+                assert!((mesh.pdf(origin, point) - pdf).abs() <= 0.001 * pdf);
+                assert!((mesh.normal(point).z - 1.0).abs() < 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mesh_normal_is_interpolated() {
+        let mesh = make_square();
+        let center = mesh.normal(Point3::new(0.0, 0.0, 0.0));
+        assert!(center.x.abs() < 0.0001);
+        assert!((center.z + 1.0).abs() < 0.0001);
+        let right = mesh.normal(Point3::new(1.0, 0.0, 0.0));
+        assert!((right.x - right.z.abs()).abs() < 0.0001);
+        let n = mesh.normal(Point3::new(0.5, -0.2, 0.0));
+        assert!(n.x > 0.0 && n.x < right.x);
+        // Off the mesh, the closest face gives the normal.
+        assert!(mesh.normal(Point3::new(5.0, 0.5, 0.3)) == Vector3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_mesh_smooth_normals() {
+        let vertices = vec![
+            Point3::new(0.0f32, 0.0, 0.0),
+            Point3::new(1.0f32, 0.0, 0.0),
+            Point3::new(0.0f32, 1.0, 0.0)
+        ];
+        let mesh = TriangleMesh::with_smooth_normals(vertices, vec![[0, 1, 2]]);
+        for n in mesh.normals.iter() {
+            assert!(*n == Vector3::new(0.0, 0.0, 1.0));
+        }
+    }
+
+    #[test]
+    fn test_mesh_uv() {
+        let mesh = make_square();
+        let (u, v) = mesh.uv(Point3::new(0.0, 0.0, 0.0));
+        assert!(((u + v) - 0.5).abs() < 0.0001);
+        let uvs = vec![(0.0f32, 0.0f32), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        let textured = make_square().with_uvs(uvs);
+        for &(x, y) in [(0.5f32, 0.5f32), (-0.5, 0.8), (0.9, -0.9)].iter() {
+            let (u, v) = textured.uv(Point3::new(x, y, 0.0));
+            assert!((u - (x + 1.0) / 2.0).abs() < 0.0001);
+            assert!((v - (y + 1.0) / 2.0).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_mesh_sample() {
+        let mesh = make_square();
+        let origin = Point3::new(0.0, 0.0, -1.0);
+        let (point, pdf) = mesh.sample(origin, 0.7, 0.2).unwrap();
+        assert!(point.z == 0.0 && point.x.abs() <= 1.0 && point.y.abs() <= 1.0);
+        assert!((mesh.pdf(origin, point) - pdf).abs() < 0.0001);
+        // Uniform over the 4 units of area: seen straight on from 1 unit away.
+        let (center, pdf_center) = mesh.sample(origin, 0.5, 0.5).unwrap();
+        let d2 = center.sub_p(&origin).length2();
+        let cos = 1.0 / d2.sqrt();
+        assert!((pdf_center - d2 / (4.0 * cos)).abs() < 0.0001);
+    }
+}
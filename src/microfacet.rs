@@ -0,0 +1,400 @@
+//! Rough surfaces made of microscopic mirrors (microfacets).
+//!
+//! The orientation of the facets follows the GGX (Trowbridge-Reitz)
+//! distribution and their masking and shadowing the height-correlated Smith
+//! function. Directions are sampled from the facets visible from the
+//! incoming direction (Heitz, "Sampling the GGX Distribution of Visible
+//! Normals", 2018), so the sample weights stay close to 1.
+//!
+//! Computations happen in a local frame whose z axis is the surface normal
+//! on the side of the incoming ray. `wo` points back towards where the
+//! ray comes from and `wi` to where it goes, both away from the surface.
+
+use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+use std::rand::{Rng, XorShiftRng};
+use std::f32::consts::PI;
+
+use light::Light;
+use material::{Material, BsdfSample, fresnel_dielectric, refract};
+use sampling::Frame;
+use shape::SurfacePoint;
+
+/// Below this roughness the distribution is too peaked for f32.
+static MIN_ALPHA: f32 = 0.001;
+
+/// Isotropic GGX distribution of facet normals.
+pub struct Ggx {
+    /// Width of the distribution, roughly the slope of the facets.
+    pub alpha: f32
+}
+
+impl Ggx {
+    pub fn new(alpha: f32) -> Ggx {
+        Ggx {alpha: alpha.max(MIN_ALPHA)}
+    }
+
+    /// Density of facets of normal `m`, per unit of solid angle and area
+    /// of the macro surface.
+    pub fn d(&self, m: Vector3<f32>) -> f32 {
+        if m.z <= 0.0 { return 0.0; }
+        let a2 = self.alpha * self.alpha;
+        let t = (m.x * m.x + m.y * m.y) / a2 + m.z * m.z;
+        1.0 / (PI * a2 * t * t)
+    }
+
+    /// Smith auxiliary function: the ratio of the area of the facets masked
+    /// from `v` to the visible one.
+    fn lambda(&self, v: Vector3<f32>) -> f32 {
+        let squared_tan = (v.x * v.x + v.y * v.y) / (v.z * v.z);
+        ((1.0 + self.alpha * self.alpha * squared_tan).sqrt() - 1.0) / 2.0
+    }
+
+    /// Fraction of the facets visible from `v`.
+    pub fn g1(&self, v: Vector3<f32>) -> f32 {
+        1.0 / (1.0 + self.lambda(v))
+    }
+
+    /// Fraction of the facets visible from both `wo` and `wi`.
+    pub fn g2(&self, wo: Vector3<f32>, wi: Vector3<f32>) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Samples a facet normal among the ones visible from `wo`, with density
+    /// `visible_d(wo, m)`.
+    pub fn sample_visible(&self, wo: Vector3<f32>, u: f32, v: f32) -> Vector3<f32> {
+        // Stretch the view so that the facets are those of a half sphere.
+        let vh = Vector3::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalize();
+        let squared_length = vh.x * vh.x + vh.y * vh.y;
+        let t1 = if squared_length > 0.0 {
+            Vector3::new(-vh.y, vh.x, 0.0).mul_s(1.0 / squared_length.sqrt())
+        } else {
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = vh.cross(&t1);
+        // Uniform point of the projected half disk.
+        let r = u.sqrt();
+        let phi = 2.0 * PI * v;
+        let p1 = r * phi.cos();
+        let s = (1.0 + vh.z) / 2.0;
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let nh = t1.mul_s(p1).add_v(&t2.mul_s(p2)).add_v(&vh.mul_s((1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt()));
+        // Unstretch.
+        Vector3::new(self.alpha * nh.x, self.alpha * nh.y, nh.z.max(0.0)).normalize()
+    }
+
+    /// Density of `sample_visible`.
+    pub fn visible_d(&self, wo: Vector3<f32>, m: Vector3<f32>) -> f32 {
+        self.g1(wo) * dot(wo, m).max(0.0) * self.d(m) / wo.z
+    }
+}
+
+/// Complex index of refraction of a metal, per channel.
+pub struct ComplexIor {
+    pub eta: Light,
+    pub k: Light
+}
+
+impl ComplexIor {
+    pub fn gold() -> ComplexIor {
+        ComplexIor {eta: Light::new(0.143, 0.374, 1.442), k: Light::new(3.983, 2.385, 1.603)}
+    }
+
+    pub fn copper() -> ComplexIor {
+        ComplexIor {eta: Light::new(0.200, 0.924, 1.102), k: Light::new(3.912, 2.452, 2.142)}
+    }
+
+    pub fn aluminium() -> ComplexIor {
+        ComplexIor {eta: Light::new(1.657, 0.880, 0.521), k: Light::new(9.224, 6.270, 4.837)}
+    }
+
+    pub fn from_name(name: &str) -> Option<ComplexIor> {
+        match name {
+            "gold" => Some(ComplexIor::gold()),
+            "copper" => Some(ComplexIor::copper()),
+            "aluminium" | "aluminum" => Some(ComplexIor::aluminium()),
+            _ => None
+        }
+    }
+
+    /// Fraction of unpolarized light reflected by the metal, from the air.
+    pub fn fresnel(&self, cos_i: f32) -> Light {
+        Light::new(fresnel_conductor(cos_i, self.eta.red, self.k.red),
+                   fresnel_conductor(cos_i, self.eta.green, self.k.green),
+                   fresnel_conductor(cos_i, self.eta.blue, self.k.blue))
+    }
+}
+
+/// Fresnel reflectance of a conductor of index `eta + i k`, relative to the
+/// incident medium.
+pub fn fresnel_conductor(cos_i: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_i.max(0.0).min(1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let a = ((a2_plus_b2 + t0) / 2.0).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos_i * a;
+    let r_s = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let r_p = r_s * (t3 - t4) / (t3 + t4);
+    (r_s + r_p) / 2.0
+}
+
+/// Frame around the normal on the side `dir_in` comes from.
+fn local_frame(n: Vector3<f32>, dir_in: Vector3<f32>) -> Frame {
+    Frame::from_normal(if dot(n, dir_in) > 0.0 { -n } else { n })
+}
+
+/// Rough metal, such as brushed aluminium.
+pub struct RoughConductorMaterial {
+    color: Light,
+    ior: ComplexIor,
+    ggx: Ggx
+}
+
+impl RoughConductorMaterial {
+    /// `color` tints the reflection and `alpha` is the GGX roughness.
+    pub fn new(r: f32, g: f32, b: f32, ior: ComplexIor, alpha: f32) -> RoughConductorMaterial {
+        RoughConductorMaterial {color: Light::new(r, g, b), ior: ior, ggx: Ggx::new(alpha)}
+    }
+}
+
+impl Material for RoughConductorMaterial {
+    fn eval(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        let n = surface.normal;
+        let frame = local_frame(n, dir_in);
+        let (wo, wi) = (frame.to_local(-dir_in), frame.to_local(dir_out));
+        if wo.z <= 0.0 || wi.z <= 0.0 { return Light::zero(); }
+        let m = wo.add_v(&wi).normalize();
+        let f = self.ggx.d(m) * self.ggx.g2(wo, wi) / (4.0 * wo.z * wi.z);
+        self.color.mul_l(self.ior.fresnel(dot(wo, m))).mul_s(f)
+    }
+
+    fn pdf(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> f32 {
+        let n = surface.normal;
+        let frame = local_frame(n, dir_in);
+        let (wo, wi) = (frame.to_local(-dir_in), frame.to_local(dir_out));
+        if wo.z <= 0.0 || wi.z <= 0.0 { return 0.0; }
+        let m = wo.add_v(&wi).normalize();
+        self.ggx.visible_d(wo, m) / (4.0 * dot(wo, m))
+    }
+
+    fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        let n = surface.normal;
+        let frame = local_frame(n, dir_in);
+        let wo = frame.to_local(-dir_in);
+        if wo.z <= 0.0 { return None; }
+        let m = self.ggx.sample_visible(wo, rng.gen(), rng.gen());
+        let cos_m = dot(wo, m);
+        let wi = m.mul_s(2.0 * cos_m).sub_v(&wo);
+        // Reflected below the surface: the light is lost.
+        if wi.z <= 0.0 { return None; }
+        let weight = self.color.mul_l(self.ior.fresnel(cos_m)).mul_s(self.ggx.g2(wo, wi) / self.ggx.g1(wo));
+        let pdf = self.ggx.visible_d(wo, m) / (4.0 * cos_m);
+        Some(BsdfSample {direction: frame.to_world(wi), weight: weight, pdf: pdf, specular: false})
+    }
+}
+
+/// Rough glass, such as frosted glass.
+pub struct RoughDielectricMaterial {
+    color: Light,
+    index: f32,
+    ggx: Ggx
+}
+
+impl RoughDielectricMaterial {
+    /// `alpha` is the GGX roughness.
+    pub fn new(r: f32, g: f32, b: f32, index: f32, alpha: f32) -> RoughDielectricMaterial {
+        RoughDielectricMaterial {color: Light::new(r, g, b), index: index, ggx: Ggx::new(alpha)}
+    }
+
+    /// Ratio of the indices of refraction on the side of `dir_in` and on
+    /// the other side.
+    fn eta(&self, n: Vector3<f32>, dir_in: Vector3<f32>) -> f32 {
+        if dot(n, dir_in) < 0.0 { 1.0 / self.index } else { self.index }
+    }
+
+    /// Facet normal turning `wo` into `wi`, on the side of `wo`.
+    fn half_vector(&self, wo: Vector3<f32>, wi: Vector3<f32>, eta: f32) -> Vector3<f32> {
+        let m = if wi.z > 0.0 { wo.add_v(&wi) } else { wo.mul_s(eta).add_v(&wi) }.normalize();
+        if m.z < 0.0 { -m } else { m }
+    }
+
+    /// Density of the facet normal `m` and the change of variable from the
+    /// facet normal to `wi`.
+    fn jacobian(&self, wo: Vector3<f32>, wi: Vector3<f32>, m: Vector3<f32>, eta: f32) -> f32 {
+        if wi.z > 0.0 {
+            1.0 / (4.0 * dot(wo, m))
+        } else {
+            let denominator = eta * dot(wo, m) + dot(wi, m);
+            dot(wi, m).abs() / (denominator * denominator)
+        }
+    }
+}
+
+impl Material for RoughDielectricMaterial {
+    fn eval(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        let n = surface.normal;
+        let frame = local_frame(n, dir_in);
+        let eta = self.eta(n, dir_in);
+        let (wo, wi) = (frame.to_local(-dir_in), frame.to_local(dir_out));
+        if wo.z <= 0.0 || wi.z == 0.0 { return Light::zero(); }
+        let m = self.half_vector(wo, wi, eta);
+        // Both sides of the facet must agree with the macro surface.
+        if dot(wo, m) <= 0.0 || dot(wi, m) * wi.z <= 0.0 { return Light::zero(); }
+        let reflectance = fresnel_dielectric(dot(wo, m), eta);
+        let lobe = if wi.z > 0.0 { reflectance } else { 1.0 - reflectance };
+        let f = lobe * self.ggx.d(m) * self.ggx.g2(wo, wi) * dot(wo, m) * self.jacobian(wo, wi, m, eta) / (wo.z * wi.z.abs());
+        self.color.mul_s(f)
+    }
+
+    fn pdf(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> f32 {
+        let n = surface.normal;
+        let frame = local_frame(n, dir_in);
+        let eta = self.eta(n, dir_in);
+        let (wo, wi) = (frame.to_local(-dir_in), frame.to_local(dir_out));
+        if wo.z <= 0.0 || wi.z == 0.0 { return 0.0; }
+        let m = self.half_vector(wo, wi, eta);
+        if dot(wo, m) <= 0.0 || dot(wi, m) * wi.z <= 0.0 { return 0.0; }
+        let reflectance = fresnel_dielectric(dot(wo, m), eta);
+        let lobe = if wi.z > 0.0 { reflectance } else { 1.0 - reflectance };
+        lobe * self.ggx.visible_d(wo, m) * self.jacobian(wo, wi, m, eta)
+    }
+
+    fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        let n = surface.normal;
+        let frame = local_frame(n, dir_in);
+        let eta = self.eta(n, dir_in);
+        let wo = frame.to_local(-dir_in);
+        if wo.z <= 0.0 { return None; }
+        let m = self.ggx.sample_visible(wo, rng.gen(), rng.gen());
+        let reflectance = fresnel_dielectric(dot(wo, m), eta);
+        // As for smooth glass, the Fresnel factor selects the lobe and
+        // cancels out of the weight.
+        let (wi, lobe) = match refract(-wo, m, eta) {
+            Some(refracted) if rng.gen::<f32>() >= reflectance => (refracted, 1.0 - reflectance),
+            _ => (m.mul_s(2.0 * dot(wo, m)).sub_v(&wo), reflectance)
+        };
+        // The facet sends the light to the wrong side of the surface.
+        if wi.z == 0.0 || dot(wi, m) * wi.z <= 0.0 { return None; }
+        let weight = self.color.mul_s(self.ggx.g2(wo, wi) / self.ggx.g1(wo));
+        let pdf = lobe * self.ggx.visible_d(wo, m) * self.jacobian(wo, wi, m, eta);
+        Some(BsdfSample {direction: frame.to_world(wi), weight: weight, pdf: pdf, specular: false})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::vector::{EuclideanVector, Vector3};
+    use std::f32::consts::PI;
+    use std::rand::{Rng, XorShiftRng};
+
+    use light::Light;
+    use material::{Material, fresnel_dielectric};
+    use microfacet::{Ggx, ComplexIor, RoughConductorMaterial, RoughDielectricMaterial, fresnel_conductor};
+    use test_helpers::make_test_surface;
+
+    #[test]
+    fn test_ggx_normalized() {
+        // The projected area of the facets is the area of the surface.
+        for &alpha in [0.05f32, 0.3, 1.0].iter() {
+            let ggx = Ggx::new(alpha);
+            let steps = 20000u;
+            let mut area = 0.0;
+            for i in range(0, steps) {
+                let theta = (i as f32 + 0.5) / steps as f32 * PI / 2.0;
+                let m = Vector3::new(theta.sin(), 0.0, theta.cos());
+                area += 2.0 * PI * ggx.d(m) * theta.cos() * theta.sin() * PI / 2.0 / steps as f32;
+            }
+            assert!((area - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_ggx_sample_visible() {
+        let ggx = Ggx::new(0.4);
+        let wo = Vector3::new(0.6f32, 0.0, 0.8);
+        let mut rng = XorShiftRng::new_unseeded();
+        for _ in range(0u, 1000) {
+            let m = ggx.sample_visible(wo, rng.gen(), rng.gen());
+            assert!((m.length() - 1.0).abs() < 0.0001);
+            assert!(m.z >= 0.0);
+        }
+        // Smith masking is 1 looking straight down.
+        assert!(ggx.g1(Vector3::new(0.0, 0.0, 1.0)) == 1.0);
+        assert!(ggx.g1(wo) < 1.0 && ggx.g2(wo, wo) < ggx.g1(wo));
+    }
+
+    #[test]
+    fn test_fresnel_conductor() {
+        // Without extinction, a conductor is a dielectric.
+        for i in range(0u, 11) {
+            let cos_i = i as f32 / 10.0;
+            assert!((fresnel_conductor(cos_i, 1.5, 0.0) - fresnel_dielectric(cos_i, 1.0 / 1.5)).abs() < 0.0001);
+        }
+        let gold = ComplexIor::gold().fresnel(1.0);
+        assert!(gold.red > gold.green && gold.green > gold.blue);
+        assert!(ComplexIor::from_name("copper").is_some() && ComplexIor::from_name("brass").is_none());
+        let grazing = ComplexIor::aluminium().fresnel(0.0);
+        assert!((grazing.red - 1.0).abs() < 0.0001);
+    }
+
+    /// Checks that the samples of `mat` are consistent with `eval` and
+    /// `pdf`, and returns the mean weight.
+    fn check_samples(mat: &Material, n: Vector3<f32>, dir_in: Vector3<f32>) -> f32 {
+        let surface = make_test_surface(n);
+        let mut rng = XorShiftRng::new_unseeded();
+        let count = 20000u;
+        let mut total = 0.0;
+        for _ in range(0, count) {
+            match mat.sample(&surface, dir_in, &mut rng) {
+                None => (),
+                Some(s) => {
+                    let pdf = mat.pdf(&surface, dir_in, s.direction);
+                    assert!((pdf - s.pdf).abs() <= 0.001 * s.pdf);
+                    let cos = (s.direction.x * n.x + s.direction.y * n.y + s.direction.z * n.z).abs();
+                    let expected = mat.eval(&surface, dir_in, s.direction).mul_s(cos / s.pdf);
+                    assert!((expected.red - s.weight.red).abs() < 0.001);
+                    assert!(s.weight.red <= 1.0 + 0.0001);
+                    total += s.weight.red / count as f32;
+                }
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn test_rough_conductor() {
+        let perfect = ComplexIor {eta: Light::white(0.0), k: Light::white(1000.0)};
+        let n = Vector3::new(0.0f32, 0.0, 1.0);
+        let dir_in = Vector3::new(0.5f32, 0.0, -1.0).normalize();
+        let smooth = RoughConductorMaterial::new(1.0, 1.0, 1.0, perfect, 0.05);
+        // A smooth perfect conductor reflects almost all the light.
+        assert!(check_samples(&smooth, n, dir_in) > 0.98);
+        let rough = RoughConductorMaterial::new(1.0, 1.0, 1.0, ComplexIor::gold(), 0.5);
+        assert!(check_samples(&rough, n, dir_in) < 0.98);
+        // Lit from below the surface.
+        assert!(check_samples(&rough, -n, dir_in) > 0.0);
+    }
+
+    #[test]
+    fn test_rough_dielectric() {
+        let n = Vector3::new(0.0f32, 0.0, 1.0);
+        let mat = RoughDielectricMaterial::new(1.0, 1.0, 1.0, 1.5, 0.3);
+        let outside = check_samples(&mat, n, Vector3::new(0.3f32, 0.0, -1.0).normalize());
+        let inside = check_samples(&mat, n, Vector3::new(0.3f32, 0.0, 1.0).normalize());
+        assert!(outside > 0.85 && inside > 0.85);
+        // Both lobes are sampled.
+        let mut rng = XorShiftRng::new_unseeded();
+        let (mut reflected, mut transmitted) = (0u, 0u);
+        for _ in range(0u, 1000) {
+            match mat.sample(&make_test_surface(n), Vector3::new(0.3f32, 0.0, -1.0).normalize(), &mut rng) {
+                Some(s) => if s.direction.z > 0.0 { reflected += 1 } else { transmitted += 1 },
+                None => ()
+            }
+        }
+        assert!(reflected > 10 && transmitted > 10 * reflected);
+    }
+}
@@ -0,0 +1,435 @@
+use cgmath::point::Point3;
+use cgmath::vector::{EuclideanVector, Vector3};
+use std::collections::HashMap;
+use std::io::{BufferedReader, File};
+
+use material::{Material, EmitterMaterial, GlobalDiffuseMaterial, ReflectiveMaterial, RefractiveMaterial};
+use mesh::TriangleMesh;
+use object::Object;
+use scene::Scene;
+
+/// Loads a Wavefront OBJ file and appends its content to `scene`.
+///
+/// Each group (`g` or `o`) and each material switch (`usemtl`) gives a
+/// separate `Object`. Polygons are triangulated as fans. Material libraries
+/// referenced with `mtllib` are looked up relative to the OBJ file.
+pub fn load(path: &Path, scene: &mut Scene) -> Result<(), String> {
+    let file = try!(open(path));
+    let obj = try!(parse_obj(&mut BufferedReader::new(file), path));
+    let mut materials = HashMap::new();
+    for lib in obj.mtllibs.iter() {
+        let lib_path = path.dir_path().join(lib.as_slice());
+        let file = try!(open(&lib_path));
+        try!(parse_mtl(&mut BufferedReader::new(file), &lib_path, &mut materials));
+    }
+    scene.add_objects(try!(obj.to_objects(&materials, path)));
+    Ok(())
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    File::open(path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn error(path: &Path, line: uint, msg: &str) -> String {
+    format!("{}:{}: {}", path.display(), line, msg)
+}
+
+fn parse_floats(words: &[&str]) -> Option<Vec<f32>> {
+    let floats: Vec<Option<f32>> = words.iter().map(|w| from_str::<f32>(*w)).collect();
+    if floats.iter().all(|f| f.is_some()) {
+        Some(floats.iter().map(|f| f.unwrap()).collect())
+    } else {
+        None
+    }
+}
+
+/// Indices of the position, texture coordinates and normal of a face corner.
+type FaceVertex = (uint, Option<uint>, Option<uint>);
+
+struct Part {
+    material: Option<String>,
+    /// Line of the `usemtl` statement giving the material.
+    material_line: uint,
+    triangles: Vec<[FaceVertex, ..3]>
+}
+
+struct ObjData {
+    positions: Vec<Point3<f32>>,
+    tex_coords: Vec<(f32, f32)>,
+    normals: Vec<Vector3<f32>>,
+    parts: Vec<Part>,
+    mtllibs: Vec<String>
+}
+
+fn parse_obj<B: Buffer>(reader: &mut B, path: &Path) -> Result<ObjData, String> {
+    let mut obj = ObjData {
+        positions: vec![],
+        tex_coords: vec![],
+        normals: vec![],
+        parts: vec![Part {material: None, material_line: 0, triangles: vec![]}],
+        mtllibs: vec![]
+    };
+    for (i, line) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = try!(line.map_err(|e| error(path, line_number, e.to_string().as_slice())));
+        let words: Vec<&str> = line.as_slice().words().collect();
+        if words.is_empty() || words[0].starts_with("#") { continue; }
+        let args = words.slice_from(1);
+        match words[0] {
+            "v" => match parse_floats(args) {
+                Some(ref v) if v.len() >= 3 => obj.positions.push(Point3::new(v[0], v[1], v[2])),
+                _ => return Err(error(path, line_number, "expected 3 coordinates"))
+            },
+            "vt" => match parse_floats(args) {
+                Some(ref v) if v.len() >= 2 => obj.tex_coords.push((v[0], v[1])),
+                Some(ref v) if v.len() == 1 => obj.tex_coords.push((v[0], 0.0)),
+                _ => return Err(error(path, line_number, "expected texture coordinates"))
+            },
+            "vn" => match parse_floats(args) {
+                Some(ref v) if v.len() >= 3 => obj.normals.push(Vector3::new(v[0], v[1], v[2]).normalize()),
+                _ => return Err(error(path, line_number, "expected 3 coordinates"))
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error(path, line_number, "a face needs at least 3 vertices"));
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for arg in args.iter() {
+                    match obj.parse_face_vertex(*arg) {
+                        Ok(fv) => corners.push(fv),
+                        Err(msg) => return Err(error(path, line_number, msg.as_slice()))
+                    }
+                }
+                let part = obj.parts.last_mut().unwrap();
+                for k in range(1, corners.len() - 1) {
+                    part.triangles.push([corners[0], corners[k], corners[k + 1]]);
+                }
+            },
+            "g" | "o" => {
+                let (material, material_line) = {
+                    let part = obj.parts.last().unwrap();
+                    (part.material.clone(), part.material_line)
+                };
+                obj.start_part(material, material_line);
+            },
+            "usemtl" => {
+                if args.is_empty() {
+                    return Err(error(path, line_number, "missing material name"));
+                }
+                obj.start_part(Some(args[0].to_string()), line_number);
+            },
+            "mtllib" => {
+                for lib in args.iter() {
+                    obj.mtllibs.push(lib.to_string());
+                }
+            },
+            // Smoothing groups and other statements have no equivalent here.
+            _ => ()
+        }
+    }
+    Ok(obj)
+}
+
+impl ObjData {
+    fn start_part(&mut self, material: Option<String>, material_line: uint) {
+        if self.parts.last().unwrap().triangles.is_empty() {
+            self.parts.pop();
+        }
+        self.parts.push(Part {material: material, material_line: material_line, triangles: vec![]});
+    }
+
+    fn parse_face_vertex(&self, arg: &str) -> Result<FaceVertex, String> {
+        let indices: Vec<&str> = arg.split('/').collect();
+        if indices.len() > 3 {
+            return Err(format!("invalid face vertex `{}`", arg));
+        }
+        let position = try!(resolve_index(indices[0], self.positions.len()));
+        let tex_coord = if indices.len() > 1 && !indices[1].is_empty() {
+            Some(try!(resolve_index(indices[1], self.tex_coords.len())))
+        } else {
+            None
+        };
+        let normal = if indices.len() > 2 && !indices[2].is_empty() {
+            Some(try!(resolve_index(indices[2], self.normals.len())))
+        } else {
+            None
+        };
+        Ok((position, tex_coord, normal))
+    }
+
+    /// Makes an object of each non-empty part. Parts without `usemtl` get
+    /// the default material, but an unknown material name is an error.
+    fn to_objects(&self, materials: &HashMap<String, MtlMaterial>, path: &Path) -> Result<Vec<Object>, String> {
+        let default = MtlMaterial::new();
+        let mut objects = vec![];
+        for part in self.parts.iter().filter(|part| !part.triangles.is_empty()) {
+            let mtl = match part.material {
+                Some(ref name) => match materials.find(name) {
+                    Some(mtl) => mtl,
+                    None => return Err(error(path, part.material_line, format!("unknown material `{}`", name).as_slice()))
+                },
+                None => &default
+            };
+            objects.push(Object {
+                shape: box self.make_mesh(part),
+                material: mtl.to_material()
+            });
+        }
+        Ok(objects)
+    }
+
+    fn make_mesh(&self, part: &Part) -> TriangleMesh {
+        // OBJ indexes positions, texture coordinates and normals separately,
+        // so each distinct combination becomes a vertex of the mesh.
+        let mut index: HashMap<FaceVertex, uint> = HashMap::new();
+        let mut corners = vec![];
+        let mut triangles = Vec::with_capacity(part.triangles.len());
+        for tri in part.triangles.iter() {
+            let mut indices = [0u, ..3];
+            for k in range(0, 3) {
+                let fv = tri[k];
+                indices[k] = match index.find_copy(&fv) {
+                    Some(i) => i,
+                    None => {
+                        let i = corners.len();
+                        index.insert(fv, i);
+                        corners.push(fv);
+                        i
+                    }
+                };
+            }
+            triangles.push(indices);
+        }
+        let vertices = corners.iter().map(|&(p, _, _)| self.positions[p]).collect();
+        let mesh = if corners.iter().all(|&(_, _, n)| n.is_some()) {
+            let normals = corners.iter().map(|&(_, _, n)| self.normals[n.unwrap()]).collect();
+            TriangleMesh::new(vertices, normals, triangles)
+        } else {
+            TriangleMesh::with_smooth_normals(vertices, triangles)
+        };
+        if corners.iter().all(|&(_, t, _)| t.is_some()) {
+            let uvs = corners.iter().map(|&(_, t, _)| self.tex_coords[t.unwrap()]).collect();
+            mesh.with_uvs(uvs)
+        } else {
+            mesh
+        }
+    }
+}
+
+/// Converts a one-based (or negative, relative) OBJ index to a zero-based one.
+fn resolve_index(s: &str, len: uint) -> Result<uint, String> {
+    match from_str::<int>(s) {
+        Some(i) if i > 0 && (i as uint) <= len => Ok(i as uint - 1),
+        Some(i) if i < 0 && ((-i) as uint) <= len => Ok(len - (-i) as uint),
+        _ => Err(format!("invalid index `{}`", s))
+    }
+}
+
+/// The subset of an MTL material that maps onto our materials.
+struct MtlMaterial {
+    diffuse: (f32, f32, f32),
+    specular: Option<(f32, f32, f32)>,
+    emission: (f32, f32, f32),
+    transmission: Option<(f32, f32, f32)>,
+    index: f32,
+    dissolve: f32,
+    illum: uint
+}
+
+impl MtlMaterial {
+    fn new() -> MtlMaterial {
+        MtlMaterial {
+            diffuse: (0.8, 0.8, 0.8),
+            specular: None,
+            emission: (0.0, 0.0, 0.0),
+            transmission: None,
+            index: 1.0,
+            dissolve: 1.0,
+            illum: 2
+        }
+    }
+
+    fn to_material(&self) -> Box<Material + Send + Sync> {
+        let (er, eg, eb) = self.emission;
+        if er > 0.0 || eg > 0.0 || eb > 0.0 {
+            box EmitterMaterial::new(er, eg, eb) as Box<Material + Send + Sync>
+        } else if self.dissolve < 1.0 || self.illum == 4 || self.illum == 6 || self.illum == 7 {
+            let (r, g, b) = self.transmission.unwrap_or((1.0, 1.0, 1.0));
+            box RefractiveMaterial::new(r, g, b, self.index) as Box<Material + Send + Sync>
+        } else if self.illum == 3 {
+            let (r, g, b) = self.specular.unwrap_or(self.diffuse);
+            box ReflectiveMaterial::new(r, g, b) as Box<Material + Send + Sync>
+        } else {
+            let (r, g, b) = self.diffuse;
+            box GlobalDiffuseMaterial::new(r, g, b) as Box<Material + Send + Sync>
+        }
+    }
+}
+
+fn parse_mtl<B: Buffer>(reader: &mut B, path: &Path, materials: &mut HashMap<String, MtlMaterial>) -> Result<(), String> {
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (i, line) in reader.lines().enumerate() {
+        let line_number = i + 1;
+        let line = try!(line.map_err(|e| error(path, line_number, e.to_string().as_slice())));
+        let words: Vec<&str> = line.as_slice().words().collect();
+        if words.is_empty() || words[0].starts_with("#") { continue; }
+        let args = words.slice_from(1);
+        if words[0] == "newmtl" {
+            if args.is_empty() {
+                return Err(error(path, line_number, "missing material name"));
+            }
+            match current.take() {
+                Some((name, mtl)) => { materials.insert(name, mtl); },
+                None => ()
+            }
+            current = Some((args[0].to_string(), MtlMaterial::new()));
+            continue;
+        }
+        let mtl = match current {
+            Some((_, ref mut mtl)) => mtl,
+            None => return Err(error(path, line_number, "statement before `newmtl`"))
+        };
+        let color = || match parse_floats(args) {
+            Some(ref v) if v.len() >= 3 => Ok((v[0], v[1], v[2])),
+            Some(ref v) if v.len() == 1 => Ok((v[0], v[0], v[0])),
+            _ => Err(error(path, line_number, "expected a color"))
+        };
+        let scalar = || match parse_floats(args) {
+            Some(ref v) if v.len() >= 1 => Ok(v[0]),
+            _ => Err(error(path, line_number, "expected a number"))
+        };
+        match words[0] {
+            "Kd" => mtl.diffuse = try!(color()),
+            "Ks" => mtl.specular = Some(try!(color())),
+            "Ke" => mtl.emission = try!(color()),
+            "Tf" => mtl.transmission = Some(try!(color())),
+            "Ni" => mtl.index = try!(scalar()),
+            "d" => mtl.dissolve = try!(scalar()),
+            "Tr" => mtl.dissolve = 1.0 - try!(scalar()),
+            "illum" => mtl.illum = match args.get(0).and_then(|w| from_str::<uint>(*w)) {
+                Some(model) if model <= 10 => model,
+                _ => return Err(error(path, line_number, "expected an illumination model from 0 to 10"))
+            },
+            _ => ()
+        }
+    }
+    match current {
+        Some((name, mtl)) => { materials.insert(name, mtl); },
+        None => ()
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::vector::Vector3;
+    use std::collections::HashMap;
+    use std::f32::consts::PI;
+    use std::io::BufReader;
+    use std::rand::XorShiftRng;
+
+    use material::Material;
+    use obj::{parse_obj, parse_mtl};
+    use test_helpers::make_test_surface;
+
+    fn parse(src: &str) -> Result<super::ObjData, String> {
+        parse_obj(&mut BufReader::new(src.as_bytes()), &Path::new("test.obj"))
+    }
+
+    #[test]
+    fn test_parse_obj_triangulates_polygons() {
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n").unwrap();
+        assert!(obj.positions.len() == 4);
+        assert!(obj.parts.len() == 1);
+        assert!(obj.parts[0].triangles.len() == 2);
+        let objects = obj.to_objects(&HashMap::new(), &Path::new("test.obj")).unwrap();
+        assert!(objects.len() == 1);
+    }
+
+    #[test]
+    fn test_to_objects_rejects_unknown_materials() {
+        let obj = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nusemtl missing\ng other\nf 1 2 3\n").unwrap();
+        let res = obj.to_objects(&HashMap::new(), &Path::new("test.obj"));
+        assert!(res.unwrap_err().as_slice() == "test.obj:4: unknown material `missing`");
+    }
+
+    #[test]
+    fn test_parse_obj_groups_and_materials() {
+        let src = "mtllib scene.mtl\n\
+                   v 0 0 0\nv 1 0 0\nv 1 1 0\n\
+                   vt 0 0\nvt 1 0\nvt 1 1\n\
+                   vn 0 0 1\n\
+                   g first\nusemtl red\nf 1/1/1 2/2/1 3/3/1\n\
+                   g second\nf -3//1 -2//1 -1//1\n";
+        let obj = parse(src).unwrap();
+        assert!(obj.mtllibs == vec!["scene.mtl".to_string()]);
+        assert!(obj.parts.len() == 2);
+        assert!(obj.parts[0].material == Some("red".to_string()));
+        assert!(obj.parts[1].material == Some("red".to_string()));
+        assert!(obj.parts[0].triangles[0][1] == (1, Some(1), Some(0)));
+        assert!(obj.parts[1].triangles[0][0] == (0, None, Some(0)));
+    }
+
+    #[test]
+    fn test_parse_obj_reports_line_numbers() {
+        let res = parse("v 0 0 0\nv 1 0 0\nf 1 2 3\n");
+        assert!(res.is_err());
+        assert!(res.unwrap_err().as_slice().starts_with("test.obj:3:"));
+    }
+
+    #[test]
+    fn test_parse_mtl() {
+        let src = "newmtl red\nKd 1 0 0\n\nnewmtl glass\nNi 1.5\nd 0.2\n";
+        let mut materials = HashMap::new();
+        let res = parse_mtl(&mut BufReader::new(src.as_bytes()), &Path::new("test.mtl"), &mut materials);
+        assert!(res.is_ok());
+        assert!(materials.len() == 2);
+        assert!(materials.find(&"red".to_string()).unwrap().diffuse == (1.0, 0.0, 0.0));
+        let glass = materials.find(&"glass".to_string()).unwrap();
+        assert!(glass.index == 1.5);
+        assert!(glass.dissolve == 0.2);
+    }
+
+    #[test]
+    fn test_parse_mtl_illum() {
+        let mut materials = HashMap::new();
+        let src = "newmtl mirror\nillum 3\n";
+        assert!(parse_mtl(&mut BufReader::new(src.as_bytes()), &Path::new("test.mtl"), &mut materials).is_ok());
+        assert!(materials.find(&"mirror".to_string()).unwrap().illum == 3);
+        for &bad in ["illum -1", "illum 2.5", "illum 11", "illum"].iter() {
+            let src = format!("newmtl bad\n{}\n", bad);
+            let res = parse_mtl(&mut BufReader::new(src.as_bytes()), &Path::new("test.mtl"), &mut materials);
+            assert!(res.unwrap_err().as_slice().starts_with("test.mtl:2:"));
+        }
+    }
+    #[test]
+    fn test_mtl_to_material() {
+        let src = "newmtl lamp\nKe 2 2 2\n\
+                   newmtl red\nKd 1 0 0\n\
+                   newmtl mirror\nKs 0 1 0\nillum 3\n\
+                   newmtl glass\nNi 1.5\nillum 7\n";
+        let mut materials = HashMap::new();
+        assert!(parse_mtl(&mut BufReader::new(src.as_bytes()), &Path::new("test.mtl"), &mut materials).is_ok());
+        let get = |name: &str| materials.find(&name.to_string()).unwrap().to_material();
+        let surface = make_test_surface(Vector3::new(0.0, 0.0, 1.0));
+        let (dir_in, dir_out) = (Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        let mut rng = XorShiftRng::new_unseeded();
+
+        assert!(get("lamp").emits());
+
+        let red = get("red");
+        assert!(!red.emits());
+        let diffuse = red.eval(&surface, dir_in, dir_out);
+        assert!((diffuse.red - 1.0 / PI).abs() < 0.0001 && diffuse.green == 0.0);
+
+        let mirror = get("mirror").sample(&surface, dir_in, &mut rng).unwrap();
+        assert!(mirror.specular && mirror.direction == dir_out);
+        assert!(mirror.weight.red == 0.0 && mirror.weight.green == 1.0);
+
+        // At normal incidence glass of index 1.5 reflects 4% of the light.
+        let glass = get("glass").sample(&surface, dir_in, &mut rng).unwrap();
+        assert!(glass.specular);
+        assert!((glass.pdf - 0.04).abs() < 0.001 || (glass.pdf - 0.96).abs() < 0.001);
+    }
+}
@@ -1,9 +1,11 @@
 use cgmath::vector::Vector3;
 use cgmath::ray::Ray3;
 use cgmath::point::Point3;
+use std::rand::XorShiftRng;
 
-use shape::Shape;
-use material::Material;
+use bvh::BoundingBox;
+use shape::{Hit, Shape, SurfacePoint};
+use material::{Material, BsdfSample};
 use light::Light;
 
 pub struct Object {
@@ -12,25 +14,39 @@ pub struct Object {
 }
 
 impl Object {
-    pub fn emittance(&self, p: Point3<f32>, dir: Vector3<f32>) -> Light {
-        self.material.emittance(self.normal(p), dir)
+    /// Surface at a point which was not found by intersection, such as a
+    /// sampled one.
+    pub fn surface(&self, point: Point3<f32>) -> SurfacePoint {
+        SurfacePoint {point: point, normal: self.shape.normal(point), uv: self.shape.uv(point)}
     }
-    pub fn reflectance(&self, p: Point3<f32>, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
-        self.material.reflectance(self.normal(p), dir_in, dir_out)
+    pub fn emittance(&self, surface: &SurfacePoint, dir: Vector3<f32>) -> Light {
+        self.material.emittance(surface, dir)
     }
-    pub fn intersect(&self, ray: Ray3<f32>) -> Option<(&Object, Point3<f32>)> {
-        match self.shape.intersect(ray) {
-            None => None,
-            Some(point) => Some((self, point))
-        }
+    pub fn emits(&self) -> bool {
+        self.material.emits()
     }
-    pub fn shadow_intersect(&self, ray: Ray3<f32>, length: f32) -> bool {
-        self.shape.shadow_intersect(ray, length)
+    pub fn reflectance(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        self.material.reflectance(surface, dir_in, dir_out)
     }
-    pub fn normal(&self, point: Point3<f32>) -> Vector3<f32> {
-        self.shape.normal(point)
+    pub fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        self.shape.intersect(ray, t_min, t_max)
     }
-    pub fn next_step(&self, point: Point3<f32>, dir_in: Vector3<f32>, tracer: |Ray3<f32>| -> Light) -> Light {
-        self.material.next_step(point, self.normal(point), dir_in, tracer)
+    pub fn shadow_intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> bool {
+        self.shape.shadow_intersect(ray, t_min, t_max)
+    }
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        self.shape.bounds()
+    }
+    pub fn eval(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> Light {
+        self.material.eval(surface, dir_in, dir_out)
+    }
+    pub fn pdf(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, dir_out: Vector3<f32>) -> f32 {
+        self.material.pdf(surface, dir_in, dir_out)
+    }
+    pub fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, rng: &mut XorShiftRng) -> Option<BsdfSample> {
+        self.material.sample(surface, dir_in, rng)
+    }
+    pub fn absorption(&self) -> Light {
+        self.material.absorption()
     }
 }
\ No newline at end of file
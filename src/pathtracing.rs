@@ -0,0 +1,337 @@
+use cgmath::ray::Ray3;
+use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+use cgmath::point::Point;
+use std::f32;
+use std::rand::{Rng, XorShiftRng};
+
+use scene::Scene;
+use object::Object;
+use light::Light;
+use shape::{Hit, SurfacePoint};
+
+/// Number of bounces before paths can be terminated by Russian roulette.
+static ROULETTE_DEPTH: uint = 3;
+
+/// Fraction of the distance to a point sampled on an emitter left out of
+/// shadow rays, so that the emitter does not hide itself. Being relative,
+/// it holds at any scale.
+static SHADOW_MARGIN: f32 = 0.001;
+
+/// Estimates the light coming along `ray` by following a single path.
+///
+/// At each vertex the material samples the next direction and the
+/// throughput of the path is multiplied by the sample weight. After a few
+/// bounces, paths carrying little light are stopped at random, and the
+/// surviving ones are boosted to keep the estimate unbiased. `max_depth`
+/// still bounds the number of bounces.
+///
+/// Emissive objects and the environment are also sampled at each vertex
+/// (next-event estimation). Light found both ways is weighted by multiple
+/// importance sampling with the power heuristic, so it is not counted twice.
+///
+/// Segments of the path inside an absorbing object are attenuated by their
+/// length. Whether a transmitted ray goes in or out is told by the
+/// geometric normal, so absorbing objects must be closed with outward
+/// normals.
+pub fn trace_path(scene: &Scene, ray: Ray3<f32>, max_depth: uint, rng: &mut XorShiftRng) -> Light {
+    let mut radiance = Light::zero();
+    let mut throughput = Light::white(1.0);
+    let mut ray = ray;
+    let mut depth = 0;
+    // Absorption of the medium the ray travels through.
+    let mut medium: Option<Light> = None;
+    // Density of the direction sampled at the previous vertex, or `None`
+    // when emitters could not have been sampled there.
+    let mut bsdf_pdf: Option<f32> = None;
+    loop {
+        let (object, hit) = match scene.intersect(ray) {
+            None => {
+                let weight = match bsdf_pdf {
+                    Some(pdf) => mis_weight(pdf, scene.background_pdf(ray.direction)),
+                    None => 1.0
+                };
+                radiance = radiance + throughput.mul_l(scene.background(ray.direction).mul_s(weight));
+                break;
+            },
+            Some(hit) => hit
+        };
+        match medium {
+            Some(absorption) => {
+                let distance = hit.t * ray.direction.length();
+                throughput = throughput.mul_l(transmittance(absorption, distance));
+            },
+            None => ()
+        }
+        let surface = hit.surface();
+        let emitted = match bsdf_pdf {
+            Some(pdf) if object.emits() => {
+                let weight = mis_weight(pdf, scene.emitter_pdf(object, ray.origin, hit.point));
+                object.emittance(&surface, -ray.direction).mul_s(weight)
+            },
+            _ => object.emittance(&surface, -ray.direction)
+        };
+        // No material sample follows the last vertex to take its share of
+        // the light sampled there.
+        let last = depth == max_depth;
+        let local = emitted + light_sources(scene, object, &hit, &surface, ray.direction, rng)
+            + emitters(scene, object, &hit, &surface, ray.direction, last, rng)
+            + environment(scene, object, &hit, &surface, ray.direction, last, rng);
+        radiance = radiance + throughput.mul_l(local);
+        if last { break; }
+        let sample = match object.sample(&surface, ray.direction, rng) {
+            None => break,
+            Some(sample) => sample
+        };
+        // The side of the actual surface, which an interpolated shading
+        // normal can get wrong near silhouettes.
+        let n = hit.geometric_normal;
+        if dot(n, ray.direction) * dot(n, sample.direction) > 0.0 {
+            medium = if dot(n, sample.direction) < 0.0 { Some(object.absorption()) } else { None };
+        }
+        throughput = throughput.mul_l(sample.weight);
+        bsdf_pdf = if sample.specular { None } else { Some(sample.pdf) };
+        depth += 1;
+        if depth >= ROULETTE_DEPTH {
+            let survival = throughput.max_component().min(0.95);
+            if survival <= 0.0 || rng.gen::<f32>() >= survival { break; }
+            throughput = throughput.mul_s(1.0 / survival);
+        }
+        ray = hit.spawn_ray(sample.direction);
+    }
+    radiance
+}
+
+/// Fraction of light going through `distance` of a medium (Beer-Lambert law).
+fn transmittance(absorption: Light, distance: f32) -> Light {
+    Light::new((-absorption.red * distance).exp(),
+               (-absorption.green * distance).exp(),
+               (-absorption.blue * distance).exp())
+}
+
+/// Light reflected towards `-dir` from the light sources, with one shadow
+/// ray towards a sampled point of each.
+fn light_sources(scene: &Scene, object: &Object, hit: &Hit, surface: &SurfacePoint, dir: Vector3<f32>, rng: &mut XorShiftRng) -> Light {
+    let mut reflected = Light::zero();
+    for source in scene.light_sources.iter() {
+        let sample = match source.sample(surface.point, rng) {
+            None => continue,
+            Some(sample) => sample
+        };
+        let shadow_ray = hit.spawn_ray(sample.direction);
+        if !scene.shadow_intersect(shadow_ray, sample.distance) {
+            let reflectance = object.reflectance(surface, -sample.direction, -dir);
+            reflected = reflected + sample.light.mul_l(reflectance);
+        }
+    }
+    reflected
+}
+
+/// Light reflected towards `-dir` from a point sampled on an emissive
+/// object, weighted against finding it by sampling the material unless
+/// `last` tells that the path stops there.
+fn emitters(scene: &Scene, object: &Object, hit: &Hit, surface: &SurfacePoint, dir: Vector3<f32>, last: bool,
+            rng: &mut XorShiftRng) -> Light {
+    let (emitter, point, light_pdf) = match scene.sample_emitter(surface.point, rng) {
+        None => return Light::zero(),
+        Some(sample) => sample
+    };
+    let to_light = point.sub_p(&surface.point);
+    let distance = to_light.length();
+    if light_pdf <= 0.0 || distance <= 0.0 {
+        return Light::zero();
+    }
+    let direction = to_light.div_s(distance);
+    let f = object.eval(surface, dir, direction);
+    if f == Light::zero() {
+        return Light::zero();
+    }
+    if scene.shadow_intersect(hit.spawn_ray(direction), distance * (1.0 - SHADOW_MARGIN)) {
+        return Light::zero();
+    }
+    let emitted = emitter.emittance(&emitter.surface(point), -direction);
+    let weight = if last { 1.0 } else { mis_weight(light_pdf, object.pdf(surface, dir, direction)) };
+    let cos = dot(surface.normal, direction).abs();
+    emitted.mul_l(f).mul_s(cos * weight / light_pdf)
+}
+
+/// Light reflected towards `-dir` from a sampled direction of the
+/// environment, weighted against finding it by sampling the material unless
+/// `last` tells that the path stops there.
+fn environment(scene: &Scene, object: &Object, hit: &Hit, surface: &SurfacePoint, dir: Vector3<f32>, last: bool,
+               rng: &mut XorShiftRng) -> Light {
+    let (direction, env_pdf) = match scene.sample_background(rng) {
+        None => return Light::zero(),
+        Some(sample) => sample
+    };
+    let f = object.eval(surface, dir, direction);
+    if f == Light::zero() || scene.shadow_intersect(hit.spawn_ray(direction), f32::INFINITY) {
+        return Light::zero();
+    }
+    let weight = if last { 1.0 } else { mis_weight(env_pdf, object.pdf(surface, dir, direction)) };
+    let cos = dot(surface.normal, direction).abs();
+    scene.background(direction).mul_l(f).mul_s(cos * weight / env_pdf)
+}
+
+/// Power heuristic weight of a sample taken with density `pdf`, when
+/// another strategy could have taken it with density `other_pdf`.
+fn mis_weight(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::ray::Ray;
+    use cgmath::vector::{EuclideanVector, Vector3};
+    use cgmath::point::{Point, Point3};
+    use cgmath::sphere::Sphere;
+    use std::f32::consts::PI;
+    use std::rand::XorShiftRng;
+
+    use pathtracing::trace_path;
+    use light::{Light, LightSource, DiskLight};
+    use material::{Material, BsdfSample, GlobalDiffuseMaterial, RefractiveMaterial, TestMaterial};
+    use object::Object;
+    use scene::Scene;
+    use shape::{Plane, SurfacePoint};
+    use test_helpers::make_test_scene;
+
+    macro_rules! assert_tp(
+        ($scene:expr, $ray:expr, $r:expr, $g:expr, $b:expr) => (
+            assert!(Light::new($r, $g, $b) == trace_path($scene, $ray, 1, &mut XorShiftRng::new_unseeded()))
+        );
+    )
+
+    #[test]
+    fn test_trace_path() {
+        let delta = 0.000001f32;
+        let scene = make_test_scene();
+        let origin = Point::origin();
+        let ray_miss = Ray::new(origin, Vector3::new(12.0/5.0 + delta, 0.0, 16.0/5.0).normalize());
+        assert_tp!(&scene, ray_miss, 0.0, 0.0, 0.0);
+        let ray_hit = Ray::new(origin, Vector3::new(12.0/5.0, 0.0, 16.0/5.0).normalize());
+        assert_tp!(&scene, ray_hit, 1.0, 1.0, 1.0);
+        let ray_miss2 = Ray::new(origin, Vector3::new(-12.0/5.0 - delta, 0.0, 16.0/5.0).normalize());
+        assert_tp!(&scene, ray_miss2, 0.0, 0.0, 0.0);
+        let ray_hit2 = Ray::new(origin, Vector3::new(-12.0/5.0, 0.0, 16.0/5.0).normalize());
+        assert_tp!(&scene, ray_hit2, 1.0, 1.0, 1.0);
+    }
+
+    /// Diffuse surface emitting the same light in every direction.
+    struct GlowingMaterial {
+        diffuse: GlobalDiffuseMaterial,
+        glow: Light
+    }
+
+    impl Material for GlowingMaterial {
+        fn emittance(&self, _: &SurfacePoint, _: Vector3<f32>) -> Light {
+            self.glow
+        }
+        fn sample(&self, surface: &SurfacePoint, dir_in: Vector3<f32>, rng: &mut XorShiftRng) -> Option<BsdfSample> {
+            self.diffuse.sample(surface, dir_in, rng)
+        }
+    }
+
+    #[test]
+    fn test_white_furnace() {
+        // Inside a closed sphere emitting `glow` and reflecting a fraction
+        // `albedo` of the light, the radiance is glow / (1 - albedo)
+        // everywhere. Russian roulette must not bias the estimate.
+        let material = GlowingMaterial {diffuse: GlobalDiffuseMaterial::new(0.5, 0.5, 0.5), glow: Light::white(0.5)};
+        let sphere = Object {shape: box Sphere {center: Point::origin(), radius: 1.0}, material: box material};
+        let scene = Scene::new(vec![sphere], vec![]);
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 4000u;
+        let mut total = Light::zero();
+        for i in range(0, n) {
+            let angle = i as f32;
+            let ray = Ray::new(Point::origin(), Vector3::new(angle.cos(), angle.sin(), 0.5).normalize());
+            total = total + trace_path(&scene, ray, 200, &mut rng);
+        }
+        let mean = total.mul_s(1.0 / n as f32);
+        assert!((mean.red - 1.0).abs() < 0.03);
+        assert!(mean.red == mean.green && mean.green == mean.blue);
+    }
+
+    #[test]
+    fn test_absorption() {
+        // Light from an emitter seen through a ball which does not bend
+        // light, crossing 2 units of the medium on the axis.
+        let glass = RefractiveMaterial::new(1.0, 1.0, 1.0, 1.0).with_absorption(0.1, 0.5, 0.0);
+        let ball = Object {shape: box Sphere {center: Point3::new(0.0, 0.0, 5.0), radius: 1.0}, material: box glass};
+        let emitter = Object {shape: box Sphere {center: Point3::new(0.0, 0.0, 20.0), radius: 3.0}, material: box TestMaterial};
+        let scene = Scene::new(vec![ball, emitter], vec![]);
+        let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 1.0));
+        let light = trace_path(&scene, ray, 4, &mut XorShiftRng::new_unseeded());
+        assert!((light.red - (-0.2f32).exp()).abs() < 0.001);
+        assert!((light.green - (-1.0f32).exp()).abs() < 0.001);
+        assert!(light.blue == 1.0);
+        // Off axis, the ray crosses less of the medium.
+        let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.1, 1.0).normalize());
+        let light_off_axis = trace_path(&scene, ray, 4, &mut XorShiftRng::new_unseeded());
+        assert!(light_off_axis.green > light.green);
+    }
+
+    #[test]
+    fn test_area_light() {
+        // A disk of radius 0.5 facing a diffuse plane from 1 unit away gives
+        // an irradiance of PI / 5 at the foot of its axis. The light is not
+        // in the way of the camera ray.
+        let plane = Object {shape: box Plane::from_abcd(0.0f32, 0.0, -1.0, 1.0), material: box GlobalDiffuseMaterial::new(0.5, 0.5, 0.5)};
+        let disk = DiskLight::new(Point::origin(), Vector3::new(0.0, 0.0, 1.0), 0.5, Light::white(1.0)).unwrap();
+        let scene = Scene::new(vec![plane], vec![box disk as Box<LightSource + Send + Sync>]);
+        let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 1.0));
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 10000u;
+        let mut total = 0.0;
+        for _ in range(0, n) {
+            total += trace_path(&scene, ray, 0, &mut rng).red;
+        }
+        let expected = 0.5 / PI * PI / 5.0;
+        assert!((total / n as f32 - expected).abs() < 0.002);
+    }
+
+    /// Emits the same light in every direction, and is sampled as a light.
+    struct LampMaterial;
+
+    impl Material for LampMaterial {
+        fn emittance(&self, _: &SurfacePoint, _: Vector3<f32>) -> Light {
+            Light::white(1.0)
+        }
+        fn emits(&self) -> bool {
+            true
+        }
+    }
+
+    /// A lamp of radius 1/4 centered 1/2 above a diffuse plane gives an
+    /// irradiance of PI / 4 at the foot of its axis. Returns the radiance
+    /// reflected there towards a camera ray, averaged over many paths.
+    fn lamp_on_plane(max_depth: uint) -> f32 {
+        let plane = Object {shape: box Plane::from_abcd(0.0f32, 0.0, -1.0, 1.0), material: box GlobalDiffuseMaterial::new(0.5, 0.5, 0.5)};
+        let lamp = Object {shape: box Sphere {center: Point3::new(0.0, 0.0, 0.5), radius: 0.25}, material: box LampMaterial};
+        let scene = Scene::new(vec![plane, lamp], vec![]);
+        let ray = Ray::new(Point3::new(-1.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 1.0).normalize());
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 10000u;
+        let mut total = 0.0;
+        for _ in range(0, n) {
+            total += trace_path(&scene, ray, max_depth, &mut rng).red;
+        }
+        total / n as f32
+    }
+
+    #[test]
+    fn test_emitter_sampling() {
+        // Found both by sampling the lamp and by bouncing off the plane,
+        // the lamp must be counted once.
+        assert!((lamp_on_plane(1) - 0.5 / PI * PI / 4.0).abs() < 0.003);
+    }
+
+    #[test]
+    fn test_emitter_sampling_at_last_bounce() {
+        // Without any bounce to find the lamp by sampling the plane, the
+        // lamp samples alone must give all of the irradiance.
+        assert!((lamp_on_plane(0) - 0.5 / PI * PI / 4.0).abs() < 0.003);
+    }
+}
@@ -1,80 +1,257 @@
-extern crate image;
-
-use cgmath::point::Point;
-use image::GenericImage;
-use std::num::Bounded;
+use std::cmp::{max, min};
+use std::os;
+use std::rand::{Rng, XorShiftRng};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, SeqCst};
+use std::task::spawn;
 
 use scene::Scene;
 use light::Light;
 use camera::Camera;
-use raytracing::trace_ray;
+use film::Film;
+use filter::{Filter, BoxFilter};
+use tonemap::{ToneMap, Srgb};
+use pathtracing::trace_path;
+use utils::pixel_rng;
 
-pub fn pixel<T: Camera>(camera: &T, scene: &Scene, x: u32, y: u32) -> image::Rgb<u8> {
-    let ray = camera.make_ray(x, y);
-    color_from_light(trace_ray(scene, ray, 4))
+pub struct RenderSettings {
+    pub width: u32,
+    pub height: u32,
+    pub bounces: uint,
+    pub samples: uint,
+    pub threads: uint,
+    /// Side of the square buckets the image is split into.
+    pub tile_size: u32,
+    pub seed: u32,
+    /// Reconstruction filter weighting the samples of each pixel.
+    pub filter: Filter,
+    /// Display transform of 8 bit output images.
+    pub tone_map: ToneMap,
+    /// Exposure adjustment of 8 bit output images, in stops.
+    pub exposure: f32
 }
 
-pub fn image(width: u32, height: u32, renderer: PixelRenderer) -> image::ImageBuf<image::Rgb<u8>> {
-    let mut buffer = image::ImageBuf::new(width, height);
-    for y in range(0, height) {
-        for x in range(0, width) {
-            let pixel = renderer(x, y);
-            buffer.put_pixel(x, y, pixel);
-        }
+impl RenderSettings {
+    pub fn new() -> RenderSettings {
+        RenderSettings {width: 1000, height: 1000, bounces: 8, samples: 1, threads: os::num_cpus(), tile_size: 32, seed: 0,
+                        filter: BoxFilter, tone_map: Srgb, exposure: 0.0}
+    }
+}
+
+pub trait PixelRenderer: Send + Sync {
+    fn render(&self, x: u32, y: u32, rng: &mut XorShiftRng) -> Light;
+}
+
+pub struct SceneRenderer {
+    pub camera: Box<Camera + Send + Sync>,
+    pub scene: Scene,
+    pub bounces: uint,
+    pub samples: uint,
+    pub filter: Filter
+}
+
+impl SceneRenderer {
+    pub fn new(camera: Box<Camera + Send + Sync>, scene: Scene, settings: &RenderSettings) -> SceneRenderer {
+        SceneRenderer {camera: camera, scene: scene, bounces: settings.bounces, samples: settings.samples, filter: settings.filter}
+    }
+}
+
+impl PixelRenderer for SceneRenderer {
+    fn render(&self, x: u32, y: u32, rng: &mut XorShiftRng) -> Light {
+        pixel(&*self.camera, &self.scene, x, y, self.bounces, self.samples, &self.filter, rng)
     }
-    buffer
 }
 
-type PixelRenderer<'a> = |u32, u32|:'a -> image::Rgb<u8>;
+/// Estimates the light reaching pixel (x, y), which covers the film area
+/// [x, x + 1) x [y, y + 1).
+///
+/// The samples are jittered over the footprint of the filter around the
+/// pixel center, on a stratified grid when `samples` is a square, and
+/// averaged with the filter weights.
+pub fn pixel(camera: &Camera, scene: &Scene, x: u32, y: u32, bounces: uint, samples: uint, filter: &Filter, rng: &mut XorShiftRng) -> Light {
+    let radius = filter.radius();
+    let strata = (samples as f32).sqrt() as uint;
+    let stratified = strata * strata == samples;
+    let mut light = Light::zero();
+    let mut total_light = Light::zero();
+    let mut total_weight = 0.0;
+    for i in range(0, samples) {
+        let (u, v) = if stratified {
+            (((i % strata) as f32 + rng.gen()) / strata as f32, ((i / strata) as f32 + rng.gen()) / strata as f32)
+        } else {
+            (rng.gen(), rng.gen())
+        };
+        let (dx, dy) = ((2.0 * u - 1.0) * radius, (2.0 * v - 1.0) * radius);
+        let ray = camera.make_ray(x as f32 + 0.5 + dx, y as f32 + 0.5 + dy, (rng.gen(), rng.gen()));
+        let sample = trace_path(scene, ray, bounces, rng);
+        let weight = filter.weight(dx, dy);
+        light = light + sample.mul_s(weight);
+        total_light = total_light + sample;
+        total_weight += weight;
+    }
+    if total_weight > 0.0 {
+        light.mul_s(1.0 / total_weight)
+    } else {
+        // Negative lobes can cancel out with few samples.
+        total_light.mul_s(1.0 / samples as f32)
+    }
+}
 
-fn color_from_light(light: Light) -> image::Rgb<u8> {
-    image::Rgb(convert(light.red), convert(light.green), convert(light.blue))
+struct Tile {
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32
+}
+
+fn make_tiles(width: u32, height: u32, size: u32) -> Vec<Tile> {
+    let mut tiles = vec![];
+    let mut y0 = 0;
+    while y0 < height {
+        let mut x0 = 0;
+        while x0 < width {
+            tiles.push(Tile {x0: x0, y0: y0, x1: min(x0 + size, width), y1: min(y0 + size, height)});
+            x0 += size;
+        }
+        y0 += size;
+    }
+    tiles
 }
 
-fn convert(x: f32) -> u8 {
-    let low: u8 = Bounded::min_value();
-    let high: u8 = Bounded::max_value();
-    let scaled = x * high as f32;
-    let fenced = scaled.max(low as f32).min(high as f32);
-    fenced.round() as u8
+/// Renders the image tile by tile on `settings.threads` tasks, into a
+/// floating point film.
+///
+/// Each pixel gets its own random number generator derived from the seed,
+/// so the result does not depend on the number of threads. Fails if a
+/// worker does, rather than leaving its tile black.
+pub fn image<R: PixelRenderer>(settings: &RenderSettings, renderer: Arc<R>) -> Film {
+    let tiles = Arc::new(make_tiles(settings.width, settings.height, settings.tile_size));
+    let next_tile = Arc::new(AtomicUint::new(0));
+    let (tx, rx) = channel();
+    for _ in range(0, max(settings.threads, 1)) {
+        let (tiles, next_tile, tx, renderer) = (tiles.clone(), next_tile.clone(), tx.clone(), renderer.clone());
+        let seed = settings.seed;
+        spawn(proc() {
+            loop {
+                let i = next_tile.fetch_add(1, SeqCst);
+                if i >= tiles.len() { break; }
+                let tile = tiles.as_slice()[i];
+                let mut pixels = Vec::with_capacity(((tile.x1 - tile.x0) * (tile.y1 - tile.y0)) as uint);
+                for y in range(tile.y0, tile.y1) {
+                    for x in range(tile.x0, tile.x1) {
+                        pixels.push(renderer.render(x, y, &mut pixel_rng(seed, x, y)));
+                    }
+                }
+                tx.send((tile, pixels));
+            }
+        });
+    }
+    // The loop below ends once every worker has dropped its sender.
+    drop(tx);
+    let mut film = Film::new(settings.width, settings.height);
+    let mut received = 0u;
+    for (tile, pixels) in rx.iter() {
+        let mut it = pixels.into_iter();
+        for y in range(tile.y0, tile.y1) {
+            for x in range(tile.x0, tile.x1) {
+                film.set(x, y, it.next().unwrap());
+            }
+        }
+        received += 1;
+    }
+    // A failed worker drops its sender with the tile it was rendering.
+    if received != tiles.len() {
+        fail!("rendering failed: {} of {} tiles were rendered", received, tiles.len());
+    }
+    film
 }
 
 #[cfg(test)]
 mod tests {
 
-    extern crate image;
-
-    use image::GenericImage;
+    use std::rand::{Rng, XorShiftRng};
+    use std::sync::Arc;
+    use light::Light;
     use render;
+    use render::{PixelRenderer, RenderSettings};
+    use filter::{BoxFilter, GaussianFilter};
+    use tonemap::LinearClamp;
 
     use test_helpers::{make_test_scene, make_test_camera};
 
+    /// Whether `light` rounds to black on 8 bits.
+    fn black(light: Light) -> bool {
+        light.max_component() < 0.5 / 255.0
+    }
+
     #[test]
     fn test_pixel() {
         let scene = make_test_scene();
         let camera = make_test_camera();
-        let black = image::Rgb(0, 0, 0);
-        assert!(black == render::pixel(&camera, &scene, 1, 1));
-        assert!(black == render::pixel(&camera, &scene, 1000, 1));
-        assert!(black == render::pixel(&camera, &scene, 1, 1000));
-        assert!(black == render::pixel(&camera, &scene, 1000, 1000));
-        assert!(black != render::pixel(&camera, &scene, 500, 500));
-        assert!(black == render::pixel(&camera, &scene, 500, 124));
-        assert!(black != render::pixel(&camera, &scene, 500, 125));
-        assert!(black == render::pixel(&camera, &scene, 500, 1000-124));
-        assert!(black != render::pixel(&camera, &scene, 500, 1000-125));
+        let pixel = |x, y| render::pixel(&camera, &scene, x, y, 4, 16, &BoxFilter, &mut XorShiftRng::new_unseeded());
+        assert!(black(pixel(1, 1)));
+        assert!(black(pixel(999, 1)));
+        assert!(black(pixel(1, 999)));
+        assert!(black(pixel(999, 999)));
+        assert!(!black(pixel(500, 500)));
+        // The sphere covers the film rows from 125 to 875.
+        assert!(black(pixel(500, 124)));
+        assert!(!black(pixel(500, 125)));
+        assert!(black(pixel(500, 1000-125)));
+        assert!(!black(pixel(500, 1000-126)));
+    }
+
+    #[test]
+    fn test_pixel_filter() {
+        let scene = make_test_scene();
+        let camera = make_test_camera();
+        // Wide filters reach samples from the neighbouring pixels.
+        let box_pixel = render::pixel(&camera, &scene, 500, 124, 4, 64, &BoxFilter, &mut XorShiftRng::new_unseeded());
+        let gaussian_pixel = render::pixel(&camera, &scene, 500, 124, 4, 64, &GaussianFilter(1.5, 2.0), &mut XorShiftRng::new_unseeded());
+        assert!(black(box_pixel));
+        assert!(!black(gaussian_pixel));
+    }
+
+    struct CoordinatesRenderer;
+
+    impl PixelRenderer for CoordinatesRenderer {
+        fn render(&self, x: u32, y: u32, _: &mut XorShiftRng) -> Light {
+            Light::new(x as f32, y as f32, 0.0)
+        }
+    }
+
+    struct NoiseRenderer;
+
+    impl PixelRenderer for NoiseRenderer {
+        fn render(&self, _: u32, _: u32, rng: &mut XorShiftRng) -> Light {
+            Light::new(rng.gen(), rng.gen(), rng.gen())
+        }
+    }
+
+    fn settings(threads: uint) -> RenderSettings {
+        RenderSettings {width: 100, height: 70, bounces: 1, samples: 1, threads: threads, tile_size: 16, seed: 42,
+                        filter: BoxFilter, tone_map: LinearClamp, exposure: 0.0}
     }
 
     #[test]
     fn test_image() {
-        let (width, height) = (100, 100);
-        let mut count = 0i;
-        let mut imbuf: image::ImageBuf<image::Rgb<u8>>;
-        { // We need a scope here because we are borrowing `count`.
-            let renderer = |_, _| {count += 1; image::Rgb(0u8, 0, 0)};
-            imbuf = render::image(width, height, renderer);
-        } // Now we can use `count`.
-        assert!(count == 100 * 100);
-        assert!(imbuf.get_pixel(34, 21) == image::Rgb(0, 0, 0));
-    }
-}
\ No newline at end of file
+        let film = render::image(&settings(3), Arc::new(CoordinatesRenderer));
+        assert!(film.width == 100 && film.height == 70);
+        for y in range(0, 70) {
+            for x in range(0, 100) {
+                assert!(film.get(x, y) == Light::new(x as f32, y as f32, 0.0));
+            }
+        }
+    }
+
+    #[test]
+    fn test_image_does_not_depend_on_thread_count() {
+        let single = render::image(&settings(1), Arc::new(NoiseRenderer));
+        let multi = render::image(&settings(4), Arc::new(NoiseRenderer));
+        for y in range(0, 70) {
+            for x in range(0, 100) {
+                assert!(single.get(x, y) == multi.get(x, y));
+            }
+        }
+    }
+}
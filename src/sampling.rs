@@ -0,0 +1,172 @@
+use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+use std::f32::consts::PI;
+
+/// Orthonormal basis around a unit normal, which is the local z axis.
+pub struct Frame {
+    pub s: Vector3<f32>,
+    pub t: Vector3<f32>,
+    pub n: Vector3<f32>
+}
+
+impl Frame {
+    /// Builds a basis without branches on the normal direction, following
+    /// Duff et al., "Building an Orthonormal Basis, Revisited" (2017).
+    pub fn from_normal(n: Vector3<f32>) -> Frame {
+        let sign = if n.z >= 0.0 { 1.0f32 } else { -1.0 };
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        Frame {
+            s: Vector3::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+            t: Vector3::new(b, sign + n.y * n.y * a, -n.y),
+            n: n
+        }
+    }
+
+    /// Builds a basis with `s` along the part of `tangent` orthogonal to
+    /// `n`, or any basis if there is none.
+    pub fn from_normal_tangent(n: Vector3<f32>, tangent: Vector3<f32>) -> Frame {
+        let s = tangent.sub_v(&n.mul_s(dot(n, tangent)));
+        if s.length2() < 1e-12 {
+            return Frame::from_normal(n);
+        }
+        let s = s.normalize();
+        Frame {s: s, t: n.cross(&s), n: n}
+    }
+
+    pub fn to_world(&self, v: Vector3<f32>) -> Vector3<f32> {
+        self.s.mul_s(v.x).add_v(&self.t.mul_s(v.y)).add_v(&self.n.mul_s(v.z))
+    }
+
+    pub fn to_local(&self, v: Vector3<f32>) -> Vector3<f32> {
+        Vector3::new(dot(v, self.s), dot(v, self.t), dot(v, self.n))
+    }
+}
+
+/// Maps a point of the unit square to the unit disk, preserving areas.
+///
+/// This is Shirley's concentric mapping, which distorts the square less than
+/// the polar one.
+pub fn concentric_disk(u: f32, v: f32) -> (f32, f32) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+/// Maps a point of the unit square to the hemisphere around +z, with a
+/// density proportional to the cosine with the z axis: `cos(theta) / PI`.
+///
+/// Points uniform on the disk are projected up to the hemisphere (Malley's
+/// method).
+pub fn cosine_hemisphere(u: f32, v: f32) -> Vector3<f32> {
+    let (x, y) = concentric_disk(u, v);
+    let z = (1.0 - x * x - y * y).max(0.0).sqrt();
+    Vector3::new(x, y, z)
+}
+
+/// Maps a point of the unit square to the hemisphere around +z, with a
+/// uniform density `1 / (2 PI)`.
+pub fn uniform_hemisphere(u: f32, v: f32) -> Vector3<f32> {
+    let z = u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Index of the first entry of the non-decreasing `cdf` which is at least
+/// `x`, or of the last one if there is none. Drawing `x` uniformly up to
+/// the last entry picks each index with a probability proportional to its
+/// increment.
+pub fn search_cdf(cdf: &[f32], x: f32) -> uint {
+    let (mut lo, mut hi) = (0u, cdf.len() - 1);
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        if cdf[mid] < x { lo = mid + 1; } else { hi = mid; }
+    }
+    lo
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+    use std::rand::{Rng, XorShiftRng};
+
+    use sampling::{Frame, concentric_disk, cosine_hemisphere, uniform_hemisphere, search_cdf};
+
+    #[test]
+    fn test_concentric_disk() {
+        assert!(concentric_disk(0.5, 0.5) == (0.0, 0.0));
+        for i in range(0u, 11) {
+            for j in range(0u, 11) {
+                let (x, y) = concentric_disk(i as f32 / 10.0, j as f32 / 10.0);
+                assert!(x * x + y * y <= 1.0 + 0.00001);
+            }
+        }
+        let (x, y) = concentric_disk(1.0, 0.5);
+        assert!((x - 1.0).abs() < 0.00001 && y.abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_cosine_hemisphere() {
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 100000u;
+        let mut mean_cos = 0.0;
+        for _ in range(0, n) {
+            let v = cosine_hemisphere(rng.gen(), rng.gen());
+            assert!((v.length() - 1.0).abs() < 0.0001 && v.z >= 0.0);
+            mean_cos += v.z / n as f32;
+        }
+        // The mean of cos(theta) under the density cos(theta) / PI is 2/3.
+        assert!((mean_cos - 2.0 / 3.0).abs() < 0.005);
+    }
+
+    #[test]
+    fn test_uniform_hemisphere() {
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 100000u;
+        let mut mean_cos = 0.0;
+        for _ in range(0, n) {
+            let v = uniform_hemisphere(rng.gen(), rng.gen());
+            assert!((v.length() - 1.0).abs() < 0.0001 && v.z >= 0.0);
+            mean_cos += v.z / n as f32;
+        }
+        assert!((mean_cos - 0.5).abs() < 0.005);
+    }
+
+    #[test]
+    fn test_frame() {
+        for n in [Vector3::new(0.0f32, 0.0, 1.0), Vector3::new(0.0, 0.0, -1.0),
+                  Vector3::new(1.0, 2.0, -3.0).normalize(), Vector3::new(-0.3, 0.1, 0.2).normalize()].iter() {
+            let frame = Frame::from_normal(*n);
+            assert!((frame.s.length() - 1.0).abs() < 0.0001 && (frame.t.length() - 1.0).abs() < 0.0001);
+            assert!(dot(frame.s, frame.t).abs() < 0.0001 && dot(frame.s, *n).abs() < 0.0001 && dot(frame.t, *n).abs() < 0.0001);
+            let v = Vector3::new(0.2f32, -0.5, 0.7);
+            let back = frame.to_local(frame.to_world(v));
+            assert!((back.x - v.x).abs() < 0.0001 && (back.y - v.y).abs() < 0.0001 && (back.z - v.z).abs() < 0.0001);
+            assert!((frame.to_world(Vector3::new(0.0, 0.0, 1.0)).sub_v(n)).length() < 0.0001);
+        }
+        let n = Vector3::new(0.0f32, 1.0, 0.0);
+        let frame = Frame::from_normal_tangent(n, Vector3::new(2.0, 1.0, 0.0));
+        assert!(frame.s == Vector3::new(1.0, 0.0, 0.0) && frame.t == Vector3::new(0.0, 0.0, -1.0));
+        // A tangent along the normal gives no direction.
+        let fallback = Frame::from_normal_tangent(n, n);
+        assert!(dot(fallback.s, n).abs() < 0.0001 && (fallback.s.length() - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn test_search_cdf() {
+        let cdf = [0.25f32, 0.25, 0.75, 1.0];
+        assert!(search_cdf(cdf.as_slice(), 0.0) == 0);
+        assert!(search_cdf(cdf.as_slice(), 0.3) == 2);
+        assert!(search_cdf(cdf.as_slice(), 0.75) == 2);
+        assert!(search_cdf(cdf.as_slice(), 0.9) == 3);
+        assert!(search_cdf(cdf.as_slice(), 2.0) == 3);
+    }
+}
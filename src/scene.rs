@@ -1,44 +1,142 @@
 use cgmath::vector::{EuclideanVector, Vector3};
 use cgmath::ray::Ray3;
-use cgmath::point::{Point, Point3};
+use cgmath::point::Point3;
+use std::cmp;
+use std::f32;
+use std::rand::{Rng, XorShiftRng};
 
-use shape::Shape;
+use bvh::Bvh;
+use environment::{Environment, ConstantBackground};
 use object::Object;
 use light::{Light, LightSource};
-use utils::cmp_float;
+use shape::Hit;
 
 pub struct Scene {
-    pub objects: Vec<Object>,
-    pub light_sources: Vec<Box<LightSource>>
+    objects: Vec<Object>,
+    pub light_sources: Vec<Box<LightSource + Send + Sync>>,
+    // Indices of the objects in `bvh` and of the unbounded ones.
+    bounded: Vec<uint>,
+    unbounded: Vec<uint>,
+    bvh: Bvh,
+    /// Indices of the emissive objects, sampled as lights.
+    emitters: Vec<uint>,
+    background: Box<Environment + Send + Sync>
 }
 
-type IntersectionInfo<'r> = (&'r Object, Point3<f32>);
+type IntersectionInfo<'r> = (&'r Object, Hit);
 
 impl Scene {
-    pub fn background(&self, _direction: Vector3<f32>) -> Light {
-        Light::zero()
+    pub fn new(objects: Vec<Object>, light_sources: Vec<Box<LightSource + Send + Sync>>) -> Scene {
+        let mut scene = Scene {
+            objects: objects,
+            light_sources: light_sources,
+            bounded: vec![],
+            unbounded: vec![],
+            bvh: Bvh::new(&[]),
+            emitters: vec![],
+            background: box ConstantBackground::new(Light::zero())
+        };
+        scene.build_bvh();
+        scene
+    }
+
+    pub fn objects(&self) -> &[Object] {
+        self.objects.as_slice()
+    }
+
+    pub fn add_objects(&mut self, objects: Vec<Object>) {
+        for object in objects.into_iter() {
+            self.objects.push(object);
+        }
+        self.build_bvh();
+    }
+
+    fn build_bvh(&mut self) {
+        let mut bounds = vec![];
+        self.bounded.clear();
+        self.unbounded.clear();
+        for (i, obj) in self.objects.iter().enumerate() {
+            match obj.bounds() {
+                Some(bb) => {
+                    self.bounded.push(i);
+                    bounds.push(bb);
+                },
+                None => self.unbounded.push(i)
+            }
+        }
+        self.bvh = Bvh::new(bounds.as_slice());
+        let emitters = range(0, self.objects.len()).filter(|&i| self.objects[i].emits()).collect();
+        self.emitters = emitters;
     }
 
+    /// Picks an emissive object uniformly and samples a point of it seen
+    /// from `origin`. Returns the object, the point and the density of its
+    /// direction from `origin` per unit solid angle.
+    pub fn sample_emitter(&self, origin: Point3<f32>, rng: &mut XorShiftRng) -> Option<(&Object, Point3<f32>, f32)> {
+        if self.emitters.is_empty() {
+            return None;
+        }
+        let n = self.emitters.len();
+        let object = &self.objects[self.emitters[cmp::min((rng.gen::<f32>() * n as f32) as uint, n - 1)]];
+        object.shape.sample(origin, rng.gen(), rng.gen()).map(|(point, pdf)| (object, point, pdf / n as f32))
+    }
+
+    /// Density of `sample_emitter` returning `point` on `object`.
+    pub fn emitter_pdf(&self, object: &Object, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        if object.emits() {
+            object.shape.pdf(origin, point) / self.emitters.len() as f32
+        } else {
+            0.0
+        }
+    }
+
+    pub fn set_background(&mut self, background: Box<Environment + Send + Sync>) {
+        self.background = background;
+    }
+
+    /// Light from the environment, for rays leaving the scene in `direction`.
+    pub fn background(&self, direction: Vector3<f32>) -> Light {
+        self.background.radiance(direction)
+    }
+
+    /// Samples a direction of the environment, with its density per unit
+    /// solid angle.
+    pub fn sample_background(&self, rng: &mut XorShiftRng) -> Option<(Vector3<f32>, f32)> {
+        self.background.sample(rng.gen(), rng.gen())
+    }
+
+    /// Density of `sample_background` returning `direction`.
+    pub fn background_pdf(&self, direction: Vector3<f32>) -> f32 {
+        self.background.pdf(direction)
+    }
+
+    /// Finds the nearest object hit by the ray. Rays leaving a surface
+    /// should start from `Hit::spawn_origin` to not find it again.
     pub fn intersect(&self, ray: Ray3<f32>) -> Option<IntersectionInfo> {
-        let mut intersections: Vec<IntersectionInfo> = self.objects.iter()
-            .map(|obj| obj.intersect(ray))
-            .filter(|opt| opt.is_some())
-            .map(|opt| opt.unwrap())
-            .collect();
-        let distance_cmp = |v1: &IntersectionInfo, v2: &IntersectionInfo| {
-            cmp_float(
-                ray.origin.sub_p(v2.ref1()).length(),
-                ray.origin.sub_p(v1.ref1()).length()
-            )
-        };
-        intersections.sort_by(distance_cmp);
-        intersections.pop()
+        let mut nearest: Option<IntersectionInfo> = None;
+        let mut t_max = f32::INFINITY;
+        for &i in self.unbounded.iter() {
+            let object = &self.objects[i];
+            match object.intersect(ray, 0.0, t_max) {
+                Some(hit) => {
+                    t_max = hit.t;
+                    nearest = Some((object, hit));
+                },
+                None => ()
+            }
+        }
+        let bounded = self.bvh.nearest(&ray, t_max, |i| {
+            let object = &self.objects[self.bounded[i]];
+            object.intersect(ray, 0.0, t_max).map(|hit| ((object, hit), hit.t))
+        });
+        bounded.map(|(info, _)| info).or(nearest)
     }
 
+    /// Whether anything lies on the ray within `length` of its origin.
     pub fn shadow_intersect(&self, ray: Ray3<f32>, length: f32) -> bool {
-        self.objects.iter().any(|obj| {
-            obj.shadow_intersect(ray, length)
-        })
+        let t_max = length / ray.direction.length();
+        self.unbounded.iter().any(|&i| self.objects[i].shadow_intersect(ray, 0.0, t_max)) ||
+            self.bvh.any(&ray, t_max, |i| self.objects[self.bounded[i]].shadow_intersect(ray, 0.0, t_max))
     }
 }
 
@@ -55,15 +153,51 @@ mod tests {
     use material::TestMaterial;
 
     fn get_point(scene: &Scene, ray: Ray3<f32>) -> Point3<f32> {
-        scene.intersect(ray).unwrap().val1()
+        scene.intersect(ray).unwrap().val1().point
     }
 
     #[test]
     fn test_scene_intersect() {
         let p1 = Object {shape: box Plane::from_abcd(0.0f32, 0.0, -1.0, 1.0), material: box TestMaterial};
         let p2 = Object {shape: box Plane::from_abcd(0.0f32, 0.0, -1.0, 2.0), material: box TestMaterial};
-        let scene = Scene {objects: vec![p1, p2], light_sources: vec![]};
+        let scene = Scene::new(vec![p1, p2], vec![]);
         let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 1.0));
         assert!(Point3::new(0.0, 0.0, 1.0) == get_point(&scene, ray));
+        let (_, hit) = scene.intersect(ray).unwrap();
+        assert!(hit.t == 1.0 && hit.front_face);
+    }
+
+    #[test]
+    fn test_scene_intersect_mixes_bounded_and_unbounded() {
+        let plane = Object {shape: box Plane::from_abcd(0.0f32, 0.0, -1.0, 3.0), material: box TestMaterial};
+        let near = Object {shape: box Sphere {center: Point3::new(0.0f32, 0.0, 2.0), radius: 0.5}, material: box TestMaterial};
+        let far = Object {shape: box Sphere {center: Point3::new(0.0f32, 0.0, 5.0), radius: 0.5}, material: box TestMaterial};
+        let scene = Scene::new(vec![plane, far, near], vec![]);
+        let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 1.0));
+        assert!((get_point(&scene, ray).z - 1.5).abs() < 0.0001);
+        let ray_aside = Ray::new(Point3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!((get_point(&scene, ray_aside).z - 3.0).abs() < 0.0001);
+        assert!(scene.shadow_intersect(ray, 1.6));
+        assert!(!scene.shadow_intersect(ray, 1.4));
+        assert!(!scene.shadow_intersect(ray_aside, 2.9));
+    }
+
+    #[test]
+    fn test_thin_shell_at_any_scale() {
+        for &scale in [1e-6f32, 1.0, 1e6].iter() {
+            // A shell a thousandth of its size thick.
+            let center = Point3::new(0.0, 0.0, 4.0 * scale);
+            let outer = Object {shape: box Sphere {center: center, radius: scale}, material: box TestMaterial};
+            let inner = Object {shape: box Sphere {center: center, radius: 0.999 * scale}, material: box TestMaterial};
+            let scene = Scene::new(vec![outer, inner], vec![]);
+            let ray = Ray::new(Point::origin(), Vector3::new(0.1, 0.0, 1.0).normalize());
+            let (_, hit) = scene.intersect(ray).unwrap();
+            assert!((hit.point.sub_p(&center).length() - scale).abs() < 1e-4 * scale);
+            // Going on through the surface, the next one is the inner side.
+            let (_, next) = scene.intersect(hit.spawn_ray(ray.direction)).unwrap();
+            assert!((next.point.sub_p(&center).length() - 0.999 * scale).abs() < 1e-4 * scale);
+            // Nothing is in the way of light reaching the outer side.
+            assert!(!scene.shadow_intersect(hit.spawn_ray(-ray.direction), 3.0 * scale));
+        }
     }
 }
\ No newline at end of file
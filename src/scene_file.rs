@@ -0,0 +1,854 @@
+//! Scene description files.
+//!
+//! Scenes are written in a subset of TOML: `[table]` and `[[array]]`
+//! headers followed by `key = value` lines, where a value is a number, a
+//! string, a boolean or a one-line array. For instance:
+//!
+//! ```toml
+//! [render]
+//! width = 800
+//! height = 600
+//! samples = 16
+//! filter = "mitchell"
+//! tonemap = "aces"
+//! exposure = 0.5
+//!
+//! [background]
+//! type = "image"
+//! file = "sky.hdr"
+//! rotation = 90.0
+//!
+//! [camera]
+//! type = "perspective"
+//! eye = [0.0, 0.0, -2.0]
+//! target = [0.0, 0.0, 5.0]
+//! up = [0.0, 1.0, 0.0]
+//! fov = 60.0
+//!
+//! [[object]]
+//! shape = "sphere"
+//! center = [0.0, 0.0, 5.0]
+//! radius = 1.0
+//! material = "refractive"
+//! color = [1.0, 1.0, 1.0]
+//! index = 1.4
+//! absorption = [0.0, 0.2, 0.4]
+//!
+//! [[texture]]
+//! name = "earth"
+//! type = "image"
+//! file = "earth.png"
+//! wrap = "repeat"
+//!
+//! [[texture]]
+//! name = "floor"
+//! type = "checker"
+//! even = [0.9, 0.9, 0.9]
+//! odd = [0.2, 0.2, 0.2]
+//! size = 0.5
+//!
+//! [[object]]
+//! shape = "sphere"
+//! center = [-2.0, 0.0, 6.0]
+//! radius = 1.0
+//! material = "global_diffuse"
+//! color = "earth"
+//!
+//! [[object]]
+//! shape = "sphere"
+//! center = [2.0, 0.0, 6.0]
+//! radius = 1.0
+//! material = "rough_conductor"
+//! color = [1.0, 1.0, 1.0]
+//! metal = "gold"
+//! roughness = 0.2
+//!
+//! [[mesh]]
+//! file = "teapot.obj"
+//!
+//! [[light]]
+//! position = [2.0, -1.0, 5.0]
+//! color = [2.0, 2.0, 2.0]
+//!
+//! [[light]]
+//! type = "rectangle"
+//! corner = [-0.5, -2.9, 4.5]
+//! edge1 = [1.0, 0.0, 0.0]
+//! edge2 = [0.0, 0.0, 1.0]
+//! color = [10.0, 10.0, 10.0]
+//! ```
+//!
+//! The `tonemap` (`linear`, `srgb`, the default, `reinhard`,
+//! `reinhard_extended` with a `white` point, 4 by default, `aces` or
+//! `hable`) and the `exposure` in stops only apply to 8 bit output images.
+//! `white` is an error with the other tone maps.
+//!
+//! Object shapes are a `sphere` (`center`, `radius`), an infinite `plane`
+//! (`abcd`, the coefficients of `ax + by + cz + d = 0`), an axis-aligned
+//! `box` (`min`, `max`), a `disk` (`center`, `normal`, `radius`), a `quad`
+//! (`corner`, `edge1`, `edge2`) or a `cylinder` closed at both ends (`base`,
+//! `top`, `radius`). Shapes without area, such as a quad with parallel
+//! edges, are errors.
+//!
+//! Textures are either an `image` (with optional `wrap` and `interpolation`),
+//! a `checker` of `even` and `odd` colors, or `noise`, `marble` or `wood`
+//! blending from a `low` to a `high` color at some `scale`. Diffuse,
+//! emitter and reflective materials accept a texture name as `color`.
+//!
+//! The background is either `constant`, of some `color`, an `image`: an
+//! equirectangular `.hdr` or `.pfm` environment map, turned by `rotation`
+//! degrees around the y axis, or a daylight `sky` with the `sun` in the given
+//! direction, some `turbidity` (from 2 to 10, 3 by default) and a `ground`
+//! albedo. The sky has the y axis up.
+//!
+//! Lights are a `point` by default, with an intensity as `color`, or a
+//! `rectangle` (`corner`, `edge1`, `edge2`), `disk` (`center`, `normal`,
+//! `radius`) or `sphere` (`center`, `radius`) with a radiance as `color`.
+//! Area lights emit on the side of `edge1 x edge2` or `normal`, and cannot be
+//! seen by the camera: pair them with an emitter object to make them visible.
+//! Area lights without area, such as a sphere of radius 0, are errors.
+
+use cgmath::point::Point3;
+use cgmath::vector::Vector3;
+use std::collections::HashMap;
+use std::io::File;
+use std::sync::Arc;
+
+use camera::{Camera, OriginCamera, PerspectiveCamera, ThinLensCamera};
+use environment::{Environment, ConstantBackground, EnvironmentMap};
+use filter::Filter;
+use light::{Light, LightSource, PointLight, RectangleLight, DiskLight, SphereLight};
+use material::{Material, DiffuseMaterial, EmitterMaterial, GlobalDiffuseMaterial, ReflectiveMaterial, RefractiveMaterial};
+use microfacet::{ComplexIor, RoughConductorMaterial, RoughDielectricMaterial};
+use object::Object;
+use obj;
+use render::RenderSettings;
+use scene::Scene;
+use shape::{Shape, Aabb, Cylinder, Disk, Plane, Quad, Sphere};
+use sky::Sky;
+use texture::{Texture, ImageTexture, Wrap, Interpolation};
+use texture::{CheckerTexture, NoiseTexture, MarbleTexture, WoodTexture};
+use tonemap::{ToneMap, ReinhardExtended};
+
+/// Everything needed to render an image.
+pub struct SceneDescription {
+    pub scene: Scene,
+    pub camera: Box<Camera + Send + Sync>,
+    pub settings: RenderSettings
+}
+
+/// Loads a scene file. Errors are reported as `file:line: message`.
+///
+/// `adjust` is applied to the render settings read from the file before the
+/// camera is set up, so that the caller can override them.
+pub fn load(path: &Path, adjust: |&mut RenderSettings|) -> Result<SceneDescription, String> {
+    let src = try!(File::open(path).read_to_string().map_err(|e| format!("{}: {}", path.display(), e)));
+    build(src.as_slice(), &path.dir_path(), adjust).map_err(|(line, msg)| {
+        format!("{}:{}: {}", path.display(), line, msg)
+    })
+}
+
+/// A line number and a message.
+type Error = (uint, String);
+
+fn build(src: &str, base_dir: &Path, adjust: |&mut RenderSettings|) -> Result<SceneDescription, Error> {
+    let tables = try!(parse(src));
+    // Textures can be used before the table defining them.
+    let mut textures = HashMap::new();
+    for table in tables.iter().filter(|table| table.name.as_slice() == "texture") {
+        let name = try!(table.string("name"));
+        if textures.contains_key(&name) {
+            return Err((table.line_of("name"), format!("texture `{}` is already defined", name)));
+        }
+        textures.insert(name, Arc::new(try!(make_texture(table, base_dir))));
+    }
+    let mut settings = RenderSettings::new();
+    let mut camera_table = None;
+    let mut background = None;
+    let mut objects = vec![];
+    let mut light_sources = vec![];
+    let mut meshes = vec![];
+    // Line of the white point, checked against the tone map once the caller
+    // had a chance to change it.
+    let mut white_line = None;
+    for table in tables.iter() {
+        match table.name.as_slice() {
+            "" => match table.entries.iter().next() {
+                Some((key, entry)) => return Err((entry.line, format!("`{}` is not in a table", key))),
+                None => ()
+            },
+            "render" => {
+                settings.width = try!(table.positive_or("width", settings.width as uint)) as u32;
+                settings.height = try!(table.positive_or("height", settings.height as uint)) as u32;
+                settings.bounces = try!(table.uint_or("bounces", settings.bounces));
+                settings.samples = try!(table.positive_or("samples", settings.samples));
+                settings.threads = try!(table.positive_or("threads", settings.threads));
+                settings.tile_size = try!(table.positive_or("tile_size", settings.tile_size as uint)) as u32;
+                settings.seed = try!(table.uint_or("seed", settings.seed as uint)) as u32;
+                if table.has("filter") {
+                    let name = try!(table.string("filter"));
+                    settings.filter = match Filter::from_name(name.as_slice()) {
+                        Some(filter) => filter,
+                        None => return Err((table.line_of("filter"), format!("unknown filter `{}`", name)))
+                    };
+                }
+                if table.has("white") {
+                    if try!(table.f32("white")) <= 0.0 {
+                        return Err((table.line_of("white"), "`white` should be greater than 0".to_string()));
+                    }
+                    white_line = Some(table.line_of("white"));
+                }
+                if table.has("tonemap") {
+                    let name = try!(table.string("tonemap"));
+                    settings.tone_map = match ToneMap::from_name(name.as_slice()) {
+                        Some(ReinhardExtended(white)) => ReinhardExtended(try!(table.f32_or("white", white))),
+                        Some(tone_map) => tone_map,
+                        None => return Err((table.line_of("tonemap"), format!("unknown tone map `{}`", name)))
+                    };
+                }
+                settings.exposure = try!(table.f32_or("exposure", settings.exposure));
+            },
+            "camera" => camera_table = Some(table),
+            "background" => background = Some(try!(make_background(table, base_dir))),
+            "texture" => (),
+            "object" => objects.push(Object {
+                shape: try!(make_shape(table)),
+                material: try!(make_material(table, &textures))
+            }),
+            "mesh" => meshes.push(table),
+            "light" => light_sources.push(try!(make_light(table))),
+            name => return Err((table.line, format!("unknown table `{}`", name)))
+        }
+    }
+    adjust(&mut settings);
+    match (settings.tone_map, white_line) {
+        (ReinhardExtended(_), _) | (_, None) => (),
+        (_, Some(line)) => return Err((line, "`white` only applies to the reinhard_extended tone map".to_string()))
+    }
+    let camera = match camera_table {
+        None => box OriginCamera {aperture: 1.0, width: settings.width, height: settings.height} as Box<Camera + Send + Sync>,
+        Some(table) => try!(make_camera(table, &settings))
+    };
+    let mut scene = Scene::new(objects, light_sources);
+    match background {
+        Some(background) => scene.set_background(background),
+        None => ()
+    }
+    for table in meshes.iter() {
+        let file = try!(table.string("file"));
+        try!(obj::load(&base_dir.join(file.as_slice()), &mut scene).map_err(|e| (table.line, e)));
+    }
+    Ok(SceneDescription {scene: scene, camera: camera, settings: settings})
+}
+
+fn make_background(table: &Table, base_dir: &Path) -> Result<Box<Environment + Send + Sync>, Error> {
+    let kind = try!(table.string("type"));
+    match kind.as_slice() {
+        "constant" => {
+            let (r, g, b) = try!(table.vec3("color"));
+            Ok(box ConstantBackground::new(Light::new(r, g, b)) as Box<Environment + Send + Sync>)
+        },
+        "image" => {
+            let file = try!(table.string("file"));
+            let map = try!(EnvironmentMap::load(&base_dir.join(file.as_slice())).map_err(|e| (table.line_of("file"), e)));
+            Ok(box map.with_rotation(try!(table.f32_or("rotation", 0.0))) as Box<Environment + Send + Sync>)
+        },
+        "sky" => {
+            let (x, y, z) = try!(table.vec3("sun"));
+            let turbidity = try!(table.f32_or("turbidity", 3.0));
+            let (r, g, b) = if table.has("ground") { try!(table.vec3("ground")) } else { (0.3, 0.3, 0.3) };
+            Sky::new(Vector3::new(x, y, z), turbidity, Light::new(r, g, b))
+                .map(|sky| box sky as Box<Environment + Send + Sync>).map_err(|msg| (table.line_of("type"), msg))
+        },
+        other => Err((table.line_of("type"), format!("unknown background type `{}`", other)))
+    }
+}
+
+fn make_light(table: &Table) -> Result<Box<LightSource + Send + Sync>, Error> {
+    let kind = if table.has("type") { try!(table.string("type")) } else { "point".to_string() };
+    let (r, g, b) = try!(table.vec3("color"));
+    let color = Light::new(r, g, b);
+    let point = |key: &str| table.vec3(key).map(|(x, y, z)| Point3::new(x, y, z));
+    let vector = |key: &str| table.vec3(key).map(|(x, y, z)| Vector3::new(x, y, z));
+    let degenerate = |msg: String| (table.line_of("type"), msg);
+    match kind.as_slice() {
+        "point" => Ok(box PointLight::new(try!(point("position")), color) as Box<LightSource + Send + Sync>),
+        "rectangle" => RectangleLight::new(try!(point("corner")), try!(vector("edge1")), try!(vector("edge2")), color)
+                           .map(|light| box light as Box<LightSource + Send + Sync>).map_err(degenerate),
+        "disk" => DiskLight::new(try!(point("center")), try!(vector("normal")), try!(table.f32("radius")), color)
+                      .map(|light| box light as Box<LightSource + Send + Sync>).map_err(degenerate),
+        "sphere" => SphereLight::new(try!(point("center")), try!(table.f32("radius")), color)
+                        .map(|light| box light as Box<LightSource + Send + Sync>).map_err(degenerate),
+        other => Err((table.line_of("type"), format!("unknown light type `{}`", other)))
+    }
+}
+
+fn make_camera(table: &Table, settings: &RenderSettings) -> Result<Box<Camera + Send + Sync>, Error> {
+    let kind = try!(table.string("type"));
+    match kind.as_slice() {
+        "origin" => Ok(box OriginCamera {
+            aperture: try!(table.f32("aperture")),
+            width: settings.width,
+            height: settings.height
+        } as Box<Camera + Send + Sync>),
+        "perspective" => Ok(box try!(make_perspective(table, settings)) as Box<Camera + Send + Sync>),
+        "thin_lens" => {
+            let pinhole = try!(make_perspective(table, settings));
+            let lens_radius = try!(table.f32("lens_radius"));
+            let focal_distance = try!(table.f32("focal_distance"));
+            Ok(box ThinLensCamera::new(pinhole, lens_radius, focal_distance) as Box<Camera + Send + Sync>)
+        },
+        other => Err((table.line_of("type"), format!("unknown camera type `{}`", other)))
+    }
+}
+
+fn make_perspective(table: &Table, settings: &RenderSettings) -> Result<PerspectiveCamera, Error> {
+    let (ex, ey, ez) = try!(table.vec3("eye"));
+    let (tx, ty, tz) = try!(table.vec3("target"));
+    let (ux, uy, uz) = try!(table.vec3("up"));
+    let fov = try!(table.f32("fov"));
+    let aspect = if table.entries.contains_key(&"aspect".to_string()) {
+        try!(table.f32("aspect"))
+    } else {
+        settings.width as f32 / settings.height as f32
+    };
+    PerspectiveCamera::new(
+        Point3::new(ex, ey, ez), Point3::new(tx, ty, tz), Vector3::new(ux, uy, uz),
+        fov, aspect, settings.width, settings.height
+    ).map_err(|msg| (table.line_of("type"), msg))
+}
+
+fn make_shape(table: &Table) -> Result<Box<Shape + Send + Sync>, Error> {
+    let kind = try!(table.string("shape"));
+    let point = |key: &str| table.vec3(key).map(|(x, y, z)| Point3::new(x, y, z));
+    let vector = |key: &str| table.vec3(key).map(|(x, y, z)| Vector3::new(x, y, z));
+    let degenerate = |msg: String| (table.line_of("shape"), msg);
+    match kind.as_slice() {
+        "sphere" => {
+            let (x, y, z) = try!(table.vec3("center"));
+            let radius = try!(table.f32("radius"));
+            if !(radius > 0.0) {
+                return Err(degenerate("the radius of a sphere must be positive".to_string()));
+            }
+            Ok(box Sphere {center: Point3::new(x, y, z), radius: radius} as Box<Shape + Send + Sync>)
+        },
+        "plane" => {
+            let abcd = try!(table.floats("abcd", 4));
+            Ok(box Plane::from_abcd(abcd[0], abcd[1], abcd[2], abcd[3]) as Box<Shape + Send + Sync>)
+        },
+        "box" => Aabb::new(try!(point("min")), try!(point("max")))
+                     .map(|aabb| box aabb as Box<Shape + Send + Sync>).map_err(degenerate),
+        "disk" => Disk::new(try!(point("center")), try!(vector("normal")), try!(table.f32("radius")))
+                      .map(|disk| box disk as Box<Shape + Send + Sync>).map_err(degenerate),
+        "quad" => Quad::new(try!(point("corner")), try!(vector("edge1")), try!(vector("edge2")))
+                      .map(|quad| box quad as Box<Shape + Send + Sync>).map_err(degenerate),
+        "cylinder" => Cylinder::new(try!(point("base")), try!(point("top")), try!(table.f32("radius")))
+                          .map(|cylinder| box cylinder as Box<Shape + Send + Sync>).map_err(degenerate),
+        other => Err((table.line_of("shape"), format!("unknown shape `{}`", other)))
+    }
+}
+
+/// Textures by name, shared by the materials using them.
+type Textures = HashMap<String, Arc<Box<Texture + Send + Sync>>>;
+
+fn make_texture(table: &Table, base_dir: &Path) -> Result<Box<Texture + Send + Sync>, Error> {
+    let kind = try!(table.string("type"));
+    match kind.as_slice() {
+        "image" => {
+            let file = try!(table.string("file"));
+            let mut texture = try!(ImageTexture::load(&base_dir.join(file.as_slice())).map_err(|e| (table.line_of("file"), e)));
+            if table.has("wrap") {
+                let name = try!(table.string("wrap"));
+                texture.wrap = match Wrap::from_name(name.as_slice()) {
+                    Some(wrap) => wrap,
+                    None => return Err((table.line_of("wrap"), format!("unknown wrap mode `{}`", name)))
+                };
+            }
+            if table.has("interpolation") {
+                let name = try!(table.string("interpolation"));
+                texture.interpolation = match Interpolation::from_name(name.as_slice()) {
+                    Some(interpolation) => interpolation,
+                    None => return Err((table.line_of("interpolation"), format!("unknown interpolation `{}`", name)))
+                };
+            }
+            Ok(box texture as Box<Texture + Send + Sync>)
+        },
+        "checker" => {
+            let (er, eg, eb) = try!(table.vec3("even"));
+            let (or, og, ob) = try!(table.vec3("odd"));
+            let size = try!(table.f32_or("size", 1.0));
+            Ok(box CheckerTexture::new(Light::new(er, eg, eb), Light::new(or, og, ob), size) as Box<Texture + Send + Sync>)
+        },
+        "noise" | "marble" | "wood" => {
+            let (lr, lg, lb) = try!(table.vec3("low"));
+            let (hr, hg, hb) = try!(table.vec3("high"));
+            let (low, high) = (Light::new(lr, lg, lb), Light::new(hr, hg, hb));
+            let scale = try!(table.f32_or("scale", 1.0));
+            Ok(match kind.as_slice() {
+                "noise" => box NoiseTexture::new(low, high, scale).with_octaves(try!(table.uint_or("octaves", 1))) as Box<Texture + Send + Sync>,
+                "marble" => box MarbleTexture::new(low, high, scale).with_turbulence(try!(table.f32_or("turbulence", 5.0))) as Box<Texture + Send + Sync>,
+                _ => box WoodTexture::new(low, high, scale).with_grain(try!(table.f32_or("grain", 0.5))) as Box<Texture + Send + Sync>
+            })
+        },
+        other => Err((table.line_of("type"), format!("unknown texture type `{}`", other)))
+    }
+}
+
+/// Reads `color`, which is either an RGB array or the name of a texture.
+fn color_texture(table: &Table, textures: &Textures) -> Result<Box<Texture + Send + Sync>, Error> {
+    let entry = try!(table.get("color"));
+    match entry.value {
+        Str(ref name) => match textures.find(name) {
+            Some(texture) => Ok(box texture.clone() as Box<Texture + Send + Sync>),
+            None => Err((entry.line, format!("unknown texture `{}`", name)))
+        },
+        _ => {
+            let (r, g, b) = try!(table.vec3("color"));
+            Ok(box Light::new(r, g, b) as Box<Texture + Send + Sync>)
+        }
+    }
+}
+
+fn make_material(table: &Table, textures: &Textures) -> Result<Box<Material + Send + Sync>, Error> {
+    let kind = try!(table.string("material"));
+    // Only some materials can be textured.
+    match kind.as_slice() {
+        "diffuse" => return Ok(box DiffuseMaterial::textured(try!(color_texture(table, textures))) as Box<Material + Send + Sync>),
+        "global_diffuse" => return Ok(box GlobalDiffuseMaterial::textured(try!(color_texture(table, textures))) as Box<Material + Send + Sync>),
+        "emitter" => return Ok(box EmitterMaterial::textured(try!(color_texture(table, textures))) as Box<Material + Send + Sync>),
+        "reflective" => return Ok(box ReflectiveMaterial::textured(try!(color_texture(table, textures))) as Box<Material + Send + Sync>),
+        _ => ()
+    }
+    let (r, g, b) = try!(table.vec3("color"));
+    match kind.as_slice() {
+        "refractive" => {
+            let index = try!(table.f32("index"));
+            let mut material = RefractiveMaterial::new(r, g, b, index);
+            if table.has("absorption") {
+                let (ar, ag, ab) = try!(table.vec3("absorption"));
+                material = material.with_absorption(ar, ag, ab);
+            }
+            Ok(box material as Box<Material + Send + Sync>)
+        },
+        "rough_conductor" => {
+            let ior = if table.has("metal") {
+                let metal = try!(table.string("metal"));
+                match ComplexIor::from_name(metal.as_slice()) {
+                    Some(ior) => ior,
+                    None => return Err((table.line_of("metal"), format!("unknown metal `{}`", metal)))
+                }
+            } else {
+                let (er, eg, eb) = try!(table.vec3("eta"));
+                let (kr, kg, kb) = try!(table.vec3("k"));
+                ComplexIor {eta: Light::new(er, eg, eb), k: Light::new(kr, kg, kb)}
+            };
+            let roughness = try!(table.f32("roughness"));
+            Ok(box RoughConductorMaterial::new(r, g, b, ior, roughness) as Box<Material + Send + Sync>)
+        },
+        "rough_dielectric" => {
+            let index = try!(table.f32("index"));
+            let roughness = try!(table.f32("roughness"));
+            Ok(box RoughDielectricMaterial::new(r, g, b, index, roughness) as Box<Material + Send + Sync>)
+        },
+        other => Err((table.line_of("material"), format!("unknown material type `{}`", other)))
+    }
+}
+
+#[deriving(Clone, PartialEq, Show)]
+enum Value {
+    Number(f64),
+    Str(String),
+    Boolean(bool),
+    Array(Vec<Value>)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match *value {
+        Number(_) => "a number",
+        Str(_) => "a string",
+        Boolean(_) => "a boolean",
+        Array(_) => "an array"
+    }
+}
+
+struct Entry {
+    value: Value,
+    line: uint
+}
+
+struct Table {
+    name: String,
+    line: uint,
+    entries: HashMap<String, Entry>
+}
+
+impl Table {
+    fn header(&self) -> String {
+        if self.name.is_empty() { "the top level".to_string() } else { format!("[{}]", self.name) }
+    }
+
+    fn line_of(&self, key: &str) -> uint {
+        self.entries.find(&key.to_string()).map_or(self.line, |e| e.line)
+    }
+
+    fn get(&self, key: &str) -> Result<&Entry, Error> {
+        match self.entries.find(&key.to_string()) {
+            Some(entry) => Ok(entry),
+            None => Err((self.line, format!("missing field `{}` in {}", key, self.header())))
+        }
+    }
+
+    fn mismatch(&self, key: &str, entry: &Entry, expected: &str) -> Error {
+        (entry.line, format!("`{}` should be {}, not {}", key, expected, type_name(&entry.value)))
+    }
+
+    fn string(&self, key: &str) -> Result<String, Error> {
+        let entry = try!(self.get(key));
+        match entry.value {
+            Str(ref s) => Ok(s.clone()),
+            _ => Err(self.mismatch(key, entry, "a string"))
+        }
+    }
+
+    fn f32(&self, key: &str) -> Result<f32, Error> {
+        let entry = try!(self.get(key));
+        match entry.value {
+            Number(x) => Ok(x as f32),
+            _ => Err(self.mismatch(key, entry, "a number"))
+        }
+    }
+
+    fn uint(&self, key: &str) -> Result<uint, Error> {
+        let entry = try!(self.get(key));
+        match entry.value {
+            Number(x) if x >= 0.0 && x.fract() == 0.0 => Ok(x as uint),
+            _ => Err(self.mismatch(key, entry, "a non-negative integer"))
+        }
+    }
+
+    fn has(&self, key: &str) -> bool {
+        self.entries.contains_key(&key.to_string())
+    }
+
+    fn uint_or(&self, key: &str, default: uint) -> Result<uint, Error> {
+        if self.has(key) { self.uint(key) } else { Ok(default) }
+    }
+
+    /// Like `uint_or`, for sizes and counts which cannot be 0.
+    fn positive_or(&self, key: &str, default: uint) -> Result<uint, Error> {
+        match try!(self.uint_or(key, default)) {
+            0 => Err((self.line_of(key), format!("`{}` should be a positive integer, not 0", key))),
+            n => Ok(n)
+        }
+    }
+
+    fn f32_or(&self, key: &str, default: f32) -> Result<f32, Error> {
+        if self.has(key) { self.f32(key) } else { Ok(default) }
+    }
+
+    fn floats(&self, key: &str, n: uint) -> Result<Vec<f32>, Error> {
+        let entry = try!(self.get(key));
+        let expected = format!("an array of {} numbers", n);
+        match entry.value {
+            Array(ref values) if values.len() == n => {
+                let mut floats = Vec::with_capacity(n);
+                for v in values.iter() {
+                    match *v {
+                        Number(x) => floats.push(x as f32),
+                        _ => return Err(self.mismatch(key, entry, expected.as_slice()))
+                    }
+                }
+                Ok(floats)
+            },
+            _ => Err(self.mismatch(key, entry, expected.as_slice()))
+        }
+    }
+
+    fn vec3(&self, key: &str) -> Result<(f32, f32, f32), Error> {
+        let v = try!(self.floats(key, 3));
+        Ok((v[0], v[1], v[2]))
+    }
+}
+
+/// Splits the source into tables. The first table has an empty name and
+/// holds the keys appearing before any header.
+fn parse(src: &str) -> Result<Vec<Table>, Error> {
+    let mut tables = vec![Table {name: String::new(), line: 1, entries: HashMap::new()}];
+    for (i, raw_line) in src.lines().enumerate() {
+        let line_number = i + 1;
+        let mut parser = Parser {chars: raw_line.chars().collect(), pos: 0, line: line_number};
+        parser.skip_whitespace();
+        if parser.at_end() { continue; }
+        if parser.eat('[') {
+            let is_array = parser.eat('[');
+            let name = try!(parser.key());
+            if !parser.eat(']') || (is_array && !parser.eat(']')) {
+                return Err(parser.error("malformed table header"));
+            }
+            try!(parser.expect_end());
+            if !is_array && tables.iter().any(|t| t.name == name) {
+                return Err((line_number, format!("duplicate table [{}]", name)));
+            }
+            tables.push(Table {name: name, line: line_number, entries: HashMap::new()});
+        } else {
+            let key = try!(parser.key());
+            if !parser.eat('=') {
+                return Err(parser.error("expected `=` after the key"));
+            }
+            let value = try!(parser.value());
+            try!(parser.expect_end());
+            let table = tables.last_mut().unwrap();
+            if table.entries.contains_key(&key) {
+                return Err((line_number, format!("duplicate field `{}`", key)));
+            }
+            table.entries.insert(key, Entry {value: value, line: line_number});
+        }
+    }
+    Ok(tables)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: uint,
+    line: uint
+}
+
+impl Parser {
+    fn error(&self, msg: &str) -> Error {
+        (self.line, msg.to_string())
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.chars.len() || self.chars[self.pos] == '#'
+    }
+
+    fn peek(&self) -> Option<char> {
+        if self.at_end() { None } else { Some(self.chars[self.pos]) }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `c` and the whitespace after it if `c` comes next.
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            self.skip_whitespace();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_end(&mut self) -> Result<(), Error> {
+        self.skip_whitespace();
+        if self.at_end() { Ok(()) } else { Err(self.error("unexpected characters at the end of the line")) }
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        let mut key = String::new();
+        loop {
+            match self.peek() {
+                Some(c) if c.is_alphanumeric() || c == '_' || c == '-' || c == '.' => {
+                    key.push(c);
+                    self.pos += 1;
+                },
+                _ => break
+            }
+        }
+        self.skip_whitespace();
+        if key.is_empty() { Err(self.error("expected a key")) } else { Ok(key) }
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        let value = match self.peek() {
+            None => return Err(self.error("expected a value")),
+            Some('"') => try!(self.string()),
+            Some('[') => {
+                self.eat('[');
+                let mut values = vec![];
+                while !self.eat(']') {
+                    values.push(try!(self.value()));
+                    if !self.eat(',') && self.peek() != Some(']') {
+                        return Err(self.error("expected `,` or `]` in array"));
+                    }
+                }
+                Array(values)
+            },
+            Some(_) => {
+                let start = self.pos;
+                while self.pos < self.chars.len() && !self.chars[self.pos].is_whitespace() &&
+                        self.chars[self.pos] != ',' && self.chars[self.pos] != ']' && self.chars[self.pos] != '#' {
+                    self.pos += 1;
+                }
+                let word = String::from_chars(self.chars.slice(start, self.pos));
+                match word.as_slice() {
+                    "true" => Boolean(true),
+                    "false" => Boolean(false),
+                    w => match from_str::<f64>(w) {
+                        Some(x) => Number(x),
+                        None => return Err((self.line, format!("invalid value `{}`", w)))
+                    }
+                }
+            }
+        };
+        self.skip_whitespace();
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<Value, Error> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            if self.pos >= self.chars.len() {
+                return Err(self.error("unterminated string"));
+            }
+            let c = self.chars[self.pos];
+            self.pos += 1;
+            match c {
+                '"' => break,
+                '\\' if self.pos < self.chars.len() => {
+                    let escaped = self.chars[self.pos];
+                    self.pos += 1;
+                    s.push(match escaped { 'n' => '\n', 't' => '\t', other => other });
+                },
+                c => s.push(c)
+            }
+        }
+        Ok(Str(s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use scene_file::{build, parse, Number, Str, Boolean, Array};
+    use tonemap::ReinhardExtended;
+
+    #[test]
+    fn test_parse_values() {
+        let src = "a = 1.5\nb = \"x # y\" # comment\n\n[t]\nc = [1, [2, -3e1]]\nd = true\n[[u]]\n[[u]]\n";
+        let tables = parse(src).unwrap();
+        assert!(tables.len() == 4);
+        assert!(tables[0].entries.find(&"a".to_string()).unwrap().value == Number(1.5));
+        assert!(tables[0].entries.find(&"b".to_string()).unwrap().value == Str("x # y".to_string()));
+        assert!(tables[1].name == "t".to_string());
+        let c = &tables[1].entries.find(&"c".to_string()).unwrap();
+        assert!(c.line == 5);
+        assert!(c.value == Array(vec![Number(1.0), Array(vec![Number(2.0), Number(-30.0)])]));
+        assert!(tables[1].entries.find(&"d".to_string()).unwrap().value == Boolean(true));
+        assert!(tables[3].line == 8);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("a = 1\nb 2\n").err().unwrap().val0() == 2);
+        assert!(parse("[t]\n[t]\n").err().unwrap().val0() == 2);
+        assert!(parse("a = [1, 2\n").is_err());
+        assert!(parse("a = \"open\n").is_err());
+    }
+
+    fn build_error(src: &str) -> (uint, String) {
+        match build(src, &Path::new("."), |_| ()) {
+            Ok(_) => fail!("expected an error"),
+            Err(e) => e
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        let src = "[render]\nwidth = 40\nheight = 10\ntonemap = \"reinhard_extended\"\nwhite = 8\nexposure = -1\n\
+                   [camera]\ntype = \"origin\"\naperture = 2\n\
+                   [[object]]\nshape = \"plane\"\nabcd = [0, 0, -1, 3]\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n\
+                   [[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nradius = 0.5\n\
+                   material = \"global_diffuse\"\ncolor = [0.5, 0.5, 0.5]\n\
+                   [[light]]\nposition = [0, 1, 0]\ncolor = [1, 1, 1]\n";
+        let desc = match build(src, &Path::new("."), |s| s.width = 20) {
+            Ok(desc) => desc,
+            Err((line, msg)) => fail!("line {}: {}", line, msg)
+        };
+        assert!(desc.settings.width == 20 && desc.settings.height == 10);
+        assert!(desc.settings.tone_map == ReinhardExtended(8.0) && desc.settings.exposure == -1.0);
+        assert!(desc.scene.objects().len() == 2);
+        assert!(desc.scene.light_sources.len() == 1);
+        // The tone map given by the caller decides whether `white` applies.
+        let aces = "[render]\ntonemap = \"aces\"\nwhite = 8\n";
+        assert!(build(aces, &Path::new("."), |s| s.tone_map = ReinhardExtended(4.0)).is_ok());
+        let sky = "[background]\ntype = \"sky\"\nsun = [1, 2, 0]\nturbidity = 4\n";
+        assert!(build(sky, &Path::new("."), |_| ()).is_ok());
+        let disk = "[[light]]\ntype = \"disk\"\ncenter = [0, -1, 2]\nnormal = [0, 1, 0]\nradius = 0.5\ncolor = [1, 1, 1]\n";
+        assert!(build(disk, &Path::new("."), |_| ()).is_ok());
+        let shapes = "[[object]]\nshape = \"box\"\nmin = [-1, -1, 3]\nmax = [1, 0, 4]\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n\
+                      [[object]]\nshape = \"disk\"\ncenter = [0, 2, 3]\nnormal = [0, -1, 0]\nradius = 1\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n\
+                      [[object]]\nshape = \"quad\"\ncorner = [-1, -1, 5]\nedge1 = [2, 0, 0]\nedge2 = [0, 2, 0]\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n\
+                      [[object]]\nshape = \"cylinder\"\nbase = [2, -1, 3]\ntop = [2, 1, 3]\nradius = 0.5\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n";
+        match build(shapes, &Path::new("."), |_| ()) {
+            Ok(desc) => assert!(desc.scene.objects().len() == 4),
+            Err((line, msg)) => fail!("line {}: {}", line, msg)
+        }
+    }
+
+    #[test]
+    fn test_build_errors_have_line_numbers() {
+        let unknown = build_error("[[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nradius = 1\nmaterial = \"chrome\"\ncolor = [1, 1, 1]\n");
+        assert!(unknown == (5, "unknown material type `chrome`".to_string()));
+        let missing = build_error("\n[[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n");
+        assert!(missing == (2, "missing field `radius` in [object]".to_string()));
+        let mistyped = build_error("[render]\nwidth = \"wide\"\n");
+        assert!(mistyped.val0() == 2);
+        let tiles = build_error("[render]\nwidth = 8\ntile_size = 0\n");
+        assert!(tiles == (3, "`tile_size` should be a positive integer, not 0".to_string()));
+        assert!(build_error("[render]\nheight = 0\n").val0() == 2);
+        assert!(build_error("[render]\nsamples = 0\n") == (2, "`samples` should be a positive integer, not 0".to_string()));
+        let metal = build_error("[[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nradius = 1\nmaterial = \"rough_conductor\"\n\
+                                 color = [1, 1, 1]\nmetal = \"brass\"\nroughness = 0.1\n");
+        assert!(metal == (7, "unknown metal `brass`".to_string()));
+        let filter = build_error("[render]\nsamples = 4\nfilter = \"lanczos\"\n");
+        assert!(filter == (3, "unknown filter `lanczos`".to_string()));
+        let tone_map = build_error("[render]\ntonemap = \"filmic\"\n");
+        assert!(tone_map == (2, "unknown tone map `filmic`".to_string()));
+        let white = build_error("[render]\ntonemap = \"aces\"\nwhite = 8\n");
+        assert!(white == (3, "`white` only applies to the reinhard_extended tone map".to_string()));
+        let black = build_error("[render]\ntonemap = \"reinhard_extended\"\nwhite = 0\n");
+        assert!(black == (3, "`white` should be greater than 0".to_string()));
+        let hazy = build_error("[background]\ntype = \"sky\"\nsun = [1, 2, 0]\nturbidity = 20\n");
+        assert!(hazy == (2, "the turbidity of the sky must be between 2 and 10".to_string()));
+        let background = build_error("[background]\ntype = \"image\"\nfile = \"no_such_file.hdr\"\n");
+        assert!(background.val0() == 3);
+        let light = build_error("[[light]]\ntype = \"spot\"\ncolor = [1, 1, 1]\n");
+        assert!(light == (2, "unknown light type `spot`".to_string()));
+        let sliver = build_error("[[light]]\ntype = \"rectangle\"\ncorner = [0, 0, 2]\nedge1 = [1, 0, 0]\nedge2 = [2, 0, 0]\n\
+                                  color = [1, 1, 1]\n");
+        assert!(sliver == (2, "the edges of a rectangle light cannot be parallel".to_string()));
+        let bulb = build_error("[[light]]\ntype = \"sphere\"\ncenter = [0, 0, 2]\nradius = 0\ncolor = [1, 1, 1]\n");
+        assert!(bulb == (2, "the radius of a sphere light must be positive".to_string()));
+        let flat = build_error("[[object]]\nshape = \"cylinder\"\nbase = [0, 0, 2]\ntop = [0, 0, 2]\nradius = 1\n\
+                                material = \"emitter\"\ncolor = [1, 1, 1]\n");
+        assert!(flat == (2, "the base and the top of a cylinder must differ".to_string()));
+        let tilted = build_error("[camera]\ntype = \"perspective\"\neye = [0, 0, 0]\ntarget = [0, 2, 0]\nup = [0, 1, 0]\nfov = 60\n");
+        assert!(tilted == (2, "the up direction of a camera cannot be parallel to the viewing direction".to_string()));
+        let speck = build_error("[[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nradius = 0\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n");
+        assert!(speck == (2, "the radius of a sphere must be positive".to_string()));
+        let slab = build_error("[[object]]\nshape = \"box\"\nmin = [0, 0, 2]\nmax = [1, 1, 2]\nmaterial = \"emitter\"\ncolor = [1, 1, 1]\n");
+        assert!(slab == (2, "the corners of a box must differ on every axis".to_string()));
+    }
+
+    #[test]
+    fn test_texture_errors() {
+        let unknown = build_error("[[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nradius = 1\nmaterial = \"diffuse\"\ncolor = \"wood\"\n");
+        assert!(unknown == (6, "unknown texture `wood`".to_string()));
+        let missing = build_error("[[texture]]\nname = \"wood\"\ntype = \"image\"\nfile = \"no_such_file.png\"\n");
+        assert!(missing.val0() == 4);
+        let kind = build_error("[[texture]]\nname = \"wood\"\ntype = \"video\"\n");
+        assert!(kind == (3, "unknown texture type `video`".to_string()));
+        let marble = build_error("[[texture]]\nname = \"stone\"\ntype = \"marble\"\nlow = [0, 0, 0]\n");
+        assert!(marble == (1, "missing field `high` in [texture]".to_string()));
+        // Textures only replace plain colors in materials which support them.
+        let untextured = build_error("[[object]]\nshape = \"sphere\"\ncenter = [0, 0, 2]\nradius = 1\nmaterial = \"refractive\"\n\
+                                      color = \"wood\"\nindex = 1.5\n");
+        assert!(untextured.val0() == 6);
+    }
+}
@@ -1,58 +1,839 @@
 use cgmath::point::{Point, Point3};
 use cgmath::ray::{Ray, Ray3};
-use cgmath::intersect::Intersect;
-use cgmath::vector::{EuclideanVector, Vector, Vector3};
-use std::num::zero;
+use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+use std::f32;
+use std::f32::consts::PI;
+use std::mem;
+
+use bvh::BoundingBox;
+use sampling::{Frame, concentric_disk, cosine_hemisphere, uniform_hemisphere};
 
 pub use cgmath::sphere::Sphere;
 pub use cgmath::plane::Plane;
 
+/// Point of the surface of a shape, with what materials need to know
+/// about the surface there.
+pub struct SurfacePoint {
+    pub point: Point3<f32>,
+    pub normal: Vector3<f32>,
+    /// Texture coordinates.
+    pub uv: (f32, f32)
+}
+
+/// Where a ray meets a shape.
+pub struct Hit {
+    /// Ray parameter of the hit: it is at `origin + t * direction`.
+    pub t: f32,
+    pub point: Point3<f32>,
+    /// Bound on the distance between `point` and the actual surface, due
+    /// to floating point rounding.
+    pub error: f32,
+    /// Normal of the actual surface, pointing outwards.
+    pub geometric_normal: Vector3<f32>,
+    /// Normal to shade with, which differs from the geometric one on
+    /// smooth meshes.
+    pub shading_normal: Vector3<f32>,
+    /// Texture coordinates.
+    pub uv: (f32, f32),
+    /// Tangent frame around the shading normal, with `s` along the
+    /// direction of increasing u where the shape defines it.
+    pub frame: Frame,
+    /// Whether the ray comes from the side the geometric normal points to.
+    pub front_face: bool,
+    /// Index of the primitive hit within the shape, such as a triangle of
+    /// a mesh, or 0 for shapes made of a single one.
+    pub primitive: uint
+}
+
+impl Hit {
+    /// Completes a hit at `t` along `ray` with its frame, built around the
+    /// shading normal and `tangent`, and the side the ray comes from.
+    pub fn new(ray: &Ray3<f32>, t: f32, geometric_normal: Vector3<f32>, shading_normal: Vector3<f32>,
+               uv: (f32, f32), tangent: Vector3<f32>, primitive: uint) -> Hit {
+        let point = ray.origin.add_v(&ray.direction.mul_s(t));
+        Hit {
+            t: t,
+            point: point,
+            // Rounding of the three products and sums, with t taken as
+            // exact: shapes computing their points otherwise replace it.
+            error: gamma(3) * (max_abs(ray.origin) + max_abs(point)),
+            geometric_normal: geometric_normal,
+            shading_normal: shading_normal,
+            uv: uv,
+            frame: Frame::from_normal_tangent(shading_normal, tangent),
+            front_face: dot(geometric_normal, ray.direction) < 0.0,
+            primitive: primitive
+        }
+    }
+
+    /// Replaces the point of the hit, computed more precisely by the shape,
+    /// and its error bound.
+    pub fn with_point(self, point: Point3<f32>, error: f32) -> Hit {
+        Hit {point: point, error: error, ..self}
+    }
+
+    pub fn surface(&self) -> SurfacePoint {
+        SurfacePoint {point: self.point, normal: self.shading_normal, uv: self.uv}
+    }
+
+    /// Origin of rays leaving the surface in `direction`: the point pushed
+    /// along the geometric normal, to the side of `direction`, beyond its
+    /// rounding error. Such rays cannot find the surface they start from,
+    /// whatever the scale of the scene.
+    pub fn spawn_origin(&self, direction: Vector3<f32>) -> Point3<f32> {
+        let n = self.geometric_normal;
+        let distance = if dot(n, direction) < 0.0 { -self.error } else { self.error };
+        let offset = n.mul_s(distance);
+        let p = self.point.add_v(&offset);
+        // Rounding the sum could bring the point back towards the surface.
+        Point3::new(away_from(p.x, offset.x), away_from(p.y, offset.y), away_from(p.z, offset.z))
+    }
+
+    pub fn spawn_ray(&self, direction: Vector3<f32>) -> Ray3<f32> {
+        Ray::new(self.spawn_origin(direction), direction)
+    }
+}
+
+/// Bound on the relative error of `n` successive floating point operations,
+/// from Higham, "Accuracy and Stability of Numerical Algorithms".
+pub fn gamma(n: uint) -> f32 {
+    let e = n as f32 * f32::EPSILON * 0.5;
+    e / (1.0 - e)
+}
+
+/// Largest absolute value of the coordinates of `p`.
+pub fn max_abs(p: Point3<f32>) -> f32 {
+    p.x.abs().max(p.y.abs()).max(p.z.abs())
+}
+
+/// Moves `x` to the next float in the direction of `offset`.
+fn away_from(x: f32, offset: f32) -> f32 {
+    if offset > 0.0 {
+        next_float_up(x)
+    } else if offset < 0.0 {
+        -next_float_up(-x)
+    } else {
+        x
+    }
+}
+
+fn next_float_up(x: f32) -> f32 {
+    if x == f32::INFINITY {
+        return x;
+    }
+    // Both zeros go to the smallest positive float.
+    let x = if x == 0.0 { 0.0 } else { x };
+    let bits: u32 = unsafe { mem::transmute(x) };
+    let next = if x >= 0.0 { bits + 1 } else { bits - 1 };
+    unsafe { mem::transmute(next) }
+}
+
 pub trait Shape {
-    fn intersect(&self, Ray3<f32>) -> Option<Point3<f32>>;
+    /// Finds the nearest hit of the ray with a parameter t in
+    /// `(t_min, t_max)`.
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit>;
+    /// Shading normal at a point of the surface.
     fn normal(&self, Point3<f32>) -> Vector3<f32>;
-    fn shadow_intersect(&self, ray: Ray3<f32>, length: f32) -> bool {
-        match self.intersect(ray) {
-            None => false,
-            Some(p) => p.sub_p(&ray.origin).length() < length
-        }
+    /// Texture coordinates of a point of the surface.
+    fn uv(&self, Point3<f32>) -> (f32, f32);
+    /// Returns `None` for unbounded shapes.
+    fn bounds(&self) -> Option<BoundingBox>;
+    /// Samples a point of the surface seen from `origin`, from two uniform
+    /// numbers. Returns it with the density of its direction from `origin`,
+    /// per unit solid angle, or `None` if no point can be sampled.
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)>;
+    /// Density of `sample` returning `point`, per unit solid angle.
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32;
+    /// Whether the ray hits the shape at all in `(t_min, t_max)`.
+    fn shadow_intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> bool {
+        self.intersect(ray, t_min, t_max).is_some()
     }
 }
 
-macro_rules! cgmath_intersect(
-    () => (
-        fn intersect(&self, ray: Ray3<f32>) -> Option<Point3<f32>> {
-            let moved_ray = Ray::new(ray.origin.add_v(&ray.direction.mul_s(0.0001)), ray.direction);
-            (*self, moved_ray).intersection()
-        }
-    )
-)
+/// Converts a density per unit area at `point`, where the surface has the
+/// normal `normal`, to a density per unit solid angle seen from `origin`.
+pub fn solid_angle_pdf(pdf_area: f32, origin: Point3<f32>, point: Point3<f32>, normal: Vector3<f32>) -> f32 {
+    let to_point = point.sub_p(&origin);
+    let d2 = to_point.length2();
+    let cos = dot(normal, to_point).abs() / d2.sqrt();
+    if cos > 0.0 { pdf_area * d2 / cos } else { 0.0 }
+}
 
 impl Shape for Sphere<f32> {
 
-    fn intersect(&self, ray: Ray3<f32>) -> Option<Point3<f32>> {
-        let moved_ray = Ray::new(ray.origin.add_v(&ray.direction.mul_s(0.0001)), ray.direction);
-        let l = self.center.sub_p(&moved_ray.origin);
-        let tca = l.dot(&moved_ray.direction);
-        if tca < zero() && l.length2() > self.radius*self.radius { return None; }
-        let d2 = l.dot(&l) - tca*tca;
-        if d2 > self.radius*self.radius { return None; }
-        let thc = (self.radius*self.radius - d2).sqrt();
-        let k = if tca > thc { tca - thc } else { tca + thc };
-        Some(moved_ray.origin.add_v(&moved_ray.direction.mul_s(k)))
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        let oc = ray.origin.sub_p(&self.center);
+        let a = ray.direction.length2();
+        let b = 2.0 * oc.dot(&ray.direction);
+        let c = oc.length2() - self.radius * self.radius;
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 || a == 0.0 { return None; }
+        // Avoids the cancellation of -b + sqrt(discriminant) when b > 0.
+        let q = -0.5 * (b + if b < 0.0 { -discriminant.sqrt() } else { discriminant.sqrt() });
+        if q == 0.0 { return None; }
+        let (t0, t1) = (q / a, c / q);
+        let (near, far) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+        let t = if near > t_min && near < t_max {
+            near
+        } else if far > t_min && far < t_max {
+            far
+        } else {
+            return None;
+        };
+        // Projected back on the sphere, the point only suffers from the
+        // rounding of its own coordinates.
+        let from_center = ray.origin.add_v(&ray.direction.mul_s(t)).sub_p(&self.center);
+        let normal = from_center.normalize();
+        let point = self.center.add_v(&normal.mul_s(self.radius));
+        let error = gamma(7) * (self.radius + max_abs(point));
+        // Around the y axis, as u goes.
+        let tangent = Vector3::new(-normal.z, 0.0, normal.x);
+        Some(Hit::new(&ray, t, normal, normal, self.uv(point), tangent, 0).with_point(point, error))
     }
 
     fn normal(&self, point: Point3<f32>) -> Vector3<f32> {
         point.sub_p(&self.center).normalize()
     }
+
+    /// Longitude and latitude: u turns around the y axis and v goes from
+    /// the +y pole (0) to the -y one (1).
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        let d = point.sub_p(&self.center).normalize();
+        let u = (d.z.atan2(d.x) + PI) / (2.0 * PI);
+        let v = d.y.max(-1.0).min(1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        Some(BoundingBox {min: self.center.add_v(&-r), max: self.center.add_v(&r)})
+    }
+
+    /// Uniform on the half of the sphere facing `origin`, which holds every
+    /// point visible from there. Nothing is sampled from inside.
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        let towards = origin.sub_p(&self.center);
+        if towards.length2() <= self.radius * self.radius {
+            return None;
+        }
+        let normal = Frame::from_normal(towards.normalize()).to_world(uniform_hemisphere(u, v));
+        let point = self.center.add_v(&normal.mul_s(self.radius));
+        Some((point, solid_angle_pdf(1.0 / (2.0 * PI * self.radius * self.radius), origin, point, normal)))
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        let towards = origin.sub_p(&self.center);
+        let normal = point.sub_p(&self.center).normalize();
+        if towards.length2() <= self.radius * self.radius || dot(normal, towards) <= 0.0 {
+            return 0.0;
+        }
+        solid_angle_pdf(1.0 / (2.0 * PI * self.radius * self.radius), origin, point, normal)
+    }
 }
 
 impl Shape for Plane<f32> {
 
-    cgmath_intersect!()
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        // The plane holds the points p where n.p + d = 0.
+        let denominator = self.n.dot(&ray.direction);
+        if denominator == 0.0 { return None; }
+        let t = -(self.d + self.n.dot(&ray.origin.sub_p(&Point::origin()))) / denominator;
+        if !(t > t_min && t < t_max) { return None; }
+        let point = ray.origin.add_v(&ray.direction.mul_s(t));
+        // The distance of the point to the plane is known from its
+        // equation, up to the rounding of its evaluation.
+        let p = point.sub_p(&Point::origin());
+        let residual = self.n.dot(&p) + self.d;
+        let magnitude = (self.n.x * p.x).abs() + (self.n.y * p.y).abs() + (self.n.z * p.z).abs() + self.d.abs();
+        let error = (residual.abs() + gamma(4) * magnitude) / self.n.length() + gamma(1) * max_abs(point);
+        let normal = self.n.normalize();
+        // The frame of the texture coordinates.
+        let tangent = Frame::from_normal(normal).s;
+        Some(Hit::new(&ray, t, normal, normal, self.uv(point), tangent, 0).with_point(point, error))
+    }
 
     fn normal(&self, _: Point3<f32>) -> Vector3<f32> {
         self.n.normalize()
     }
+
+    /// Coordinates in an orthonormal basis of the plane, so that textures
+    /// repeat every unit of length.
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        let frame = Frame::from_normal(self.n.normalize());
+        let p = point.sub_p(&Point::origin());
+        (p.dot(&frame.s), p.dot(&frame.t))
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        None
+    }
+
+    /// A plane has no finite area to sample uniformly, but covers a whole
+    /// hemisphere of directions: they are sampled with a density
+    /// proportional to their cosine with the normal.
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        towards_plane(self, origin).and_then(|towards| {
+            let local = cosine_hemisphere(u, v);
+            let dir = Frame::from_normal(towards).to_world(local);
+            self.intersect(Ray::new(origin, dir), 0.0, f32::INFINITY).map(|hit| (hit.point, local.z / PI))
+        })
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        match towards_plane(self, origin) {
+            None => 0.0,
+            Some(towards) => dot(point.sub_p(&origin).normalize(), towards).max(0.0) / PI
+        }
+    }
+}
+
+/// Normal of the plane pointing to it from `origin`, or `None` when
+/// `origin` lies on the plane.
+fn towards_plane(plane: &Plane<f32>, origin: Point3<f32>) -> Option<Vector3<f32>> {
+    let n = plane.n.normalize();
+    if plane.intersect(Ray::new(origin, n), 0.0, f32::INFINITY).is_some() {
+        Some(n)
+    } else if plane.intersect(Ray::new(origin, -n), 0.0, f32::INFINITY).is_some() {
+        Some(-n)
+    } else {
+        None
+    }
+}
+
+/// Box with its faces orthogonal to the axes.
+///
+/// Its faces are the primitives of its hits, numbered `2 * axis + side`
+/// where `side` is 1 at `max`: -x, +x, -y, +y, -z and +z.
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>
+}
+
+impl Aabb {
+    /// The box between two opposite corners, given in any order. Fails if
+    /// the corners do not differ on every axis.
+    pub fn new(a: Point3<f32>, b: Point3<f32>) -> Result<Aabb, String> {
+        let min = Point3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+        let max = Point3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+        if !(min.x < max.x && min.y < max.y && min.z < max.z) {
+            return Err("the corners of a box must differ on every axis".to_string());
+        }
+        Ok(Aabb {min: min, max: max})
+    }
+
+    fn size(&self, axis: uint) -> f32 {
+        coordinate(self.max, axis) - coordinate(self.min, axis)
+    }
+
+    fn face_area(&self, face: uint) -> f32 {
+        let axis = face / 2;
+        self.size((axis + 1) % 3) * self.size((axis + 2) % 3)
+    }
+
+    /// Face nearest to `point`.
+    fn face(&self, point: Point3<f32>) -> uint {
+        let mut nearest = 0;
+        let mut distance = f32::INFINITY;
+        for face in range(0u, 6) {
+            let side = if face % 2 == 1 { self.max } else { self.min };
+            let d = (coordinate(point, face / 2) - coordinate(side, face / 2)).abs();
+            if d < distance {
+                nearest = face;
+                distance = d;
+            }
+        }
+        nearest
+    }
+
+    /// Whether `origin` is on the outer side of `face`, and sees it.
+    fn faces_towards(&self, face: uint, origin: Point3<f32>) -> bool {
+        let axis = face / 2;
+        if face % 2 == 1 {
+            coordinate(origin, axis) > coordinate(self.max, axis)
+        } else {
+            coordinate(origin, axis) < coordinate(self.min, axis)
+        }
+    }
+
+    fn visible_area(&self, origin: Point3<f32>) -> f32 {
+        range(0u, 6).filter(|&face| self.faces_towards(face, origin)).fold(0.0, |area, face| area + self.face_area(face))
+    }
+
+    /// The coordinates along the two other axes, in their cyclic order,
+    /// from 0 to 1 across the face.
+    fn face_uv(&self, face: uint, point: Point3<f32>) -> (f32, f32) {
+        let (b, c) = ((face / 2 + 1) % 3, (face / 2 + 2) % 3);
+        ((coordinate(point, b) - coordinate(self.min, b)) / self.size(b),
+         (coordinate(point, c) - coordinate(self.min, c)) / self.size(c))
+    }
+}
+
+fn face_normal(face: uint) -> Vector3<f32> {
+    axis_vector(face / 2, if face % 2 == 1 { 1.0 } else { -1.0 })
+}
+
+impl Shape for Aabb {
+
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        // The ray is in the box from its last entry into the slab between
+        // two opposite faces to its first exit from one.
+        let (mut near, mut far) = (f32::NEG_INFINITY, f32::INFINITY);
+        let (mut near_face, mut far_face) = (0u, 0u);
+        for axis in range(0u, 3) {
+            let o = coordinate(ray.origin, axis);
+            let d = component(ray.direction, axis);
+            let (low, high) = (coordinate(self.min, axis), coordinate(self.max, axis));
+            if d == 0.0 {
+                if o < low || o > high { return None; }
+                continue;
+            }
+            let (t_low, t_high) = ((low - o) / d, (high - o) / d);
+            // Rays going up enter through the low face.
+            let (entry, exit, up) = if d > 0.0 { (t_low, t_high, 1) } else { (t_high, t_low, 0) };
+            if entry > near {
+                near = entry;
+                near_face = 2 * axis + 1 - up;
+            }
+            if exit < far {
+                far = exit;
+                far_face = 2 * axis + up;
+            }
+        }
+        if near > far { return None; }
+        let (t, face) = if near > t_min && near < t_max {
+            (near, near_face)
+        } else if far > t_min && far < t_max {
+            (far, far_face)
+        } else {
+            return None;
+        };
+        // Snapped to its face, the point is exactly on the surface, but
+        // for the rounding of its other coordinates near the edges.
+        let axis = face / 2;
+        let side = if face % 2 == 1 { self.max } else { self.min };
+        let point = with_coordinate(ray.origin.add_v(&ray.direction.mul_s(t)), axis, coordinate(side, axis));
+        let error = gamma(1) * max_abs(point);
+        let normal = face_normal(face);
+        let tangent = axis_vector((axis + 1) % 3, 1.0);
+        Some(Hit::new(&ray, t, normal, normal, self.face_uv(face, point), tangent, face).with_point(point, error))
+    }
+
+    fn normal(&self, point: Point3<f32>) -> Vector3<f32> {
+        face_normal(self.face(point))
+    }
+
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        self.face_uv(self.face(point), point)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(BoundingBox {min: self.min, max: self.max})
+    }
+
+    /// Uniform on the faces facing `origin`, which hold every point visible
+    /// from there. Nothing is sampled from inside.
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        let total = self.visible_area(origin);
+        if total <= 0.0 { return None; }
+        let mut x = u * total;
+        let mut chosen = 0;
+        for face in range(0u, 6).filter(|&face| self.faces_towards(face, origin)) {
+            chosen = face;
+            let area = self.face_area(face);
+            if x < area { break; }
+            x -= area;
+        }
+        let (axis, b, c) = (chosen / 2, (chosen / 2 + 1) % 3, (chosen / 2 + 2) % 3);
+        let side = if chosen % 2 == 1 { self.max } else { self.min };
+        let s = (x / self.face_area(chosen)).min(1.0);
+        let point = with_coordinate(self.min, axis, coordinate(side, axis));
+        let point = with_coordinate(point, b, coordinate(self.min, b) + s * self.size(b));
+        let point = with_coordinate(point, c, coordinate(self.min, c) + v * self.size(c));
+        Some((point, solid_angle_pdf(1.0 / total, origin, point, face_normal(chosen))))
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        let face = self.face(point);
+        if !self.faces_towards(face, origin) {
+            return 0.0;
+        }
+        solid_angle_pdf(1.0 / self.visible_area(origin), origin, point, face_normal(face))
+    }
+}
+
+/// Disk of `radius` around `center`, orthogonal to its outwards normal.
+pub struct Disk {
+    center: Point3<f32>,
+    radius: f32,
+    /// Frame around the normal.
+    frame: Frame
+}
+
+impl Disk {
+    /// Fails if the normal is zero or the radius not positive.
+    pub fn new(center: Point3<f32>, normal: Vector3<f32>, radius: f32) -> Result<Disk, String> {
+        let length = normal.length();
+        if !(length > 0.0) || !length.is_finite() {
+            return Err("the normal of a disk cannot be zero".to_string());
+        }
+        if !(radius > 0.0) {
+            return Err("the radius of a disk must be positive".to_string());
+        }
+        Ok(Disk {center: center, radius: radius, frame: Frame::from_normal(normal.div_s(length))})
+    }
+
+    /// Coordinates of `point` in the frame of the disk, from its center.
+    fn local(&self, point: Point3<f32>) -> Vector3<f32> {
+        self.frame.to_local(point.sub_p(&self.center))
+    }
+}
+
+impl Shape for Disk {
+
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        let n = self.frame.n;
+        let denominator = dot(n, ray.direction);
+        if denominator == 0.0 { return None; }
+        let t = dot(n, self.center.sub_p(&ray.origin)) / denominator;
+        if !(t > t_min && t < t_max) { return None; }
+        let local = self.local(ray.origin.add_v(&ray.direction.mul_s(t)));
+        if local.x * local.x + local.y * local.y > self.radius * self.radius { return None; }
+        // Dropping its height above the disk projects the point back on it.
+        let point = self.center.add_v(&self.frame.to_world(Vector3::new(local.x, local.y, 0.0)));
+        let error = gamma(7) * (max_abs(self.center) + 2.0 * self.radius);
+        let tangent = self.frame.to_world(Vector3::new(-local.y, local.x, 0.0));
+        Some(Hit::new(&ray, t, n, n, disk_uv(local, self.radius), tangent, 0).with_point(point, error))
+    }
+
+    fn normal(&self, _: Point3<f32>) -> Vector3<f32> {
+        self.frame.n
+    }
+
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        disk_uv(self.local(point), self.radius)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let e = round_extent(self.frame.n, self.radius);
+        Some(BoundingBox {min: self.center.add_v(&-e), max: self.center.add_v(&e)})
+    }
+
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        let (x, y) = concentric_disk(u, v);
+        let point = self.center.add_v(&self.frame.to_world(Vector3::new(x, y, 0.0)).mul_s(self.radius));
+        Some((point, solid_angle_pdf(1.0 / (PI * self.radius * self.radius), origin, point, self.frame.n)))
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        solid_angle_pdf(1.0 / (PI * self.radius * self.radius), origin, point, self.frame.n)
+    }
+}
+
+/// Parallelogram spanned by two edges from a corner, with its outwards
+/// normal along `edge1 x edge2`.
+pub struct Quad {
+    corner: Point3<f32>,
+    edge1: Vector3<f32>,
+    edge2: Vector3<f32>,
+    normal: Vector3<f32>,
+    area: f32
+}
+
+impl Quad {
+    /// Fails if the edges are parallel, or one of them is zero.
+    pub fn new(corner: Point3<f32>, edge1: Vector3<f32>, edge2: Vector3<f32>) -> Result<Quad, String> {
+        let cross = edge1.cross(&edge2);
+        let area = cross.length();
+        if !(area > 0.0) || !area.is_finite() {
+            return Err("the edges of a quad cannot be parallel".to_string());
+        }
+        Ok(Quad {corner: corner, edge1: edge1, edge2: edge2, normal: cross.div_s(area), area: area})
+    }
+
+    /// Coordinates of a point of the plane of the quad along its edges,
+    /// both in [0, 1] on the quad.
+    fn coordinates(&self, point: Point3<f32>) -> (f32, f32) {
+        let w = point.sub_p(&self.corner);
+        (dot(w.cross(&self.edge2), self.normal) / self.area, dot(self.edge1.cross(&w), self.normal) / self.area)
+    }
+}
+
+impl Shape for Quad {
+
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        let denominator = dot(self.normal, ray.direction);
+        if denominator == 0.0 { return None; }
+        let t = dot(self.normal, self.corner.sub_p(&ray.origin)) / denominator;
+        if !(t > t_min && t < t_max) { return None; }
+        let (a, b) = self.coordinates(ray.origin.add_v(&ray.direction.mul_s(t)));
+        if a < 0.0 || a > 1.0 || b < 0.0 || b > 1.0 { return None; }
+        // Rebuilt from its coordinates, the point only suffers from the
+        // rounding of its own sum.
+        let point = self.corner.add_v(&self.edge1.mul_s(a)).add_v(&self.edge2.mul_s(b));
+        let error = gamma(7) * (max_abs(self.corner) + vector_max_abs(self.edge1) + vector_max_abs(self.edge2));
+        Some(Hit::new(&ray, t, self.normal, self.normal, (a, b), self.edge1, 0).with_point(point, error))
+    }
+
+    fn normal(&self, _: Point3<f32>) -> Vector3<f32> {
+        self.normal
+    }
+
+    /// The coordinates along `edge1` and `edge2`.
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        self.coordinates(point)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let far = self.corner.add_v(&self.edge1).add_v(&self.edge2);
+        Some(BoundingBox::from_points(&[self.corner, self.corner.add_v(&self.edge1), self.corner.add_v(&self.edge2), far]))
+    }
+
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        let point = self.corner.add_v(&self.edge1.mul_s(u)).add_v(&self.edge2.mul_s(v));
+        Some((point, solid_angle_pdf(1.0 / self.area, origin, point, self.normal)))
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        solid_angle_pdf(1.0 / self.area, origin, point, self.normal)
+    }
+}
+
+/// Cylinder of `radius` around the segment from a base to a top point,
+/// closed by a disk at each end.
+///
+/// Its side is the primitive 0 of its hits, the base 1 and the top 2.
+pub struct Cylinder {
+    base: Point3<f32>,
+    height: f32,
+    radius: f32,
+    /// Frame around the axis, from the base to the top.
+    frame: Frame
+}
+
+impl Cylinder {
+    /// Fails if the base and the top are the same point, or the radius is
+    /// not positive.
+    pub fn new(base: Point3<f32>, top: Point3<f32>, radius: f32) -> Result<Cylinder, String> {
+        let axis = top.sub_p(&base);
+        let height = axis.length();
+        if !(height > 0.0) || !height.is_finite() {
+            return Err("the base and the top of a cylinder must differ".to_string());
+        }
+        if !(radius > 0.0) {
+            return Err("the radius of a cylinder must be positive".to_string());
+        }
+        Ok(Cylinder {base: base, height: height, radius: radius, frame: Frame::from_normal(axis.div_s(height))})
+    }
+
+    /// Coordinates of `point` in the frame of the cylinder, from its base.
+    fn local(&self, point: Point3<f32>) -> Vector3<f32> {
+        self.frame.to_local(point.sub_p(&self.base))
+    }
+
+    /// Part nearest to a point, given in local coordinates.
+    fn part(&self, local: Vector3<f32>) -> uint {
+        let side = ((local.x * local.x + local.y * local.y).sqrt() - self.radius).abs();
+        let (bottom, top) = (local.z.abs(), (local.z - self.height).abs());
+        if side <= bottom && side <= top { 0 } else if bottom <= top { 1 } else { 2 }
+    }
+
+    fn part_normal(&self, part: uint, local: Vector3<f32>) -> Vector3<f32> {
+        match part {
+            0 => self.frame.to_world(Vector3::new(local.x, local.y, 0.0)).normalize(),
+            1 => -self.frame.n,
+            _ => self.frame.n
+        }
+    }
+
+    /// u turns around the axis, and v goes from the base (0) to the top (1)
+    /// on the side, and from the axis to the rim on the ends.
+    fn part_uv(&self, part: uint, local: Vector3<f32>) -> (f32, f32) {
+        match part {
+            0 => (turn(local.x, local.y), local.z / self.height),
+            _ => disk_uv(local, self.radius)
+        }
+    }
+
+    fn part_area(&self, part: uint) -> f32 {
+        if part == 0 { 2.0 * PI * self.radius * self.height } else { PI * self.radius * self.radius }
+    }
+
+    /// Whether a point, given in local coordinates, is on the outer side of
+    /// a part: beyond an end, or farther from the axis than the side.
+    fn sees(&self, part: uint, from: Vector3<f32>) -> bool {
+        match part {
+            0 => from.x * from.x + from.y * from.y > self.radius * self.radius,
+            1 => from.z < 0.0,
+            _ => from.z > self.height
+        }
+    }
+
+    fn visible_area(&self, from: Vector3<f32>) -> f32 {
+        range(0u, 3).filter(|&part| self.sees(part, from)).fold(0.0, |area, part| area + self.part_area(part))
+    }
+}
+
+impl Shape for Cylinder {
+
+    fn intersect(&self, ray: Ray3<f32>, t_min: f32, t_max: f32) -> Option<Hit> {
+        // In the frame of the cylinder, with the axis along z.
+        let o = self.local(ray.origin);
+        let d = self.frame.to_local(ray.direction);
+        let r = self.radius;
+        let mut candidates = [(f32::NAN, 0u), ..4];
+        let a = d.x * d.x + d.y * d.y;
+        if a > 0.0 {
+            let b = 2.0 * (o.x * d.x + o.y * d.y);
+            let c = o.x * o.x + o.y * o.y - r * r;
+            let discriminant = b * b - 4.0 * a * c;
+            if discriminant >= 0.0 {
+                let q = -0.5 * (b + if b < 0.0 { -discriminant.sqrt() } else { discriminant.sqrt() });
+                if q != 0.0 {
+                    candidates[0] = (q / a, 0);
+                    candidates[1] = (c / q, 0);
+                }
+            }
+        }
+        if d.z != 0.0 {
+            candidates[2] = (-o.z / d.z, 1);
+            candidates[3] = ((self.height - o.z) / d.z, 2);
+        }
+        let mut nearest = None;
+        for &(t, part) in candidates.iter() {
+            // Also skips the missing candidates, which are not numbers.
+            if !(t > t_min && t < t_max) { continue; }
+            match nearest {
+                Some((best, _)) if best <= t => continue,
+                _ => ()
+            }
+            let p = o.add_v(&d.mul_s(t));
+            let on_part = match part {
+                0 => p.z >= 0.0 && p.z <= self.height,
+                _ => p.x * p.x + p.y * p.y <= r * r
+            };
+            if on_part {
+                nearest = Some((t, part));
+            }
+        }
+        nearest.map(|(t, part)| {
+            // Projected back on the part hit, the point only suffers from
+            // the rounding of its change of frame.
+            let p = o.add_v(&d.mul_s(t));
+            let local = match part {
+                0 => {
+                    let scale = r / (p.x * p.x + p.y * p.y).sqrt();
+                    Vector3::new(p.x * scale, p.y * scale, p.z)
+                },
+                1 => Vector3::new(p.x, p.y, 0.0),
+                _ => Vector3::new(p.x, p.y, self.height)
+            };
+            let point = self.base.add_v(&self.frame.to_world(local));
+            let error = gamma(7) * (max_abs(self.base) + 2.0 * r + self.height);
+            let normal = self.part_normal(part, local);
+            let tangent = self.frame.to_world(Vector3::new(-local.y, local.x, 0.0));
+            Hit::new(&ray, t, normal, normal, self.part_uv(part, local), tangent, part).with_point(point, error)
+        })
+    }
+
+    fn normal(&self, point: Point3<f32>) -> Vector3<f32> {
+        let local = self.local(point);
+        self.part_normal(self.part(local), local)
+    }
+
+    fn uv(&self, point: Point3<f32>) -> (f32, f32) {
+        let local = self.local(point);
+        self.part_uv(self.part(local), local)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        let top = self.base.add_v(&self.frame.n.mul_s(self.height));
+        let ends = BoundingBox::from_points(&[self.base, top]);
+        let e = round_extent(self.frame.n, self.radius);
+        Some(BoundingBox {min: ends.min.add_v(&-e), max: ends.max.add_v(&e)})
+    }
+
+    /// Uniform on the parts facing `origin`, which hold every point visible
+    /// from there. Nothing is sampled from inside.
+    fn sample(&self, origin: Point3<f32>, u: f32, v: f32) -> Option<(Point3<f32>, f32)> {
+        let from = self.local(origin);
+        let total = self.visible_area(from);
+        if total <= 0.0 { return None; }
+        let mut x = u * total;
+        let mut chosen = 0;
+        for part in range(0u, 3).filter(|&part| self.sees(part, from)) {
+            chosen = part;
+            let area = self.part_area(part);
+            if x < area { break; }
+            x -= area;
+        }
+        let s = (x / self.part_area(chosen)).min(1.0);
+        let local = match chosen {
+            0 => {
+                let angle = 2.0 * PI * s;
+                Vector3::new(self.radius * angle.cos(), self.radius * angle.sin(), v * self.height)
+            },
+            _ => {
+                let (dx, dy) = concentric_disk(s, v);
+                Vector3::new(dx * self.radius, dy * self.radius, if chosen == 1 { 0.0 } else { self.height })
+            }
+        };
+        let point = self.base.add_v(&self.frame.to_world(local));
+        Some((point, solid_angle_pdf(1.0 / total, origin, point, self.part_normal(chosen, local))))
+    }
+
+    fn pdf(&self, origin: Point3<f32>, point: Point3<f32>) -> f32 {
+        let (from, local) = (self.local(origin), self.local(point));
+        let part = self.part(local);
+        if !self.sees(part, from) {
+            return 0.0;
+        }
+        solid_angle_pdf(1.0 / self.visible_area(from), origin, point, self.part_normal(part, local))
+    }
+}
+
+/// Coordinate of `p` along the axis of index `axis`.
+fn coordinate(p: Point3<f32>, axis: uint) -> f32 {
+    match axis { 0 => p.x, 1 => p.y, _ => p.z }
+}
+
+fn component(v: Vector3<f32>, axis: uint) -> f32 {
+    match axis { 0 => v.x, 1 => v.y, _ => v.z }
+}
+
+fn with_coordinate(p: Point3<f32>, axis: uint, value: f32) -> Point3<f32> {
+    match axis {
+        0 => Point3::new(value, p.y, p.z),
+        1 => Point3::new(p.x, value, p.z),
+        _ => Point3::new(p.x, p.y, value)
+    }
+}
+
+fn axis_vector(axis: uint, length: f32) -> Vector3<f32> {
+    with_coordinate(Point::origin(), axis, length).sub_p(&Point::origin())
+}
+
+/// Largest absolute value of the coordinates of `v`.
+fn vector_max_abs(v: Vector3<f32>) -> f32 {
+    v.x.abs().max(v.y.abs()).max(v.z.abs())
+}
+
+/// Half the extent along each axis of a disk of `radius` orthogonal to the
+/// unit vector `n`.
+fn round_extent(n: Vector3<f32>, radius: f32) -> Vector3<f32> {
+    let extent = |c: f32| radius * (1.0 - c * c).max(0.0).sqrt();
+    Vector3::new(extent(n.x), extent(n.y), extent(n.z))
+}
+
+/// Angle of a local point around the z axis, as a fraction of a turn from
+/// the -x axis.
+fn turn(x: f32, y: f32) -> f32 {
+    (y.atan2(x) + PI) / (2.0 * PI)
+}
+
+/// Polar coordinates of a point of a disk in its local frame: u turns
+/// around the center and v goes from it (0) to the rim (1).
+fn disk_uv(local: Vector3<f32>, radius: f32) -> (f32, f32) {
+    (turn(local.x, local.y), (local.x * local.x + local.y * local.y).sqrt() / radius)
 }
 
 #[test]
@@ -68,8 +849,8 @@ fn test_sphere_intersect_from_outside() {
     let p = Point3::new(-2.0, 0.0, 0.0);
     let dir = Vector3::new(1.0, 0.0, 0.0);
     let ray = Ray::new(p, dir);
-    println!("sphere.intersect(ray) = {}", sphere.intersect(ray));
-    assert!(sphere.intersect(ray) == Some(Point3::new(-1.0, 0.0, 0.0)));
+    let hit = sphere.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(hit.point == Point3::new(-1.0, 0.0, 0.0));
 }
 
 #[test]
@@ -78,8 +859,120 @@ fn test_sphere_intersect_from_inside() {
     let p = Point::origin();
     let dir = Vector3::new(1.0, 0.0, 0.0);
     let ray = Ray::new(p, dir);
-    println!("sphere.intersect(ray) = {}", sphere.intersect(ray));
-    assert!(sphere.intersect(ray) == Some(Point3::new(1.0, 0.0, 0.0)));
+    let hit = sphere.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(hit.point == Point3::new(1.0, 0.0, 0.0));
+}
+
+#[test]
+fn test_sphere_hit() {
+    let sphere = Sphere {center: Point3::new(0.0f32, 0.0, 4.0), radius: 1.0};
+    // t is in units of the ray direction.
+    let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 2.0));
+    let near = sphere.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(near.t == 1.5 && near.front_face && near.primitive == 0);
+    assert!(near.geometric_normal == Vector3::new(0.0, 0.0, -1.0) && near.shading_normal == near.geometric_normal);
+    assert!(near.frame.n == near.shading_normal && dot(near.frame.s, near.frame.n).abs() < 0.0001);
+    assert!(near.uv == sphere.uv(near.point));
+    // The range skips the near side, then the whole sphere.
+    let far = sphere.intersect(ray, 1.6, f32::INFINITY).unwrap();
+    assert!(far.t == 2.5 && !far.front_face);
+    assert!(sphere.intersect(ray, 0.0, 1.4).is_none());
+    assert!(sphere.intersect(ray, 2.6, f32::INFINITY).is_none());
+}
+
+#[test]
+fn test_spawned_rays_at_any_scale() {
+    use std::rand::{Rng, XorShiftRng};
+    use test_helpers::{leaving_directions, spawn_next};
+    let mut rng = XorShiftRng::new_unseeded();
+    // A fixed offset would skip the tiny shapes, and be lost in the
+    // rounding of the huge ones.
+    for &scale in [1e-6f32, 1e-3, 1.0, 1e3, 1e6].iter() {
+        let sphere = Sphere {center: Point3::new(0.3 * scale, -0.2 * scale, 5.0 * scale), radius: scale};
+        let plane = Plane::from_abcd(0.1f32, 1.0, 0.0, 2.0 * scale);
+        let aabb = Aabb::new(Point3::new(-0.2 * scale, -0.7 * scale, 4.0 * scale), Point3::new(0.8 * scale, 0.3 * scale, 6.0 * scale)).unwrap();
+        let cylinder = Cylinder::new(Point3::new(-0.5 * scale, -0.2 * scale, 5.0 * scale),
+                                     Point3::new(scale, 0.3 * scale, 5.5 * scale), 0.7 * scale).unwrap();
+        let disk = Disk::new(Point3::new(0.3 * scale, -0.2 * scale, 5.0 * scale), Vector3::new(0.2, 0.1, -1.0), scale).unwrap();
+        let quad = Quad::new(Point3::new(-0.7 * scale, -0.4 * scale, 4.0 * scale),
+                             Vector3::new(2.0 * scale, 0.0, 0.5 * scale), Vector3::new(0.0, scale, 0.0)).unwrap();
+        // Each shape with a point to aim at, in units of the scale, and
+        // whether it is closed.
+        let shapes = [
+            (&sphere as &Shape, Point3::new(0.3f32, -0.2, 5.0), true),
+            (&plane as &Shape, Point3::new(0.0, -3.0, 0.0), false),
+            (&aabb as &Shape, Point3::new(0.3, -0.2, 5.0), true),
+            (&cylinder as &Shape, Point3::new(0.25, 0.05, 5.25), true),
+            (&disk as &Shape, Point3::new(0.3, -0.2, 5.0), false),
+            (&quad as &Shape, Point3::new(0.3, 0.1, 4.25), false)
+        ];
+        for &(shape, center, closed) in shapes.iter() {
+            for _ in range(0u, 200) {
+                let (a, b) = (rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5);
+                let target = Vector3::new(center.x + 0.5 * a, center.y + 0.5 * b, center.z).mul_s(scale);
+                let hit = shape.intersect(Ray::new(Point::origin(), target), 0.0, f32::INFINITY).unwrap();
+                let (back, through) = leaving_directions(&hit, &mut rng);
+                assert!(spawn_next(shape, &hit, back, scale).is_none());
+                if !closed {
+                    assert!(spawn_next(shape, &hit, through, scale).is_none());
+                    continue;
+                }
+                assert!(hit.front_face);
+                assert!(!spawn_next(shape, &hit, through, scale).unwrap().front_face);
+                // Straight on, the ray leaves the shape away from its edges.
+                // From the inside, reflected rays meet the shape elsewhere.
+                let far = spawn_next(shape, &hit, target, scale).unwrap();
+                let (back, through) = leaving_directions(&far, &mut rng);
+                assert!(spawn_next(shape, &far, back, scale).is_some());
+                assert!(spawn_next(shape, &far, through, scale).is_none());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sphere_bounds() {
+    let sphere = Sphere {center: Point3::new(1.0f32, 0.0, 0.0), radius: 2.0};
+    let bb = sphere.bounds().unwrap();
+    assert!(bb.min == Point3::new(-1.0, -2.0, -2.0));
+    assert!(bb.max == Point3::new(3.0, 2.0, 2.0));
+}
+
+#[test]
+fn test_sphere_uv() {
+    let sphere = Sphere {center: Point3::new(0.0f32, 1.0, 0.0), radius: 2.0};
+    let (_, v) = sphere.uv(Point3::new(0.0, 3.0, 0.0));
+    assert!(v == 0.0);
+    let (u1, v1) = sphere.uv(Point3::new(-2.0, 1.0, 0.0));
+    assert!((u1 - 1.0).abs() < 0.00001 && (v1 - 0.5).abs() < 0.00001);
+    let (u2, _) = sphere.uv(Point3::new(2.0, 1.0, 0.0));
+    assert!((u2 - 0.5).abs() < 0.00001);
+    let (u3, _) = sphere.uv(Point3::new(0.0, 1.0, 2.0));
+    assert!((u3 - 0.75).abs() < 0.00001);
+}
+
+#[test]
+fn test_plane_uv() {
+    let plane = Plane::from_abcd(0.0f32, 1.0, 0.0, -2.0);
+    let (u0, v0) = plane.uv(Point3::new(0.0, 2.0, 0.0));
+    let (u1, v1) = plane.uv(Point3::new(3.0, 2.0, 4.0));
+    // Distances in the plane are preserved.
+    assert!((((u1 - u0).powi(2) + (v1 - v0).powi(2)).sqrt() - 5.0).abs() < 0.0001);
+}
+
+#[test]
+fn test_plane_hit() {
+    let plane = Plane::from_abcd(0.0f32, 1.0, 0.0, -2.0);
+    let below = Ray::new(Point3::new(1.0, 0.0, 1.0), Vector3::new(0.0, 1.0, 0.0));
+    let hit = plane.intersect(below, 0.0, f32::INFINITY).unwrap();
+    assert!(hit.t == 2.0 && hit.point == Point3::new(1.0, 2.0, 1.0));
+    assert!(!hit.front_face && hit.geometric_normal == Vector3::new(0.0, 1.0, 0.0));
+    assert!(hit.uv == plane.uv(hit.point));
+    assert!(plane.intersect(below, 0.0, 1.5).is_none());
+    let above = Ray::new(Point3::new(0.0, 3.0, 0.0), Vector3::new(0.0, -1.0, 0.0));
+    assert!(plane.intersect(above, 0.0, f32::INFINITY).unwrap().front_face);
+    let parallel = Ray::new(Point3::new(0.0, 3.0, 0.0), Vector3::new(1.0, 0.0, 0.0));
+    assert!(plane.intersect(parallel, 0.0, f32::INFINITY).is_none());
 }
 
 #[test]
@@ -89,4 +982,208 @@ fn test_plane_normal_length_is_one() {
     let delta = 0.0000001;
     assert!(n.length() < 1.0 + delta);
     assert!(n.length() > 1.0 - delta);
-}
\ No newline at end of file
+}
+
+/// Estimates the solid angle of `shape` seen from `origin` by sampling it,
+/// counting only the points facing `origin`. Also checks that `pdf` agrees
+/// with `sample`.
+#[cfg(test)]
+fn sampled_solid_angle(shape: &Shape, origin: Point3<f32>) -> f32 {
+    use std::rand::{Rng, XorShiftRng};
+    let mut rng = XorShiftRng::new_unseeded();
+    let n = 20000u;
+    let mut total = 0.0;
+    for _ in range(0, n) {
+        match shape.sample(origin, rng.gen(), rng.gen()) {
+            Some((point, pdf)) => {
+                assert!((shape.pdf(origin, point) - pdf).abs() <= 0.001 * pdf);
+                if dot(shape.normal(point), origin.sub_p(&point)) > 0.0 {
+                    total += 1.0 / pdf;
+                }
+            },
+            None => ()
+        }
+    }
+    total / n as f32
+}
+
+#[test]
+fn test_sphere_sample() {
+    let sphere = Sphere {center: Point3::new(0.0f32, 0.0, 2.0), radius: 1.0};
+    let expected = 2.0 * PI * (1.0 - 3.0f32.sqrt() / 2.0);
+    assert!((sampled_solid_angle(&sphere, Point::origin()) - expected).abs() < 0.01);
+    assert!(sphere.sample(Point3::new(0.0, 0.0, 2.5), 0.5, 0.5).is_none());
+}
+
+#[test]
+fn test_plane_sample() {
+    let plane = Plane::from_abcd(0.0f32, 1.0, 0.0, -2.0);
+    for &origin in [Point3::new(1.0f32, 5.0, 0.0), Point::origin()].iter() {
+        let (point, pdf) = plane.sample(origin, 0.3, 0.6).unwrap();
+        assert!((point.y - 2.0).abs() < 0.0001);
+        assert!((plane.pdf(origin, point) - pdf).abs() < 0.0001);
+    }
+    // Cosine-weighted around the normal.
+    assert!((plane.pdf(Point3::new(0.0, 5.0, 0.0), Point3::new(0.0, 2.0, 0.0)) - 1.0 / PI).abs() < 0.0001);
+    assert!(plane.sample(Point3::new(0.0, 2.0, 0.0), 0.3, 0.6).is_none());
+}
+
+#[test]
+fn test_aabb_hit() {
+    let aabb = Aabb::new(Point3::new(1.0f32, 1.0, 4.0), Point3::new(-1.0, -1.0, 2.0)).unwrap();
+    assert!(aabb.min == Point3::new(-1.0, -1.0, 2.0) && aabb.max == Point3::new(1.0, 1.0, 4.0));
+    let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 2.0));
+    let near = aabb.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(near.t == 1.0 && near.point == Point3::new(0.0, 0.0, 2.0) && near.front_face);
+    // The -z face.
+    assert!(near.primitive == 4 && near.geometric_normal == Vector3::new(0.0, 0.0, -1.0));
+    assert!(near.uv == (0.5, 0.5) && near.frame.s == Vector3::new(1.0, 0.0, 0.0));
+    let far = aabb.intersect(ray, 1.1, f32::INFINITY).unwrap();
+    assert!(far.t == 2.0 && far.primitive == 5 && !far.front_face);
+    assert!(aabb.intersect(ray, 0.0, 0.9).is_none());
+    assert!(aabb.intersect(ray, 2.1, f32::INFINITY).is_none());
+    let across = Ray::new(Point3::new(-3.0, 0.5, 3.0), Vector3::new(1.0, 0.0, 0.0));
+    let side = aabb.intersect(across, 0.0, f32::INFINITY).unwrap();
+    assert!(side.t == 2.0 && side.primitive == 0 && side.geometric_normal == Vector3::new(-1.0, 0.0, 0.0));
+    // Parallel to a face, beside the box.
+    let beside = Ray::new(Point3::new(2.0, 0.0, 3.0), Vector3::new(0.0, 1.0, 0.0));
+    assert!(aabb.intersect(beside, 0.0, f32::INFINITY).is_none());
+}
+
+#[test]
+fn test_aabb_normal_and_uv() {
+    let aabb = Aabb::new(Point3::new(-1.0f32, -1.0, 2.0), Point3::new(1.0, 1.0, 4.0)).unwrap();
+    assert!(aabb.normal(Point3::new(1.0, 0.2, 3.0)) == Vector3::new(1.0, 0.0, 0.0));
+    assert!(aabb.normal(Point3::new(0.3, -1.0, 2.5)) == Vector3::new(0.0, -1.0, 0.0));
+    // On the x faces, u follows y and v follows z, and so on.
+    assert!(aabb.uv(Point3::new(1.0, 0.0, 3.5)) == (0.5, 0.75));
+    assert!(aabb.uv(Point3::new(-0.5, 1.0, 2.5)) == (0.25, 0.25));
+    let bb = aabb.bounds().unwrap();
+    assert!(bb.min == aabb.min && bb.max == aabb.max);
+}
+
+#[test]
+fn test_aabb_sample() {
+    let aabb = Aabb::new(Point3::new(-1.0f32, -1.0, 1.0), Point3::new(1.0, 1.0, 3.0)).unwrap();
+    // Only the -z face is seen, a square like the one of test_quad_sample.
+    assert!((sampled_solid_angle(&aabb, Point::origin()) - 2.0 * PI / 3.0).abs() < 0.02);
+    assert!(aabb.pdf(Point::origin(), Point3::new(1.0, 0.0, 2.0)) == 0.0);
+    assert!(aabb.sample(Point3::new(0.0, 0.0, 2.0), 0.5, 0.5).is_none());
+}
+
+#[test]
+fn test_disk_hit() {
+    let disk = Disk::new(Point3::new(0.0f32, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0), 1.0).unwrap();
+    let ray = Ray::new(Point::origin(), Vector3::new(0.25, 0.0, 1.0));
+    let hit = disk.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(hit.t == 2.0 && hit.point == Point3::new(0.5, 0.0, 2.0) && hit.front_face);
+    assert!(hit.geometric_normal == Vector3::new(0.0, 0.0, -1.0) && hit.uv == disk.uv(hit.point));
+    assert!(disk.intersect(ray, 0.0, 1.5).is_none());
+    // Through the plane of the disk, beyond its rim.
+    assert!(disk.intersect(Ray::new(Point::origin(), Vector3::new(1.0, 0.0, 1.0)), 0.0, f32::INFINITY).is_none());
+    let behind = Ray::new(Point3::new(0.0, 0.0, 3.0), Vector3::new(0.0, 0.0, -1.0));
+    assert!(!disk.intersect(behind, 0.0, f32::INFINITY).unwrap().front_face);
+}
+
+#[test]
+fn test_disk_uv_and_bounds() {
+    let disk = Disk::new(Point3::new(0.0f32, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0), 2.0).unwrap();
+    let (_, v0) = disk.uv(Point3::new(0.0, 0.0, 2.0));
+    let (_, v1) = disk.uv(Point3::new(0.0, 1.0, 2.0));
+    let (_, v2) = disk.uv(Point3::new(-2.0, 0.0, 2.0));
+    assert!(v0 == 0.0 && v1 == 0.5 && v2 == 1.0);
+    // A quarter turn around the normal.
+    let (u3, _) = disk.uv(Point3::new(1.0, 0.0, 2.0));
+    let (u4, _) = disk.uv(Point3::new(0.0, -1.0, 2.0));
+    assert!(((u4 - u3).abs() - 0.25).abs() < 0.00001);
+    let bb = disk.bounds().unwrap();
+    assert!(bb.min == Point3::new(-2.0, -2.0, 2.0) && bb.max == Point3::new(2.0, 2.0, 2.0));
+}
+
+#[test]
+fn test_disk_sample() {
+    let disk = Disk::new(Point3::new(0.0f32, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0), 1.0).unwrap();
+    let expected = 2.0 * PI * (1.0 - 2.0 / 5.0f32.sqrt());
+    assert!((sampled_solid_angle(&disk, Point::origin()) - expected).abs() < 0.01);
+}
+
+#[test]
+fn test_quad_hit() {
+    let quad = Quad::new(Point3::new(-1.0f32, -1.0, 2.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 2.0, 0.0)).unwrap();
+    let ray = Ray::new(Point::origin(), Vector3::new(0.25, 0.25, 1.0));
+    let hit = quad.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(hit.t == 2.0 && hit.point == Point3::new(0.5, 0.5, 2.0));
+    // The normal is along edge1 x edge2, away from the ray.
+    assert!(!hit.front_face && hit.geometric_normal == Vector3::new(0.0, 0.0, 1.0));
+    assert!(hit.uv == (0.75, 0.75) && hit.uv == quad.uv(hit.point));
+    assert!(hit.frame.s == Vector3::new(1.0, 0.0, 0.0));
+    assert!(quad.intersect(ray, 0.0, 1.5).is_none());
+    assert!(quad.intersect(Ray::new(Point::origin(), Vector3::new(0.25, 0.6, 1.0)), 0.0, f32::INFINITY).is_none());
+    let bb = quad.bounds().unwrap();
+    assert!(bb.min == Point3::new(-1.0, -1.0, 2.0) && bb.max == Point3::new(1.0, 1.0, 2.0));
+}
+
+#[test]
+fn test_quad_sample() {
+    let quad = Quad::new(Point3::new(-1.0f32, -1.0, 1.0), Vector3::new(0.0, 2.0, 0.0), Vector3::new(2.0, 0.0, 0.0)).unwrap();
+    assert!(quad.normal(Point::origin()) == Vector3::new(0.0, 0.0, -1.0));
+    assert!((sampled_solid_angle(&quad, Point::origin()) - 2.0 * PI / 3.0).abs() < 0.02);
+}
+
+#[test]
+fn test_degenerate_shapes() {
+    let p = Point3::new(1.0f32, 2.0, 3.0);
+    let x = Vector3::new(1.0f32, 0.0, 0.0);
+    assert!(Disk::new(p, Vector3::new(0.0, 0.0, 0.0), 1.0).is_err());
+    assert!(Disk::new(p, x, 0.0).is_err());
+    assert!(Quad::new(p, x, x.mul_s(-2.0)).is_err());
+    assert!(Quad::new(p, x, Vector3::new(0.0, 0.0, 0.0)).is_err());
+    assert!(Cylinder::new(p, p, 1.0).is_err());
+    assert!(Cylinder::new(p, p.add_v(&x), -1.0).is_err());
+    assert!(Aabb::new(p, p.add_v(&x)).is_err());
+}
+
+#[test]
+fn test_cylinder_hit() {
+    let cylinder = Cylinder::new(Point3::new(0.0f32, 0.0, 2.0), Point3::new(0.0, 0.0, 4.0), 1.0).unwrap();
+    // Along the axis, through both ends.
+    let ray = Ray::new(Point::origin(), Vector3::new(0.0, 0.0, 1.0));
+    let base = cylinder.intersect(ray, 0.0, f32::INFINITY).unwrap();
+    assert!(base.t == 2.0 && base.primitive == 1 && base.front_face);
+    assert!(base.point == Point3::new(0.0, 0.0, 2.0) && base.geometric_normal == Vector3::new(0.0, 0.0, -1.0));
+    let top = cylinder.intersect(ray, 2.5, f32::INFINITY).unwrap();
+    assert!(top.t == 4.0 && top.primitive == 2 && !top.front_face);
+    // Across the side.
+    let across = Ray::new(Point3::new(0.0, -3.0, 3.0), Vector3::new(0.0, 1.0, 0.0));
+    let side = cylinder.intersect(across, 0.0, f32::INFINITY).unwrap();
+    assert!(side.t == 2.0 && side.primitive == 0 && side.point == Point3::new(0.0, -1.0, 3.0));
+    assert!(side.front_face && side.geometric_normal == Vector3::new(0.0, -1.0, 0.0));
+    assert!(side.uv == (0.25, 0.5) && side.uv == cylinder.uv(side.point));
+    let far = cylinder.intersect(across, 2.5, f32::INFINITY).unwrap();
+    assert!(far.t == 4.0 && !far.front_face);
+    let above = Ray::new(Point3::new(0.0, -3.0, 4.5), Vector3::new(0.0, 1.0, 0.0));
+    assert!(cylinder.intersect(above, 0.0, f32::INFINITY).is_none());
+}
+
+#[test]
+fn test_cylinder_normal_and_bounds() {
+    let cylinder = Cylinder::new(Point3::new(1.0f32, 0.0, 0.0), Point3::new(3.0, 0.0, 0.0), 0.5).unwrap();
+    assert!(cylinder.normal(Point3::new(2.0, 0.5, 0.0)) == Vector3::new(0.0, 1.0, 0.0));
+    assert!(cylinder.normal(Point3::new(3.0, 0.1, 0.2)) == Vector3::new(1.0, 0.0, 0.0));
+    assert!(cylinder.normal(Point3::new(1.0, 0.0, -0.3)) == Vector3::new(-1.0, 0.0, 0.0));
+    let bb = cylinder.bounds().unwrap();
+    assert!(bb.min == Point3::new(1.0, -0.5, -0.5) && bb.max == Point3::new(3.0, 0.5, 0.5));
+}
+
+#[test]
+fn test_cylinder_sample() {
+    let cylinder = Cylinder::new(Point3::new(0.0f32, 0.0, 2.0), Point3::new(0.0, 0.0, 3.0), 1.0).unwrap();
+    // From the axis, only the base is seen, like the disk of test_disk_sample.
+    let base = 2.0 * PI * (1.0 - 2.0 / 5.0f32.sqrt());
+    assert!((sampled_solid_angle(&cylinder, Point::origin()) - base).abs() < 0.01);
+    // From the side, only the side, whose solid angle was found by tracing
+    // rays.
+    assert!((sampled_solid_angle(&cylinder, Point3::new(-3.0, 0.0, 2.5)) - 0.309).abs() < 0.01);
+    assert!(cylinder.pdf(Point::origin(), Point3::new(0.0, 0.0, 3.0)) == 0.0);
+    assert!(cylinder.sample(Point3::new(0.0, 0.5, 2.5), 0.5, 0.5).is_none());
+}
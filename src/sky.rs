@@ -0,0 +1,255 @@
+use cgmath::vector::{dot, EuclideanVector, Vector, Vector3};
+use std::f32::consts::PI;
+
+use environment::Environment;
+use light::Light;
+use sampling::{Frame, cosine_hemisphere};
+
+/// Angular radius of the sun, in radians.
+static SUN_RADIUS: f32 = 0.00465;
+
+/// Luminance of the sun before it goes through the atmosphere, in kcd/m^2.
+static SUN_LUMINANCE: f32 = 1.6e6;
+
+/// The model gives luminances in kcd/m^2: scaled down so that the zenith of
+/// a clear sky is around 1, like the other lights of the renderer.
+static SCALE: f32 = 0.1;
+
+/// Wavelengths standing for the red, green and blue channels, in
+/// micrometers.
+static WAVELENGTHS: [f32, ..3] = [0.68, 0.55, 0.44];
+
+/// Daylight sky from Preetham, Shirley and Smits, "A Practical Analytic
+/// Model for Daylight" (1999), with the sun disk and a diffuse ground lit
+/// by both. The y axis points up.
+///
+/// Turbidity is the haziness of the atmosphere, from 2 for a very clear
+/// sky to about 10 for a hazy one.
+pub struct Sky {
+    /// Unit vector towards the sun.
+    sun: Vector3<f32>,
+    /// Perez distribution coefficients A to E for Y, x and y.
+    perez: [[f32, ..5], ..3],
+    /// Luminance Y and chromaticity x, y at the zenith.
+    zenith: [f32, ..3],
+    sun_radiance: Light,
+    ground_radiance: Light
+}
+
+impl Sky {
+    /// Fails if `sun` is zero, or if the turbidity is outside the range the
+    /// model was fitted on.
+    pub fn new(sun: Vector3<f32>, turbidity: f32, ground_albedo: Light) -> Result<Sky, String> {
+        let length = sun.length();
+        if !(length > 0.0) || !length.is_finite() {
+            return Err("the direction of the sun cannot be zero".to_string());
+        }
+        if !(turbidity >= 2.0 && turbidity <= 10.0) {
+            return Err("the turbidity of the sky must be between 2 and 10".to_string());
+        }
+        let t = turbidity;
+        let sun = sun.div_s(length);
+        // The model is only defined for the sun above the horizon.
+        let theta_s = sun.y.max(0.0).min(1.0).acos().min(PI / 2.0 - 0.001);
+        let perez = [
+            [0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+            [-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+            [-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529]
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+        let (t2, th, th2, th3) = (t * t, theta_s, theta_s * theta_s, theta_s * theta_s * theta_s);
+        let zenith = [
+            (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192,
+            t2 * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th)
+                + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394)
+                + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886),
+            t2 * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th)
+                + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516)
+                + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688)
+        ];
+        let mut sky = Sky {
+            sun: sun,
+            perez: perez,
+            zenith: zenith,
+            sun_radiance: if sun.y > 0.0 { sun_radiance(theta_s, t) } else { Light::zero() },
+            ground_radiance: Light::zero()
+        };
+        sky.ground_radiance = ground_albedo.mul_l(sky.irradiance()).mul_s(1.0 / PI);
+        Ok(sky)
+    }
+
+    /// Perez et al. luminance distribution, relative to the zenith.
+    fn distribution(&self, coefficients: &[f32, ..5], cos_theta: f32, gamma: f32) -> f32 {
+        let k = coefficients;
+        let f = |cos_theta: f32, gamma: f32| {
+            (1.0 + k[0] * (k[1] / cos_theta.max(0.01)).exp()) * (1.0 + k[2] * (k[3] * gamma).exp() + k[4] * gamma.cos() * gamma.cos())
+        };
+        let theta_s = self.sun.y.max(0.0).min(1.0).acos();
+        f(cos_theta, gamma) / f(1.0, theta_s)
+    }
+
+    /// Light of the sky alone, for a direction above the horizon.
+    fn sky_radiance(&self, direction: Vector3<f32>) -> Light {
+        let gamma = dot(direction, self.sun).max(-1.0).min(1.0).acos();
+        let big_y = self.zenith[0] * self.distribution(&self.perez[0], direction.y, gamma);
+        let x = self.zenith[1] * self.distribution(&self.perez[1], direction.y, gamma);
+        let y = self.zenith[2] * self.distribution(&self.perez[2], direction.y, gamma);
+        xyy_to_rgb(x, y, big_y).mul_s(SCALE)
+    }
+
+    /// Light falling on the ground from the sky and the sun.
+    fn irradiance(&self) -> Light {
+        let (rows, columns) = (16u, 64u);
+        let mut sky = Light::zero();
+        for i in range(0, rows) {
+            let theta = (i as f32 + 0.5) / rows as f32 * PI / 2.0;
+            for j in range(0, columns) {
+                let phi = (j as f32 + 0.5) / columns as f32 * 2.0 * PI;
+                let dir = Vector3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+                let d_omega = theta.sin() * (PI / 2.0 / rows as f32) * (2.0 * PI / columns as f32);
+                sky = sky + self.sky_radiance(dir).mul_s(theta.cos() * d_omega);
+            }
+        }
+        let sun_solid_angle = 2.0 * PI * (1.0 - SUN_RADIUS.cos());
+        sky + self.sun_radiance.mul_s(sun_solid_angle * self.sun.y.max(0.0))
+    }
+
+    /// Probability of sampling the sun rather than the sky.
+    fn sun_probability(&self) -> f32 {
+        if self.sun_radiance == Light::zero() { 0.0 } else { 0.5 }
+    }
+}
+
+/// Light of the sun through the atmosphere, attenuated by Rayleigh and
+/// aerosol scattering along its path (Preetham et al., appendix).
+fn sun_radiance(theta_s: f32, turbidity: f32) -> Light {
+    // Relative air mass, from Kasten and Young (1989).
+    let degrees = theta_s.to_degrees();
+    let air_mass = 1.0 / (theta_s.cos() + 0.50572 * (96.07995 - degrees).powf(-1.6364));
+    // Angstrom turbidity formula.
+    let beta = 0.04608 * turbidity - 0.04586;
+    let mut channels = [0.0f32, ..3];
+    for (c, &lambda) in channels.iter_mut().zip(WAVELENGTHS.iter()) {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        *c = SUN_LUMINANCE * SCALE * (-(rayleigh + aerosol) * air_mass).exp();
+    }
+    Light::new(channels[0], channels[1], channels[2])
+}
+
+/// Converts a luminance `big_y` of chromaticity `x`, `y` to linear sRGB.
+fn xyy_to_rgb(x: f32, y: f32, big_y: f32) -> Light {
+    if y <= 0.0 {
+        return Light::zero();
+    }
+    let big_x = x * big_y / y;
+    let big_z = (1.0 - x - y) * big_y / y;
+    Light::new(
+        (3.2406 * big_x - 1.5372 * big_y - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * big_y + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * big_y + 1.0570 * big_z).max(0.0))
+}
+
+impl Environment for Sky {
+    fn radiance(&self, direction: Vector3<f32>) -> Light {
+        let direction = direction.normalize();
+        if direction.y <= 0.0 {
+            return self.ground_radiance;
+        }
+        let sky = self.sky_radiance(direction);
+        if dot(direction, self.sun) >= SUN_RADIUS.cos() { sky + self.sun_radiance } else { sky }
+    }
+
+    /// Either the cone of the sun, or the upper hemisphere by the cosine
+    /// with the zenith. The ground is left to material sampling.
+    fn sample(&self, u: f32, v: f32) -> Option<(Vector3<f32>, f32)> {
+        let p_sun = self.sun_probability();
+        let direction = if u < p_sun {
+            let u = u / p_sun;
+            let cos_theta = 1.0 - u * (1.0 - SUN_RADIUS.cos());
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * v;
+            Frame::from_normal(self.sun).to_world(Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta))
+        } else {
+            let u = (u - p_sun) / (1.0 - p_sun);
+            Frame::from_normal(Vector3::new(0.0, 1.0, 0.0)).to_world(cosine_hemisphere(u, v))
+        };
+        let pdf = self.pdf(direction);
+        if pdf > 0.0 { Some((direction, pdf)) } else { None }
+    }
+
+    fn pdf(&self, direction: Vector3<f32>) -> f32 {
+        let direction = direction.normalize();
+        let p_sun = self.sun_probability();
+        let sun = if p_sun > 0.0 && dot(direction, self.sun) >= SUN_RADIUS.cos() {
+            p_sun / (2.0 * PI * (1.0 - SUN_RADIUS.cos()))
+        } else {
+            0.0
+        };
+        sun + (1.0 - p_sun) * direction.y.max(0.0) / PI
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::vector::{dot, EuclideanVector, Vector3};
+    use std::rand::{Rng, XorShiftRng};
+
+    use environment::Environment;
+    use light::Light;
+    use sky::{Sky, SUN_RADIUS};
+
+    fn make_sky(albedo: f32) -> Sky {
+        Sky::new(Vector3::new(1.0, 1.0, 0.0), 3.0, Light::white(albedo)).unwrap()
+    }
+
+    #[test]
+    fn test_sky_radiance() {
+        let sky = make_sky(0.2);
+        let up = sky.radiance(Vector3::new(0.0, 1.0, 0.0));
+        // A clear sky is blue, brighter around the sun and dazzling on it.
+        assert!(up.blue > up.red && up.red > 0.0);
+        let near_sun = sky.radiance(Vector3::new(1.0, 1.2, 0.0));
+        let away = sky.radiance(Vector3::new(-1.0, 1.2, 0.0));
+        assert!(near_sun.green > away.green);
+        assert!(sky.radiance(Vector3::new(1.0, 1.0, 0.0)).green > 1000.0 * near_sun.green);
+        // The sun goes red through the thick atmosphere at sunset.
+        let sunset = Sky::new(Vector3::new(1.0, 0.05, 0.0), 3.0, Light::zero()).unwrap();
+        let sun = sunset.radiance(Vector3::new(1.0, 0.05, 0.0));
+        assert!(sun.red > sun.green && sun.green > sun.blue);
+    }
+
+    #[test]
+    fn test_sky_errors() {
+        let up = Vector3::new(0.0f32, 1.0, 0.0);
+        assert!(Sky::new(Vector3::new(0.0, 0.0, 0.0), 3.0, Light::zero()).is_err());
+        assert!(Sky::new(up, 1.5, Light::zero()).is_err());
+        assert!(Sky::new(up, 12.0, Light::zero()).is_err());
+        assert!(Sky::new(up, 2.0, Light::zero()).is_ok() && Sky::new(up, 10.0, Light::zero()).is_ok());
+    }
+
+    #[test]
+    fn test_sky_ground() {
+        let down = Vector3::new(0.3, -1.0, 0.0);
+        assert!(make_sky(0.0).radiance(down) == Light::zero());
+        let grey = make_sky(0.2).radiance(down);
+        let white = make_sky(0.4).radiance(down);
+        assert!(grey.green > 0.0 && (white.green - 2.0 * grey.green).abs() < 0.0001 * white.green);
+    }
+
+    #[test]
+    fn test_sky_sampling() {
+        let sky = make_sky(0.2);
+        let sun = Vector3::new(1.0f32, 1.0, 0.0).normalize();
+        let mut rng = XorShiftRng::new_unseeded();
+        let n = 1000u;
+        let mut on_sun = 0u;
+        for _ in range(0, n) {
+            let (dir, pdf) = sky.sample(rng.gen(), rng.gen()).unwrap();
+            assert!(dir.y >= 0.0 && (sky.pdf(dir) - pdf).abs() <= 0.001 * pdf);
+            if dot(dir, sun) >= SUN_RADIUS.cos() { on_sun += 1; }
+        }
+        assert!(on_sun > n * 2 / 5 && on_sun < n * 3 / 5);
+    }
+}
@@ -1,22 +1,49 @@
 use cgmath::sphere::Sphere;
-use cgmath::point::Point3;
+use cgmath::point::{Point, Point3};
+use cgmath::vector::{EuclideanVector, Vector3};
+use std::f32;
+use std::rand::{Rng, XorShiftRng};
 
 use scene::Scene;
 use object::Object;
 use material::TestMaterial;
 use camera::OriginCamera;
+use sampling::{Frame, cosine_hemisphere};
+use shape::{Hit, Shape, SurfacePoint};
 
 pub fn make_test_scene() -> Scene {
     let obj = Object {
         shape: box Sphere {center: Point3::new(0.0f32, 0.0, 5.0), radius: 3.0},
         material: box TestMaterial
     };
-    Scene {
-        objects: vec![obj],
-        light_sources: vec![]
-    }
+    Scene::new(vec![obj], vec![])
 }
 
 pub fn make_test_camera() -> OriginCamera {
     OriginCamera {aperture: 2.0, height: 1000, width: 1000}
-}
\ No newline at end of file
+}
+
+/// Surface at the origin, for testing materials.
+pub fn make_test_surface(normal: Vector3<f32>) -> SurfacePoint {
+    SurfacePoint {point: Point::origin(), normal: normal, uv: (0.0, 0.0)}
+}
+
+/// Random directions leaving `hit` back to the side the ray came from, and
+/// through the surface.
+pub fn leaving_directions(hit: &Hit, rng: &mut XorShiftRng) -> (Vector3<f32>, Vector3<f32>) {
+    let n = hit.geometric_normal;
+    let side = if hit.front_face { n } else { -n };
+    let back = Frame::from_normal(side).to_world(cosine_hemisphere(rng.gen(), rng.gen()));
+    (back, -back)
+}
+
+/// Follows a ray leaving `hit` in `direction`, checking that it does not
+/// find the point it starts from again, in a scene of size `scale`.
+pub fn spawn_next(shape: &Shape, hit: &Hit, direction: Vector3<f32>, scale: f32) -> Option<Hit> {
+    let next = shape.intersect(hit.spawn_ray(direction), 0.0, f32::INFINITY);
+    match next {
+        Some(ref other) => assert!(other.point.sub_p(&hit.point).length() > 1e-4 * scale),
+        None => ()
+    }
+    next
+}
@@ -0,0 +1,449 @@
+extern crate image;
+
+use cgmath::point::Point3;
+use image::GenericImage;
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use std::sync::Arc;
+
+use light::Light;
+use shape::SurfacePoint;
+use tonemap::srgb_eotf;
+
+/// Color varying over a surface.
+pub trait Texture {
+    fn color(&self, surface: &SurfacePoint) -> Light;
+}
+
+/// A plain color is a texture which is the same everywhere.
+impl Texture for Light {
+    fn color(&self, _: &SurfacePoint) -> Light {
+        *self
+    }
+}
+
+/// Lets several materials share a texture, such as a large image.
+impl Texture for Arc<Box<Texture + Send + Sync>> {
+    fn color(&self, surface: &SurfacePoint) -> Light {
+        (**self).color(surface)
+    }
+}
+
+/// What to do with texture coordinates outside of [0, 1].
+#[deriving(Clone, PartialEq, Show)]
+pub enum Wrap {
+    /// Tile the image.
+    Repeat,
+    /// Stretch the border pixels.
+    Clamp,
+    /// Tile the image, flipping every other copy.
+    Mirror
+}
+
+impl Wrap {
+    pub fn from_name(name: &str) -> Option<Wrap> {
+        match name {
+            "repeat" => Some(Repeat),
+            "clamp" => Some(Clamp),
+            "mirror" => Some(Mirror),
+            _ => None
+        }
+    }
+
+    /// Brings the pixel index `i` within `[0, size)`.
+    fn apply(&self, i: int, size: uint) -> uint {
+        let n = size as int;
+        let wrapped = match *self {
+            Repeat => ((i % n) + n) % n,
+            Clamp => if i < 0 { 0 } else if i >= n { n - 1 } else { i },
+            Mirror => {
+                let m = ((i % (2 * n)) + 2 * n) % (2 * n);
+                if m >= n { 2 * n - 1 - m } else { m }
+            }
+        };
+        wrapped as uint
+    }
+}
+
+/// How to read an image between the centers of its pixels.
+#[deriving(Clone, PartialEq, Show)]
+pub enum Interpolation {
+    Nearest,
+    Bilinear
+}
+
+impl Interpolation {
+    pub fn from_name(name: &str) -> Option<Interpolation> {
+        match name {
+            "nearest" => Some(Nearest),
+            "bilinear" => Some(Bilinear),
+            _ => None
+        }
+    }
+}
+
+/// Image mapped on the surface by its texture coordinates: (0, 0) is the
+/// bottom left corner and (1, 1) the top right one, as in OBJ files.
+pub struct ImageTexture {
+    width: uint,
+    height: uint,
+    /// Rows from top to bottom.
+    pixels: Vec<Light>,
+    pub wrap: Wrap,
+    pub interpolation: Interpolation
+}
+
+impl ImageTexture {
+    pub fn new(width: uint, height: uint, pixels: Vec<Light>) -> ImageTexture {
+        assert!(width > 0 && height > 0 && pixels.len() == width * height);
+        ImageTexture {width: width, height: height, pixels: pixels, wrap: Repeat, interpolation: Bilinear}
+    }
+
+    /// Loads any image format supported by the `image` crate. The 8 bit
+    /// pixel values are taken as sRGB encoded, as in most image files, and
+    /// decoded to linear light.
+    pub fn load(path: &Path) -> Result<ImageTexture, String> {
+        let img = match image::open(path) {
+            Ok(img) => img.to_rgb(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e))
+        };
+        let decoded: Vec<f32> = range(0u, 256).map(|x| srgb_eotf(x as f32 / 255.0)).collect();
+        let (width, height) = img.dimensions();
+        let mut pixels = Vec::with_capacity((width * height) as uint);
+        for y in range(0, height) {
+            for x in range(0, width) {
+                let image::Rgb(r, g, b) = img.get_pixel(x, y);
+                pixels.push(Light::new(decoded[r as uint], decoded[g as uint], decoded[b as uint]));
+            }
+        }
+        Ok(ImageTexture::new(width as uint, height as uint, pixels))
+    }
+
+    pub fn with_wrap(self, wrap: Wrap) -> ImageTexture {
+        ImageTexture {wrap: wrap, ..self}
+    }
+
+    pub fn with_interpolation(self, interpolation: Interpolation) -> ImageTexture {
+        ImageTexture {interpolation: interpolation, ..self}
+    }
+
+    fn texel(&self, x: int, y: int) -> Light {
+        self.pixels[self.wrap.apply(y, self.height) * self.width + self.wrap.apply(x, self.width)]
+    }
+
+    /// Color at texture coordinates (u, v).
+    pub fn lookup(&self, u: f32, v: f32) -> Light {
+        // Continuous pixel coordinates, with pixel centers at half integers.
+        let x = u * self.width as f32;
+        let y = (1.0 - v) * self.height as f32;
+        match self.interpolation {
+            Nearest => self.texel(x.floor() as int, y.floor() as int),
+            Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (fx, fy) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as int, y0 as int);
+                let top = self.texel(x0, y0).mul_s(1.0 - fx) + self.texel(x0 + 1, y0).mul_s(fx);
+                let bottom = self.texel(x0, y0 + 1).mul_s(1.0 - fx) + self.texel(x0 + 1, y0 + 1).mul_s(fx);
+                top.mul_s(1.0 - fy) + bottom.mul_s(fy)
+            }
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn color(&self, surface: &SurfacePoint) -> Light {
+        let (u, v) = surface.uv;
+        self.lookup(u, v)
+    }
+}
+
+fn mix(a: Light, b: Light, t: f32) -> Light {
+    a.mul_s(1.0 - t) + b.mul_s(t)
+}
+
+fn scaled(p: Point3<f32>, s: f32) -> Point3<f32> {
+    Point3::new(p.x * s, p.y * s, p.z * s)
+}
+
+/// Alternating cubes of two colors, `size` wide. Seen on a plane, it is a
+/// checkerboard.
+pub struct CheckerTexture {
+    even: Light,
+    odd: Light,
+    size: f32
+}
+
+impl CheckerTexture {
+    pub fn new(even: Light, odd: Light, size: f32) -> CheckerTexture {
+        CheckerTexture {even: even, odd: odd, size: size}
+    }
+}
+
+impl Texture for CheckerTexture {
+    fn color(&self, surface: &SurfacePoint) -> Light {
+        // Planes are often placed on cube faces, where rounding errors in the
+        // hit point would flip between cubes: the offset keeps them on one side.
+        let p = scaled(surface.point, 1.0 / self.size);
+        let cell = |x: f32| (x + 0.001).floor() as int;
+        if (cell(p.x) + cell(p.y) + cell(p.z)) % 2 == 0 { self.even } else { self.odd }
+    }
+}
+
+/// Ken Perlin's improved gradient noise.
+pub struct Perlin {
+    /// Shuffled permutation of 0..255, repeated twice to avoid wrapping indices.
+    perm: Vec<uint>
+}
+
+impl Perlin {
+    pub fn new(seed: u32) -> Perlin {
+        let mut rng: XorShiftRng = SeedableRng::from_seed([seed, 0x6c8e9cf5, 0x3c6ef372, 0xa54ff53a]);
+        let mut perm: Vec<uint> = range(0u, 256).collect();
+        rng.shuffle(perm.as_mut_slice());
+        let repeated = perm.clone();
+        perm.push_all(repeated.as_slice());
+        Perlin {perm: perm}
+    }
+
+    fn hash(&self, x: int, y: int, z: int) -> uint {
+        let p = &self.perm;
+        p[p[p[(x & 255) as uint] + (y & 255) as uint] + (z & 255) as uint]
+    }
+
+    /// Smooth noise in [-1, 1], zero on the integer lattice.
+    pub fn noise(&self, p: Point3<f32>) -> f32 {
+        let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+        let (x, y, z) = (p.x - x0, p.y - y0, p.z - z0);
+        let (i, j, k) = (x0 as int, y0 as int, z0 as int);
+        let (u, v, w) = (fade(x), fade(y), fade(z));
+        let corner = |di: int, dj: int, dk: int| {
+            grad(self.hash(i + di, j + dj, k + dk), x - di as f32, y - dj as f32, z - dk as f32)
+        };
+        lerp(w, lerp(v, lerp(u, corner(0, 0, 0), corner(1, 0, 0)),
+                        lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+                lerp(v, lerp(u, corner(0, 0, 1), corner(1, 0, 1)),
+                        lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+    }
+
+    /// Sum of the absolute value of `octaves` layers of noise, each twice
+    /// as fine and half as strong as the previous one.
+    pub fn turbulence(&self, p: Point3<f32>, octaves: uint) -> f32 {
+        let mut sum = 0.0;
+        let mut frequency = 1.0;
+        for _ in range(0, octaves) {
+            sum += self.noise(scaled(p, frequency)).abs() / frequency;
+            frequency *= 2.0;
+        }
+        sum
+    }
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// Dot product with one of the 12 gradients pointing to the edges of a cube.
+fn grad(hash: uint, x: f32, y: f32, z: f32) -> f32 {
+    match hash & 15 {
+        0 | 12 => x + y,
+        1 | 14 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 | 13 => -y + z,
+        10 => y - z,
+        _ => -y - z
+    }
+}
+
+/// Blend between two colors driven by Perlin noise, or by turbulence when
+/// there is more than one octave.
+pub struct NoiseTexture {
+    perlin: Perlin,
+    low: Light,
+    high: Light,
+    /// Frequency of the noise, in features per unit.
+    scale: f32,
+    octaves: uint
+}
+
+impl NoiseTexture {
+    pub fn new(low: Light, high: Light, scale: f32) -> NoiseTexture {
+        NoiseTexture {perlin: Perlin::new(0), low: low, high: high, scale: scale, octaves: 1}
+    }
+
+    pub fn with_octaves(self, octaves: uint) -> NoiseTexture {
+        NoiseTexture {octaves: octaves, ..self}
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn color(&self, surface: &SurfacePoint) -> Light {
+        let p = scaled(surface.point, self.scale);
+        let t = if self.octaves <= 1 {
+            0.5 * (self.perlin.noise(p) + 1.0)
+        } else {
+            self.perlin.turbulence(p, self.octaves)
+        };
+        mix(self.low, self.high, t.max(0.0).min(1.0))
+    }
+}
+
+/// Veins along the x axis, made irregular by turbulence.
+pub struct MarbleTexture {
+    perlin: Perlin,
+    low: Light,
+    high: Light,
+    scale: f32,
+    /// How far the veins are pushed around.
+    turbulence: f32
+}
+
+impl MarbleTexture {
+    pub fn new(low: Light, high: Light, scale: f32) -> MarbleTexture {
+        MarbleTexture {perlin: Perlin::new(0), low: low, high: high, scale: scale, turbulence: 5.0}
+    }
+
+    pub fn with_turbulence(self, turbulence: f32) -> MarbleTexture {
+        MarbleTexture {turbulence: turbulence, ..self}
+    }
+}
+
+impl Texture for MarbleTexture {
+    fn color(&self, surface: &SurfacePoint) -> Light {
+        let p = scaled(surface.point, self.scale);
+        let phase = p.x + self.turbulence * self.perlin.turbulence(p, 6);
+        mix(self.low, self.high, 0.5 * (phase.sin() + 1.0))
+    }
+}
+
+/// Growth rings around the y axis, slightly distorted by noise.
+pub struct WoodTexture {
+    perlin: Perlin,
+    light: Light,
+    dark: Light,
+    /// Rings per unit.
+    scale: f32,
+    /// Distortion of the rings, as a fraction of their width.
+    grain: f32
+}
+
+impl WoodTexture {
+    pub fn new(light: Light, dark: Light, scale: f32) -> WoodTexture {
+        WoodTexture {perlin: Perlin::new(0), light: light, dark: dark, scale: scale, grain: 0.5}
+    }
+
+    pub fn with_grain(self, grain: f32) -> WoodTexture {
+        WoodTexture {grain: grain, ..self}
+    }
+}
+
+impl Texture for WoodTexture {
+    fn color(&self, surface: &SurfacePoint) -> Light {
+        let p = scaled(surface.point, self.scale);
+        let r = (p.x * p.x + p.z * p.z).sqrt() + self.grain * self.perlin.noise(p);
+        // Sharp edge at the end of each ring, as latewood is darker.
+        let t = r - r.floor();
+        mix(self.light, self.dark, t * t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use cgmath::point::Point3;
+    use cgmath::vector::Vector3;
+    use light::Light;
+    use shape::SurfacePoint;
+    use test_helpers::make_test_surface;
+    use texture::{Texture, ImageTexture, Repeat, Clamp, Mirror, Nearest};
+    use texture::{CheckerTexture, Perlin, MarbleTexture, WoodTexture};
+
+    fn checker() -> ImageTexture {
+        // Black and white 2x2 pixels, white in the top left corner.
+        ImageTexture::new(2, 2, vec![Light::white(1.0), Light::zero(), Light::zero(), Light::white(1.0)])
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert!(Repeat.apply(-1, 4) == 3 && Repeat.apply(5, 4) == 1);
+        assert!(Clamp.apply(-1, 4) == 0 && Clamp.apply(5, 4) == 3);
+        assert!(Mirror.apply(-1, 4) == 0 && Mirror.apply(5, 4) == 2 && Mirror.apply(8, 4) == 0);
+    }
+
+    #[test]
+    fn test_nearest() {
+        let tex = checker().with_interpolation(Nearest);
+        assert!(tex.lookup(0.25, 0.75) == Light::white(1.0));
+        assert!(tex.lookup(0.75, 0.75) == Light::zero());
+        assert!(tex.lookup(0.25, 0.25) == Light::zero());
+        assert!(tex.lookup(1.25, 1.75) == Light::white(1.0));
+    }
+
+    #[test]
+    fn test_bilinear() {
+        let tex = checker();
+        // At a pixel center, and between the four pixels.
+        assert!(tex.lookup(0.25, 0.75) == Light::white(1.0));
+        assert!(tex.lookup(0.5, 0.5) == Light::white(0.5));
+        let quarter = tex.lookup(0.375, 0.75);
+        assert!((quarter.red - 0.75).abs() < 0.00001);
+        // Repeating, the right border blends with the left one.
+        let border = tex.lookup(1.0, 0.75);
+        assert!((border.red - 0.5).abs() < 0.00001);
+        let clamped = checker().with_wrap(Clamp).lookup(1.0, 0.75);
+        assert!(clamped == Light::zero());
+    }
+
+    fn at(x: f32, y: f32, z: f32) -> SurfacePoint {
+        SurfacePoint {point: Point3::new(x, y, z), ..make_test_surface(Vector3::new(0.0, 1.0, 0.0))}
+    }
+
+    #[test]
+    fn test_checker() {
+        let tex = CheckerTexture::new(Light::white(1.0), Light::zero(), 0.5);
+        assert!(tex.color(&at(0.25, 0.0, 0.25)) == Light::white(1.0));
+        assert!(tex.color(&at(0.75, 0.0, 0.25)) == Light::zero());
+        assert!(tex.color(&at(-0.25, 0.0, 0.25)) == Light::zero());
+        // Just below a cube face counts as on it.
+        assert!(tex.color(&at(0.25, -0.00001, 0.25)) == Light::white(1.0));
+    }
+
+    #[test]
+    fn test_perlin() {
+        let perlin = Perlin::new(0);
+        assert!(perlin.noise(Point3::new(3.0, -2.0, 7.0)) == 0.0);
+        let mut nonzero = false;
+        for i in range(0i, 1000) {
+            let p = Point3::new(i as f32 * 0.137, i as f32 * -0.071, i as f32 * 0.0293);
+            let n = perlin.noise(p);
+            assert!(n >= -1.0 && n <= 1.0);
+            // Continuous, and the same for a given seed.
+            assert!((perlin.noise(Point3::new(p.x + 0.001, p.y, p.z)) - n).abs() < 0.01);
+            assert!(Perlin::new(0).noise(p) == n);
+            nonzero = nonzero || n.abs() > 0.1;
+        }
+        assert!(nonzero);
+        assert!(perlin.turbulence(Point3::new(0.3, 0.6, 0.9), 4) >= 0.0);
+    }
+
+    #[test]
+    fn test_marble_and_wood_stay_between_colors() {
+        let marble = MarbleTexture::new(Light::zero(), Light::white(1.0), 2.0);
+        let wood = WoodTexture::new(Light::white(1.0), Light::zero(), 4.0);
+        for i in range(0i, 100) {
+            let surface = at(i as f32 * 0.31, i as f32 * 0.17, i as f32 * -0.23);
+            for &c in [marble.color(&surface), wood.color(&surface)].iter() {
+                assert!(c.red >= 0.0 && c.red <= 1.0 && c.red == c.green && c.green == c.blue);
+            }
+        }
+    }
+}
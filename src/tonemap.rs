@@ -0,0 +1,142 @@
+use light::Light;
+
+/// Display transforms, bringing the linear light of the film into the
+/// [0, 1] range of 8 bit images.
+///
+/// All but `LinearClamp` compress the light channel by channel, then encode
+/// it with the sRGB transfer function.
+#[deriving(Clone, PartialEq, Show)]
+pub enum ToneMap {
+    /// Clamps the linear light, without encoding.
+    LinearClamp,
+    /// Clamps the light and applies the sRGB transfer function.
+    Srgb,
+    /// x / (1 + x), which never reaches white.
+    Reinhard,
+    /// Reinhard with the given white point, the light mapped to 1.
+    ReinhardExtended(f32),
+    /// Narkowicz's fit of the ACES filmic reference rendering transform.
+    Aces,
+    /// John Hable's filmic curve from Uncharted 2.
+    Hable
+}
+
+impl ToneMap {
+    /// Parses a tone map name, using common parameters for each kind.
+    pub fn from_name(name: &str) -> Option<ToneMap> {
+        match name {
+            "linear" => Some(LinearClamp),
+            "srgb" => Some(Srgb),
+            "reinhard" => Some(Reinhard),
+            "reinhard_extended" => Some(ReinhardExtended(4.0)),
+            "aces" => Some(Aces),
+            "hable" | "uncharted2" => Some(Hable),
+            _ => None
+        }
+    }
+
+    /// Maps `light`, scaled by 2 to the power of `exposure`, to display
+    /// values in [0, 1].
+    pub fn apply(&self, light: Light, exposure: f32) -> Light {
+        let scale = 2.0f32.powf(exposure);
+        Light::new(self.channel(light.red * scale), self.channel(light.green * scale), self.channel(light.blue * scale))
+    }
+
+    fn channel(&self, x: f32) -> f32 {
+        // Negative filter lobes can leave a little negative light.
+        let x = x.max(0.0);
+        let mapped = match *self {
+            LinearClamp => return x.min(1.0),
+            Srgb => x,
+            Reinhard => x / (1.0 + x),
+            ReinhardExtended(white) => x * (1.0 + x / (white * white)) / (1.0 + x),
+            Aces => x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14),
+            Hable => hable(2.0 * x) / hable(11.2)
+        };
+        srgb_oetf(mapped.min(1.0))
+    }
+}
+
+/// Filmic curve, before normalization by its value at the white point.
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+}
+
+/// Encodes linear light in [0, 1] for display, as sRGB does.
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes a value in [0, 1] from an sRGB image to linear light: the
+/// inverse of `srgb_oetf`.
+pub fn srgb_eotf(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use light::Light;
+    use tonemap::{ToneMap, LinearClamp, Srgb, Reinhard, ReinhardExtended, Aces, Hable, srgb_oetf, srgb_eotf};
+
+    #[test]
+    fn test_srgb_oetf() {
+        assert!(srgb_oetf(0.0) == 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+        // Middle grey comes out a bit above half.
+        assert!((srgb_oetf(0.18) - 0.4614).abs() < 0.001);
+        // Both pieces meet.
+        assert!((srgb_oetf(0.0031308) - srgb_oetf(0.0031309)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_srgb_eotf() {
+        assert!(srgb_eotf(0.0) == 0.0);
+        assert!((srgb_eotf(1.0) - 1.0).abs() < 1e-6);
+        for i in range(0u, 101) {
+            let x = i as f32 / 100.0;
+            assert!((srgb_eotf(srgb_oetf(x)) - x).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_tone_maps() {
+        let grey = Light::white(0.18);
+        assert!(LinearClamp.apply(Light::new(0.5, 3.0, -1.0), 0.0) == Light::new(0.5, 1.0, 0.0));
+        // One stop doubles the light.
+        assert!(LinearClamp.apply(Light::white(0.25), 1.0) == Light::white(0.5));
+        assert!(Srgb.apply(grey, 0.0) == Light::white(srgb_oetf(0.18)));
+        assert!(Reinhard.apply(Light::white(1.0), 0.0) == Light::white(srgb_oetf(0.5)));
+        assert!((ReinhardExtended(4.0).apply(Light::white(4.0), 0.0).red - 1.0).abs() < 1e-6);
+        for tone_map in [Srgb, Reinhard, ReinhardExtended(4.0), Aces, Hable].iter() {
+            // Monotonic, in [0, 1], with some detail kept above white for
+            // all but the clamp.
+            let mut last = -1.0;
+            for i in range(0u, 200) {
+                let x = tone_map.apply(Light::white(i as f32 * 0.05), 0.0).red;
+                assert!(x >= last && x >= 0.0 && x <= 1.0);
+                last = x;
+            }
+            let bright = tone_map.apply(Light::white(2.0), 0.0).red;
+            assert!((*tone_map == Srgb) == (bright > 0.9999));
+        }
+        assert!(Hable.apply(Light::white(11.2 / 2.0), 0.0).red > 0.999);
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert!(ToneMap::from_name("aces") == Some(Aces));
+        assert!(ToneMap::from_name("uncharted2") == Some(Hable));
+        assert!(ToneMap::from_name("reinhard_extended") == Some(ReinhardExtended(4.0)));
+        assert!(ToneMap::from_name("filmic") == None);
+    }
+}
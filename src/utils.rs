@@ -1,6 +1,33 @@
+use std::rand::{SeedableRng, XorShiftRng};
+
 pub fn cmp_float<F: PartialOrd>(f1: F, f2: F) -> Ordering {
     match f1.partial_cmp(&f2) {
         None => Less,
         Some(ord) => ord
     }
-}
\ No newline at end of file
+}
+
+/// Returns a random number generator depending only on `seed` and the
+/// pixel coordinates, so that renders are reproducible whatever the order
+/// in which pixels are computed.
+pub fn pixel_rng(seed: u32, x: u32, y: u32) -> XorShiftRng {
+    // Murmur3 finalizer, so that neighbouring pixels get unrelated seeds.
+    let mix = |v: u32| {
+        let mut h = v;
+        h = (h ^ (h >> 16)) * 0x85ebca6b;
+        h = (h ^ (h >> 13)) * 0xc2b2ae35;
+        h ^ (h >> 16)
+    };
+    // The last word is never zero, as XorShift forbids an all-zero seed.
+    SeedableRng::from_seed([mix(seed ^ 0x9e3779b9), mix(x ^ 0x7f4a7c15), mix(y ^ 0x94d049bb), 0x2545f491])
+}
+
+#[test]
+fn test_pixel_rng_is_reproducible() {
+    use std::rand::Rng;
+    let a: Vec<u32> = pixel_rng(1, 2, 3).gen_iter().take(4).collect();
+    let b: Vec<u32> = pixel_rng(1, 2, 3).gen_iter().take(4).collect();
+    let c: Vec<u32> = pixel_rng(1, 3, 2).gen_iter().take(4).collect();
+    assert!(a == b);
+    assert!(a != c);
+}